@@ -1,79 +1,410 @@
 // Take a path to a bam file
 use core::str;
-use rust_htslib::bam::{self, record::Aux, FetchDefinition, Read, Record};
+use rust_htslib::bam::{
+    self,
+    record::{Aux, Cigar},
+    FetchDefinition, Read,
+};
 use rust_htslib::errors::Error;
-use std::clone;
-use std::io::Write;
-use std::path::Path;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
 
 use crate::kraken::KrakenConfig;
+use crate::regions::{overlaps_any, RegionSet};
+use crate::summary::RunStatus;
 
-pub fn bam2microbes(bam: &str, outdir: &str, config_kraken: KrakenConfig) {
-    //Filepaths
-    let bam_path = std::path::Path::new(bam);
-    assert!(
-        bam_path.exists(),
-        "Could not find BAM file [{}]",
-        bam_path.to_str().unwrap()
-    );
-    let bam_prefix = bam_path
-        .file_stem()
-        .expect("failed to extract file stem")
-        .to_str()
-        .expect("Failed to convert bam file stem into prefix");
+/// Attach a description of what was being attempted to a failure, e.g. `.context("Failed to fetch unmapped reads")?`
+pub(crate) trait Context<T> {
+    fn context(self, what: &str) -> Result<T, Box<dyn std::error::Error>>;
+}
 
-    let unmapped_fasta = format!("{outdir}/{bam_prefix}.fasta");
-    // Create working directory
-    std::fs::create_dir_all(outdir).expect("Failed to create output directory");
+impl<T, E: std::fmt::Display> Context<T> for Result<T, E> {
+    fn context(self, what: &str) -> Result<T, Box<dyn std::error::Error>> {
+        self.map_err(|err| format!("{}: {}", what, err).into())
+    }
+}
 
-    // Collect unmapped reads into FASTQAformat
-    bam2unmappedreads(bam, unmapped_fasta.as_str(), 50, 17.0);
-    eprintln!("Created fasta file of unmapped reads at {unmapped_fasta}");
+impl<T> Context<T> for Option<T> {
+    fn context(self, what: &str) -> Result<T, Box<dyn std::error::Error>> {
+        self.ok_or_else(|| what.into())
+    }
+}
 
-    // Run Kraken
-    crate::kraken::run_kraken(unmapped_fasta.into(), config_kraken);
+/// Thresholds deciding whether a read is a good quality sequence (see [is_good_quality_sequence])
+#[derive(Debug, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReadFilterConfig {
+    /// Minimum read length
+    pub min_len: usize,
+    /// Minimum average phred score
+    pub min_phred: f64,
+    /// Maximum number of ambiguous bases (N)
+    pub max_n: usize,
+    /// Minimum sequence complexity (see [sequence_complexity]).
+    /// Homopolymer and short tandem repeat reads are a common source of spurious kraken hits
+    pub min_complexity: f64,
+    /// Reject reads with a single-base run longer than this (see [longest_homopolymer]), e.g. poly-A tails
+    /// on otherwise diverse reads that still pass [ReadFilterConfig::min_complexity]
+    pub max_homopolymer_run: usize,
+    /// Reject reads more than this fraction of which is one tandem repeat of a 2-6bp motif
+    /// (see [tandem_repeat_fraction]), e.g. `CAGCAGCAG...` stretches
+    pub max_tandem_repeat_fraction: f64,
+    /// Reads on microbial contigs need a MAPQ above this to count as a good quality alignment
+    /// (see [is_good_quality_alignment]). 10 suits bwa mem and minimap2 (MAPQ 0-60) as well as bowtie2 (0-42)
+    pub microbial_min_mapq: u8,
+    /// Minimum `AS` per base for reads on microbial contigs to count as a good quality alignment.
+    /// The scale depends on the aligner's match score: bwa mem scores at most 1 per base (0.5 is the default),
+    /// minimap2 `-x sr` at most 2 (try 1.0). bowtie2 `--end-to-end` scores are never positive (try -0.6)
+    pub microbial_min_as_per_base: f64,
+    /// SAM flag bits a read must all have to be extracted, like `samtools view -f` (0: no requirement).
+    /// Applied to every record of the unmapped and microbial-contig passes. Bits:
+    /// 0x1 paired, 0x2 proper pair, 0x4 unmapped, 0x8 mate unmapped, 0x10 reverse strand, 0x20 mate reverse strand,
+    /// 0x40 first in pair, 0x80 second in pair, 0x100 secondary, 0x200 QC fail, 0x400 duplicate, 0x800 supplementary.
+    /// JSON has no hex literals, so give the decimal sum (e.g. 3 for paired + proper pair)
+    pub require_flags: u16,
+    /// SAM flag bits a read must have none of to be extracted, like `samtools view -F` (bits as for
+    /// [ReadFilterConfig::require_flags]). Defaults to 2304 (0x100 | 0x800): secondary and supplementary
+    /// alignments, which repeat the sequence of their primary record
+    pub exclude_flags: u16,
 }
 
-// Go from bam to unmapped reads
-pub fn bam2unmappedreads(bam_path: &str, fasta_output_path: &str, min_len: usize, min_phred: f64) {
-    let microbial_contigs = common_microbial_contigs();
+impl ReadFilterConfig {
+    /// Whether a record's SAM flags pass [ReadFilterConfig::require_flags] and [ReadFilterConfig::exclude_flags]
+    pub fn passes_flags(&self, flags: u16) -> bool {
+        (flags & self.require_flags == self.require_flags) & (flags & self.exclude_flags == 0)
+    }
 
-    // Create Bam Reader
-    let bam_result = bam::IndexedReader::from_path(bam_path);
-    let mut bam = match bam_result {
-        Ok(value) => value,
-        Err(e) => {
-            panic!("An error occurred: {:?}", e);
+    /// Check thresholds that would otherwise silently filter everything (or nothing)
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        check_range("min_phred", self.min_phred, 0.0, 93.0, "a phred score")?;
+        check_range(
+            "min_complexity",
+            self.min_complexity,
+            0.0,
+            1.0,
+            "a fraction of the maximum entropy, not a percentage",
+        )?;
+        check_range(
+            "max_tandem_repeat_fraction",
+            self.max_tandem_repeat_fraction,
+            0.0,
+            1.0,
+            "a fraction of the read",
+        )?;
+        if !self.microbial_min_as_per_base.is_finite() {
+            return Err("microbial_min_as_per_base must be a number".into());
+        }
+        for (name, flags) in [
+            ("require_flags", self.require_flags),
+            ("exclude_flags", self.exclude_flags),
+        ] {
+            if flags > 0xFFF {
+                return Err(format!(
+                    "{} [{}] has bits that aren't SAM flags (expected a value between 0 and 4095)",
+                    name, flags
+                )
+                .into());
+            }
+        }
+        if self.require_flags & self.exclude_flags != 0 {
+            return Err(format!(
+                "require_flags [{}] and exclude_flags [{}] share bits [{}], so no read could pass",
+                self.require_flags,
+                self.exclude_flags,
+                self.require_flags & self.exclude_flags
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// Fail unless `value` is a number between `min` and `max` (inclusive). `expected` says what kind of value it is
+fn check_range(
+    name: &str,
+    value: f64,
+    min: f64,
+    max: f64,
+    expected: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !(min..=max).contains(&value) {
+        return Err(format!(
+            "{} [{}] is out of range (expected {} between {} and {})",
+            name, value, expected, min, max
+        )
+        .into());
+    }
+    Ok(())
+}
+
+impl Default for ReadFilterConfig {
+    fn default() -> Self {
+        ReadFilterConfig {
+            min_len: 50,
+            min_phred: 17.0,
+            max_n: 2,
+            min_complexity: 0.5,
+            max_homopolymer_run: 20,
+            max_tandem_repeat_fraction: 0.5,
+            microbial_min_mapq: 10,
+            microbial_min_as_per_base: 0.5,
+            require_flags: 0,
+            exclude_flags: 0x100 | 0x800,
+        }
+    }
+}
+
+/// Options controlling how reads are pulled out of a bam
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExtractionConfig {
+    /// Write a TSV describing the quality-filter decision for every read inspected (opt-in, verbose).
+    /// When kraken's per-read output is kept ([KrakenConfig::keep_kout]), [bam2microbes] adds the taxid each read
+    /// was classified to (see [add_classified_taxids]). `micrite batch` writes one per sample instead
+    /// (see [crate::batch::screen_bams])
+    pub debug_reads: Option<PathBuf>,
+    /// Directory for intermediate files (e.g. the unmapped read FASTA).
+    /// Defaults to `$TMPDIR` so scratch I/O can stay on node-local storage
+    pub tmpdir: Option<PathBuf>,
+    /// Split reads on microbial contigs with a terminal soft-clip at least this long into
+    /// `{qname}_clip` and `{qname}_aln` FASTA entries so kraken can classify each segment independently
+    /// (reads confidently aligned to the contig are reported directly instead)
+    pub split_softclips_min_len: Option<usize>,
+    /// Halt [bam2microbes] cleanly after this stage (useful for inspecting intermediates)
+    pub stop_after: Option<Stage>,
+    /// Log a warning if the fraction of unmapped reads exceeds this.
+    /// A high unmapped fraction can indicate contamination or a mis-referenced sample
+    pub warn_unmapped_fraction: Option<f64>,
+    /// Write the reads of each observed microbial contig to a coordinate-sorted, indexed
+    /// `<bam stem>.<contig>.bam` in this directory (directly loadable in IGV)
+    pub contig_bam_dir: Option<PathBuf>,
+    /// Don't report reads mapped to a microbial contig as direct evidence when the contig's mean MAPQ is below this.
+    /// Real microbial reads map confidently; cross-species noise doesn't
+    pub contig_min_mean_mapq: Option<f64>,
+    /// Also send good quality reads mapped to unplaced/random human contigs to kraken.
+    /// Contigs are matched if their name contains any of these patterns (see [DEFAULT_UNPLACED_PATTERNS])
+    pub unplaced_contig_patterns: Option<Vec<String>>,
+    /// Reference FASTA used to decode CRAM input (required when the input ends in `.cram`)
+    pub reference: Option<PathBuf>,
+    /// Write extracted reads as FASTQ (keeping base qualities) instead of FASTA.
+    /// Reads without stored qualities (`*`) are still written as FASTA records
+    pub emit_fastq: bool,
+    /// Also write mates of paired reads to `<reads>_R1.<ext>` and `<reads>_R2.<ext>`, in sync.
+    /// Mates whose partner failed the quality filters are dropped from these files (and counted in the bam summary)
+    pub paired_output: bool,
+    /// Gzip the extracted reads written by [bam2microbes] (`<prefix>.fasta.gz`).
+    /// [bam2unmappedreads] compresses any output path ending in `.gz`
+    pub compress_intermediates: bool,
+    /// Also scan the whole bam for one-end-anchored pairs (exactly one mate mapped) and send the good quality
+    /// reads of both ends to kraken. These pairs frequently span viral integration junctions.
+    /// Reads on microbial/unplaced contigs are left to their own passes
+    pub include_mate_anchored: bool,
+    /// Also scan the whole bam for mapped reads with a terminal soft-clip at least this long and send each clipped
+    /// segment to kraken as `<qname>_SC<pos>` (`pos`: 1-based reference position of the clip junction).
+    /// Viral-host integration reads often align the host half and soft-clip the viral half
+    pub min_softclip_len: Option<usize>,
+    /// CSV (`taxid,common_name,contigs`, contigs `;` separated) of the microbial contigs in the reference,
+    /// for builds whose viral contigs aren't named like those in [common_microbial_contigs]
+    pub microbial_contigs: Option<PathBuf>,
+    /// Only send the first of any reads with identical sequences to kraken (the rest are counted in the bam summary).
+    /// Collapses unflagged optical/PCR duplicates that inflate clade counts, but also genuine coverage sleuth
+    /// could use. Keeps an 8 byte hash per distinct sequence in memory.
+    /// With [ExtractionConfig::paired_output], a pair with a duplicate mate is left out of the paired files entirely
+    pub dedup_sequences: bool,
+    /// Don't draw progress bars for the read passes (they are only drawn when stderr is a terminal anyway)
+    pub quiet: bool,
+    /// Prefix of every output file (and per-contig bam). Defaults to the bam file stem
+    pub sample_id: Option<String>,
+    /// Skip stages that already completed with the same inputs in an earlier run (see [crate::state]).
+    /// Intermediates must survive between runs for extraction to be skipped, so set [ExtractionConfig::tmpdir]
+    pub resume: bool,
+    /// BED of untrustworthy loci (e.g. retroviral-origin regions that attract mis-mapped microbial reads).
+    /// The full-bam scan treats mapped reads whose alignment overlaps one of these regions as unmapped:
+    /// good quality reads are sent to kraken to be re-classified
+    pub unmap_bed: Option<PathBuf>,
+    /// Don't write the MultiQC custom content files (see [crate::summary::RunSummary::write_multiqc])
+    pub no_multiqc: bool,
+}
+
+/// Name patterns of unplaced and unlocalised contigs in common human references
+pub const DEFAULT_UNPLACED_PATTERNS: [&str; 2] = ["chrUn_", "_random"];
+
+/// Stages of the [bam2microbes] pipeline, in the order they run
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+pub enum Stage {
+    /// Unmapped/microbial reads extracted. Outputs: `<tmpdir>/<prefix>.fasta` (or `.fastq`) and `<outdir>/<prefix>.bam_summary.txt`
+    Extract,
+    /// Kraken classification run. Outputs: everything from [Stage::Extract] plus `<outdir>/<prefix>.kreport`
+    Classify,
+}
+
+impl ExtractionConfig {
+    /// Resolve where intermediates should be written (`tmpdir` if set, otherwise `$TMPDIR`)
+    pub fn intermediate_dir(&self) -> PathBuf {
+        self.tmpdir.clone().unwrap_or_else(std::env::temp_dir)
+    }
+
+    /// Check thresholds before any reads are read
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(fraction) = self.warn_unmapped_fraction {
+            check_range(
+                "warn_unmapped_fraction",
+                fraction,
+                0.0,
+                1.0,
+                "a fraction (0.05 is 5%)",
+            )?;
+        }
+        if let Some(mapq) = self.contig_min_mean_mapq {
+            check_range("contig_min_mean_mapq", mapq, 0.0, 255.0, "a MAPQ")?;
         }
+        for (name, len) in [
+            ("split_softclips_min_len", self.split_softclips_min_len),
+            ("min_softclip_len", self.min_softclip_len),
+        ] {
+            if len == Some(0) {
+                return Err(format!(
+                    "{} must be at least 1 (leave it out to turn the option off)",
+                    name
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Screen `bam` into `outdir` (see [crate::pipeline::screen]), returning how far the run got
+pub fn bam2microbes(
+    bam: &str,
+    outdir: &str,
+    config_kraken: KrakenConfig,
+    config_filters: &ReadFilterConfig,
+    config_extraction: &ExtractionConfig,
+) -> Result<RunStatus, Box<dyn std::error::Error>> {
+    let config_kraken = KrakenConfig {
+        outdir: outdir.to_string(),
+        ..config_kraken
     };
+    let run_summary = crate::pipeline::screen(
+        Path::new(bam),
+        &config_kraken,
+        config_filters,
+        config_extraction,
+    )?;
+    Ok(run_summary.status)
+}
 
-    // Get Bam Header
-    let bam_header = bam.header();
-    let contigs: Vec<String> = bam_header
-        .target_names()
-        .iter()
-        .map(|t| std::str::from_utf8(t).unwrap().to_string())
-        .collect();
-    // Braces set to end mutable borrow of bam.header()
+// Go from bam to unmapped reads
+pub fn bam2unmappedreads(
+    bam_path: &str,
+    fasta_output_path: &str,
+    summary_output_path: &str,
+    filters: &ReadFilterConfig,
+    config: &ExtractionConfig,
+) -> Result<ExtractionSummary, Box<dyn std::error::Error>> {
+    let microbial_contigs = match &config.microbial_contigs {
+        Some(path) => read_microbial_contigs(path).map_err(|err| {
+            format!(
+                "Failed to read microbial contigs from [{}]: {}",
+                path.display(),
+                err
+            )
+        })?,
+        None => common_microbial_contigs(),
+    };
+    let bam_stem = match &config.sample_id {
+        Some(sample_id) => sample_id.as_str(),
+        None => Path::new(bam_path)
+            .file_stem()
+            .context("failed to extract file stem")?
+            .to_str()
+            .context("Failed to convert bam file stem into prefix")?,
+    };
 
-    // eprintln!("Bam has the following contigs: {:#?}", contigs);
-    let observed_microbial_contigs: Vec<String> = contigs
-        .iter()
-        .filter(|c| microbial_contigs.contains(c))
-        .cloned()
-        .collect();
+    // Freshly merged bams often lack an index
+    ensure_index(bam_path)?;
+
+    // Create Bam Reader
+    let mut bam = bam::IndexedReader::from_path(bam_path)
+        .map_err(|err| format!("Failed to open [{}]: {}", bam_path, err))?;
+
+    // CRAM records can't be decoded without the reference they were compressed against
+    if bam_path.ends_with(".cram") {
+        let reference = config.reference.as_ref().ok_or_else(|| {
+            format!(
+                "Input [{}] is a CRAM: please supply the reference FASTA it was aligned to",
+                bam_path
+            )
+        })?;
+        bam.set_reference(reference)
+            .context("Failed to set CRAM reference")?;
+    }
+
+    // Get Bam Header
+    let observed_microbial_contigs = find_microbial_contigs(bam.header(), &microbial_contigs)?;
 
     // Check if we found any microbial contigs
     if !observed_microbial_contigs.is_empty() {
         eprintln!(
             "Found {} contigs in bam that are probably microbial: [{}]",
             observed_microbial_contigs.len(),
-            observed_microbial_contigs.join(",")
+            observed_microbial_contigs
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>()
+                .join(",")
+        )
+    } else {
+        eprintln!(
+            "Note: no known microbial (decoy) contigs in the bam header, so only unmapped reads will be screened. \
+             Aligning to a reference with viral decoys (e.g. chrEBV in GRCh38 full analysis sets) lets reads of those \
+             microbes be counted directly"
         )
     }
+    let microbial_contigs_present = !observed_microbial_contigs.is_empty();
+
+    // Untrustworthy regions, as per-target-id interval lists
+    let unmap_regions: Option<Vec<Vec<(u64, u64)>>> = config
+        .unmap_bed
+        .as_ref()
+        .map(|path| -> Result<_, Box<dyn std::error::Error>> {
+            let regions = RegionSet::read_bed(path)
+                .map_err(|err| format!("Failed to read unmap bed [{}]: {}", path.display(), err))?;
+            let header = bam.header();
+            let mut missing: Vec<&str> = regions
+                .contigs()
+                .filter(|contig| header.tid(contig.as_bytes()).is_none())
+                .collect();
+            if !missing.is_empty() {
+                missing.sort_unstable();
+                eprintln!(
+                    "Warning: contigs in [{}] that aren't in the bam header will be ignored: [{}]",
+                    path.display(),
+                    missing.join(",")
+                );
+            }
+            eprintln!(
+                "Treating reads aligned to [{}] regions in {} as unmapped",
+                regions.len(),
+                path.display()
+            );
+            Ok((0..header.target_count())
+                .map(|tid| {
+                    regions
+                        .intervals(&String::from_utf8_lossy(header.tid2name(tid)))
+                        .to_vec()
+                })
+                .collect())
+        })
+        .transpose()?;
 
     // Grab BAM Summary Stats
-    let idxstats = bam.index_stats().expect("Failed to get index stats");
+    let idxstats = if bam_path.ends_with(".cram") {
+        count_reads_per_contig(&mut bam)?
+    } else {
+        bam.index_stats().context("Failed to get index stats")?
+    };
     let total_reads: u64 = idxstats.iter().map(|c| c.2 + c.3).sum();
     let total_mapped_reads: u64 = idxstats.iter().map(|c| c.2).sum();
     let total_unmapped_reads: u64 = idxstats.iter().map(|c| c.3).sum();
@@ -81,35 +412,75 @@ pub fn bam2unmappedreads(bam_path: &str, fasta_output_path: &str, min_len: usize
     eprintln!("\ttotal depth (number of reads): [{}]", total_reads);
     eprintln!("\ttotal mapped reads: [{}]", total_mapped_reads);
     eprintln!("\ttotal unmapped reads: [{}]", total_unmapped_reads);
+    let unmapped_fraction = if total_reads > 0 {
+        total_unmapped_reads as f64 / total_reads as f64
+    } else {
+        0.0
+    };
+    eprintln!("\tunmapped fraction: [{:.4}]", unmapped_fraction);
+    if let Some(max_fraction) = config.warn_unmapped_fraction {
+        if unmapped_fraction > max_fraction {
+            eprintln!(
+                "WARNING: unmapped fraction [{:.4}] exceeds [{}]. Sample may be contaminated or aligned to the wrong reference",
+                unmapped_fraction, max_fraction
+            );
+        }
+    }
     // Write Bam Summary Stats
-    let outdir = Path::new(fasta_output_path)
-        .parent()
-        .unwrap_or(Path::new("."))
-        .to_str()
-        .unwrap();
-
-    let stem = Path::new(fasta_output_path)
-        .file_stem()
-        .unwrap()
-        .to_str()
-        .unwrap();
-
-    let mut summary_writer = std::fs::File::create(format!("{outdir}/{stem}.bam_summary.txt"))
-        .expect("failed to open connection to bam summary stats file");
+    let mut summary_writer = std::io::BufWriter::new(
+        std::fs::File::create(summary_output_path)
+            .context("failed to open connection to bam summary stats file")?,
+    );
     writeln!(
         summary_writer,
         "total depth (number of reads)\t{}",
         total_reads
     )
-    .expect("Bam summary write failed");
+    .context("Failed to write bam summary")?;
     writeln!(summary_writer, "total mapped reads\t{}", total_mapped_reads)
-        .expect("Bam summary write failed");
+        .context("Failed to write bam summary")?;
     writeln!(
         summary_writer,
         "total unmapped reads\t{}",
         total_unmapped_reads
     )
-    .expect("Bam summary write failed");
+    .context("Failed to write bam summary")?;
+    writeln!(
+        summary_writer,
+        "unmapped fraction\t{:.6}",
+        unmapped_fraction
+    )
+    .context("Failed to write bam summary")?;
+
+    // Per-contig breakdown (unplaced unmapped reads are reported as contig `*`).
+    // Reads already aligned to microbial contigs are direct evidence before kraken even runs
+    for &(tid, _, mapped, unmapped) in &idxstats {
+        if mapped + unmapped == 0 {
+            continue;
+        }
+        let contig_name = if tid < 0 {
+            "*".to_string()
+        } else {
+            String::from_utf8_lossy(bam.header().tid2name(tid as u32)).into_owned()
+        };
+        let marker = if microbial_contigs.contains(&contig_name) {
+            " (*microbial*)"
+        } else {
+            ""
+        };
+        writeln!(
+            summary_writer,
+            "Contig [{}] mapped reads{}\t{}",
+            contig_name, marker, mapped
+        )
+        .context("Failed to write bam summary")?;
+        writeln!(
+            summary_writer,
+            "Contig [{}] unmapped reads{}\t{}",
+            contig_name, marker, unmapped
+        )
+        .context("Failed to write bam summary")?;
+    }
 
     // Fetch Just the Unmapped reads (based on unmapped flag)
     // Note that some aligners may not set unmapped flag properly
@@ -117,304 +488,3678 @@ pub fn bam2unmappedreads(bam_path: &str, fasta_output_path: &str, min_len: usize
     // Since the only way to get a complete set of unmapped reads is to manually
     // look through cigar strings of every read, we're going to assume
     // upstream aligners do the right thing.
-    bam.fetch(FetchDefinition::Unmapped)
-        .expect("Failed to fetch unmapped reads from bam");
+    bam.fetch(FetchDefinition::Unmapped).map_err(|err| {
+        index_error(
+            err,
+            bam_path,
+            bam.header(),
+            "Failed to fetch unmapped reads from bam",
+        )
+    })?;
+
+    // Open the output FASTA (or FASTQ) file
+    let mut fasta_writer = ReadWriter::create(Path::new(fasta_output_path), config)?;
 
-    // Open the output FASTA file
-    let mut fasta_writer = std::fs::File::create(fasta_output_path)
-        .expect("fasta file to output unmapped reads could not be created");
+    // Optionally open a per-read debug TSV
+    let mut debug_writer = config
+        .debug_reads
+        .as_ref()
+        .map(|path| {
+            eprintln!("Writing per-read filtering decisions to {}", path.display());
+            csv::WriterBuilder::new()
+                .delimiter(b'\t')
+                .from_path(path)
+                .context("Failed to create debug reads file")
+        })
+        .transpose()?;
 
     // Iterate through Unmapped reads and Save to FASTA if they're good quality
     let mut unmapped_good_quality_sequences: u64 = 0;
     let mut unmapped_counter: u64 = 0;
+    let mut unmapped_quality_failures = QualityFailures::default();
+    let mut unmapped_flag_filtered: u64 = 0;
+    let mut progress = ReadProgress::new(
+        "unmapped reads",
+        idxstats
+            .iter()
+            .find(|c| c.0 < 0)
+            .map_or(total_unmapped_reads, |c| c.3),
+        config.quiet,
+    );
     for r in bam.records() {
-        let record = r.unwrap_or_else(|err| panic!("Failed to read bam record: {:?}", err));
-        let bam_record = parse_record(&record);
+        let record = r.context("Failed to read bam record")?;
+        progress.tick();
         unmapped_counter += 1;
+        if !filters.passes_flags(record.flags()) {
+            unmapped_flag_filtered += 1;
+            continue;
+        }
+        let bam_record = parse_record(&record)?;
+        if let Some(writer) = debug_writer.as_mut() {
+            write_debug_read(writer, &bam_record, filters)?;
+        }
         // Write to the FASTA file in the correct format
-        if is_good_quality_sequence(&bam_record, 50, 17.0, 2) {
+        let qc = is_good_quality_sequence(&bam_record, filters);
+        unmapped_quality_failures.add(qc);
+        if qc.is_pass() {
             unmapped_good_quality_sequences += 1;
-            writeln!(
-                fasta_writer,
-                ">{}\n{}",
-                bam_record.qname, bam_record.sequence
-            )
-            .expect("Failed to write unmapped read to FASTA file");
+            fasta_writer
+                .write_read(
+                    bam_record.fragment,
+                    bam_record.mate,
+                    bam_record.sequence.as_bytes(),
+                    record.qual(),
+                )
+                .context("Failed to write unmapped read to FASTA file")?;
         }
     }
+    progress.finish();
     eprintln!("Unmapped Read Summary: ");
     eprintln!("\ttotal unmapped reads: [{}]", unmapped_counter);
     eprintln!(
         "\tgood quality sequences: [{}]",
         unmapped_good_quality_sequences
     );
+    eprintln!(
+        "\treads excluded by flag filter: [{}]",
+        unmapped_flag_filtered
+    );
+    eprintln!(
+        "\treads failing quality filters: {}",
+        unmapped_quality_failures.describe()
+    );
+    writeln!(
+        summary_writer,
+        "unmapped reads excluded by flag filter\t{}",
+        unmapped_flag_filtered
+    )
+    .context("Failed to write bam summary")?;
+    unmapped_quality_failures
+        .write(&mut summary_writer, "unmapped reads")
+        .context("Failed to write bam summary")?;
 
-    // TODO: iterate through any contigs matching known microbial contigs and write mapped reads
-    for contig_name in observed_microbial_contigs {
-        bam.fetch(&contig_name)
-            .expect("Error fetching bam sequences from specific contigs");
+    let mut species_good_alignments: std::collections::BTreeMap<String, u64> =
+        std::collections::BTreeMap::new();
+    let mut handled_tids: std::collections::HashSet<u32> = observed_microbial_contigs
+        .iter()
+        .map(|(_, tid)| *tid)
+        .collect();
+    for (contig_name, tid) in observed_microbial_contigs {
+        // Fetch by target id: names like `gi|123|ref|NC_001526.4|` would otherwise be parsed as region strings
+        bam.fetch(tid).map_err(|err| {
+            index_error(
+                err,
+                bam_path,
+                bam.header(),
+                "Error fetching bam sequences from specific contigs",
+            )
+        })?;
+
+        // Records come back from fetch in coordinate order, so the per-contig bam is already sorted
+        let mut contig_bam = config
+            .contig_bam_dir
+            .as_ref()
+            .map(|dir| -> Result<_, Box<dyn std::error::Error>> {
+                std::fs::create_dir_all(dir).context("Failed to create contig bam directory")?;
+                let path = dir.join(format!(
+                    "{}.{}.bam",
+                    bam_stem,
+                    sanitize_filename(&contig_name)
+                ));
+                let header = bam::Header::from_template(bam.header());
+                let writer = bam::Writer::from_path(&path, &header, bam::Format::Bam)
+                    .context("Failed to create per-contig bam")?;
+                Ok((path, writer))
+            })
+            .transpose()?;
 
         let mut nreads: u64 = 0;
         let mut nreads_mapped: u64 = 0;
-        let mut nreads_good_sequence: u64 = 0;
+        let mut nreads_reclassify: u64 = 0;
         let mut nreads_good_alignment: u64 = 0;
+        let mut nreads_hard_clipped: u64 = 0;
+        let mut nreads_split: u64 = 0;
+        let mut quality_failures = QualityFailures::default();
+        let mut nreads_flag_filtered: u64 = 0;
+        let mut mapq_total: u64 = 0;
+        let mut progress = ReadProgress::new(
+            &contig_name,
+            idxstats
+                .iter()
+                .find(|c| c.0 == tid as i64)
+                .map_or(0, |c| c.2 + c.3),
+            config.quiet,
+        );
         for r in bam.records() {
-            let record = r.unwrap_or_else(|err| panic!("Failed to read bam record: {:?}", err));
-            let bam_record = parse_record(&record);
+            let record = r.context("Failed to read bam record")?;
+            progress.tick();
+            let bam_record = parse_record(&record)?;
 
             nreads += 1;
 
-            if (!record.is_unmapped()) {
-                nreads_mapped += 1
+            if let Some((_, writer)) = contig_bam.as_mut() {
+                writer
+                    .write(&record)
+                    .context("Failed to write record to per-contig bam")?;
             }
 
-            // Write good quality sequences mapped to microbial contigs to the fasta file
-            if !record.is_unmapped() & is_good_quality_sequence(&bam_record, 50, 17.0, 2) {
-                nreads_good_sequence += 1;
-                writeln!(
-                    fasta_writer,
-                    ">{}\n{}",
-                    bam_record.qname, bam_record.sequence
-                )
-                .expect("Failed to write unmapped read to FASTA file");
+            if !record.is_unmapped() {
+                nreads_mapped += 1;
+                mapq_total += record.mapq() as u64;
+            }
+
+            if !filters.passes_flags(record.flags()) {
+                nreads_flag_filtered += 1;
+                continue;
+            }
+
+            // Hard-clipped bases are absent from SEQ, so the sequence we have is only part of the read.
+            // These are typically supplementary alignments whose full sequence lives on the primary record,
+            // so we skip them rather than let a truncated sequence through the length filter
+            if hard_clipped_bases(&record) > 0 {
+                nreads_hard_clipped += 1;
+                continue;
+            }
+
+            if let Some(writer) = debug_writer.as_mut() {
+                write_debug_read(writer, &bam_record, filters)?;
             }
 
-            // Count Number of Good Quality Alignments
-            // TODO: MAke alignment scores (AS) sequence length independent (might end up making micrite even more aligner specific though)
-            if is_good_quality_alignment(&bam_record, 50, 17.0, 2, 10, 130) {
+            // Reads confidently aligned to a known microbial contig are direct evidence and reported as-is.
+            // Good quality sequences without a convincing alignment are written to the fasta to be re-classified by kraken
+            let good_alignment = is_good_quality_alignment(
+                &bam_record,
+                filters,
+                filters.microbial_min_mapq,
+                filters.microbial_min_as_per_base,
+            );
+            if good_alignment {
                 nreads_good_alignment += 1
             }
+
+            let qc = is_good_quality_sequence(&bam_record, filters);
+            quality_failures.add(qc);
+            let good_sequence = !record.is_unmapped() & qc.is_pass();
+
+            // Chimeric reads: classify the clipped and aligned segments separately.
+            // Confident alignments are already counted as direct evidence, so they are never split
+            let segments = config.split_softclips_min_len.and_then(|min_len| {
+                let sequence = split_at_softclip(&record, bam_record.sequence.as_bytes(), min_len)?;
+                let qual = split_at_softclip(&record, record.qual(), min_len)?;
+                Some((sequence, qual))
+            });
+
+            match segments {
+                Some(((clipped, aligned), (clipped_qual, aligned_qual)))
+                    if good_sequence & !good_alignment =>
+                {
+                    nreads_split += 1;
+                    fasta_writer
+                        .write_read(
+                            &segment_name(&bam_record, "clip"),
+                            None,
+                            clipped,
+                            clipped_qual,
+                        )
+                        .and_then(|_| {
+                            fasta_writer.write_read(
+                                &segment_name(&bam_record, "aln"),
+                                None,
+                                aligned,
+                                aligned_qual,
+                            )
+                        })
+                        .context("Failed to write soft-clip segments to FASTA file")?;
+                }
+                _ if good_sequence & !good_alignment => {
+                    nreads_reclassify += 1;
+                    fasta_writer
+                        .write_read(
+                            bam_record.fragment,
+                            bam_record.mate,
+                            bam_record.sequence.as_bytes(),
+                            record.qual(),
+                        )
+                        .context("Failed to write unmapped read to FASTA file")?;
+                }
+                _ => {}
+            }
+        }
+        progress.finish();
+        if let Some((path, writer)) = contig_bam {
+            // Writer must be closed before the bam can be indexed
+            drop(writer);
+            bam::index::build(&path, None, index_type(bam.header()), 1)
+                .context("Failed to index per-contig bam")?;
+            eprintln!(
+                "Wrote indexed bam of [{}] reads to {}",
+                contig_name,
+                path.display()
+            );
         }
+
+        let mean_mapq = if nreads_mapped > 0 {
+            mapq_total as f64 / nreads_mapped as f64
+        } else {
+            0.0
+        };
+
         eprintln!("Microbial Contig Stats: {}", contig_name);
+        eprintln!("\ttotal reads: [{}]", nreads);
         eprintln!("\ttotal reads mapped: [{}]", nreads_mapped);
+        eprintln!("\tmean MAPQ of mapped reads: [{:.2}]", mean_mapq);
+        eprintln!(
+            "\treads excluded by flag filter: [{}]",
+            nreads_flag_filtered
+        );
+        eprintln!("\thard-clipped reads skipped: [{}]", nreads_hard_clipped);
+        if config.split_softclips_min_len.is_some() {
+            eprintln!(
+                "\tsoft-clipped reads split into segments: [{}]",
+                nreads_split
+            );
+        }
         eprintln!(
             "\tgood quality alignments mapped: [{}]",
             nreads_good_alignment
         );
         eprintln!(
-            "\tgood quality sequences mapped: [{}]",
-            nreads_good_sequence
+            "\tgood quality sequences with poor alignments (sent to kraken): [{}]",
+            nreads_reclassify
+        );
+        eprintln!(
+            "\treads failing quality filters: {}",
+            quality_failures.describe()
         );
+        quality_failures
+            .write(
+                &mut summary_writer,
+                &format!("Contig [{}] reads", contig_name),
+            )
+            .context("Failed to write bam summary")?;
         writeln!(
             summary_writer,
             "Contig [{}] good quality alignments\t{}",
             contig_name, nreads_good_alignment
         )
-        .expect("Failed write");
-    }
-}
+        .context("Failed to write bam summary")?;
+        writeln!(
+            summary_writer,
+            "Contig [{}] hard-clipped reads skipped\t{}",
+            contig_name, nreads_hard_clipped
+        )
+        .context("Failed to write bam summary")?;
+        writeln!(
+            summary_writer,
+            "Contig [{}] reads excluded by flag filter\t{}",
+            contig_name, nreads_flag_filtered
+        )
+        .context("Failed to write bam summary")?;
 
-// A custom struct that adds a couple of key properties to bam::record
-struct BamRecordEnriched<'a> {
-    record: &'a rust_htslib::bam::Record,
-    qname: &'a str,
-    sequence: String,
-    alignment_score: i32,
-}
+        writeln!(
+            summary_writer,
+            "Contig [{}] mean MAPQ\t{:.2}",
+            contig_name, mean_mapq
+        )
+        .context("Failed to write bam summary")?;
 
-fn get_as_tag(record: &bam::Record) -> Option<i32> {
-    match record.aux(b"AS") {
-        Ok(Aux::I8(value)) => Some(value as i32),
-        Ok(Aux::U8(value)) => Some(value as i32),
-        Ok(Aux::I16(value)) => Some(value as i32),
-        Ok(Aux::U16(value)) => Some(value as i32),
-        Ok(Aux::I32(value)) => Some(value),
-        Ok(Aux::U32(value)) => Some(value as i32),
-        Ok(_) => None, // The AS tag exists but is of an unexpected type
-        Err(Error::BamAuxTagNotFound) => None, // AS tag not found
-        Err(e) => {
-            // Handle other potential errors
-            eprintln!("Error retrieving AS tag: {}", e);
-            None
+        if let Some(min_mean_mapq) = config.contig_min_mean_mapq {
+            if mean_mapq < min_mean_mapq {
+                eprintln!(
+                    "\tmean MAPQ below [{}]: not counting [{}] alignments as evidence",
+                    min_mean_mapq, contig_name
+                );
+                writeln!(
+                    summary_writer,
+                    "Contig [{}] suppressed (mean MAPQ below {})\ttrue",
+                    contig_name, min_mean_mapq
+                )
+                .context("Failed to write bam summary")?;
+                continue;
+            }
         }
-    }
-}
 
-fn parse_record(record: &bam::Record) -> BamRecordEnriched {
-    // Run computationally intensive checks
-    let seq = record.seq().as_bytes();
-    let sequence: String = seq.iter().map(|&b| b as char).collect();
-    let qname = str::from_utf8(record.qname()).expect("Failed to parse qname to string slice");
-    let alignment_score = get_as_tag(record).unwrap_or(0);
+        let species = microbial_contigs
+            .contig_to_species(&contig_name)
+            .context("observed microbial contig missing from microbial contig list")?
+            .to_string();
+        *species_good_alignments.entry(species).or_insert(0) += nreads_good_alignment;
+    }
 
-    BamRecordEnriched {
-        record,
-        qname,
-        sequence,
-        alignment_score,
+    // Direct evidence: reads confidently mapped to known microbial contigs (independent of kraken)
+    for (species, nreads) in &species_good_alignments {
+        eprintln!(
+            "Reads mapped to known microbial contigs for [{}]: [{}]",
+            species, nreads
+        );
+        writeln!(
+            summary_writer,
+            "Species [{}] reads mapped to known microbial contigs\t{}",
+            species, nreads
+        )
+        .context("Failed to write bam summary")?;
     }
-}
 
-/// Check whether a bam sequence is considered 'good quality'.
-///
-/// A good quality *sequence* is likely to be a real biological
-/// sequence that should be fed into kraken downstream for read classification.
-/// Note a good quality sequence is not necessarily a good quality 'alignment'
-///
-/// A good quality sequence has the following properties
-/// 1. Reasonable length (>`min_len``)
-/// 2. Good Average Phred Scores (>=`min_phred`)
-/// 3. Contains very few ambiguous/masked nucleotides (Number of Ns < `max_n`)
-/// 4. Is not a PCR duplicate or flagged as 'is_quality_check_failed'
-/// 5. Has a reasonable sequence complexity (No homopolymer reads) (not yet implemented)
-///
-fn is_good_quality_sequence(
-    record: &BamRecordEnriched,
-    min_len: usize,
-    min_phred: f64,
-    max_n: usize,
-) -> bool {
-    // Start with the quick checks
+    // Novel insertions and viruses often get anchored to unplaced/random contigs
+    if let Some(patterns) = &config.unplaced_contig_patterns {
+        let unplaced_contigs = find_unplaced_contigs(bam.header(), patterns)?;
+        handled_tids.extend(unplaced_contigs.iter().map(|(_, tid)| *tid));
+        eprintln!(
+            "Scanning {} unplaced/random contigs for reads to classify",
+            unplaced_contigs.len()
+        );
+        let mut nreads_unplaced_total: u64 = 0;
+        for (contig_name, tid) in unplaced_contigs {
+            bam.fetch(tid).map_err(|err| {
+                index_error(
+                    err,
+                    bam_path,
+                    bam.header(),
+                    "Error fetching bam sequences from unplaced contigs",
+                )
+            })?;
+            let mut nreads_unplaced: u64 = 0;
+            for r in bam.records() {
+                let record = r.context("Failed to read bam record")?;
+                // Secondary/supplementary records (excluded by default) would duplicate their primary alignment
+                if record.is_unmapped()
+                    | !filters.passes_flags(record.flags())
+                    | (hard_clipped_bases(&record) > 0)
+                {
+                    continue;
+                }
+                let bam_record = parse_record(&record)?;
+                if is_good_quality_sequence(&bam_record, filters).is_pass() {
+                    nreads_unplaced += 1;
+                    fasta_writer
+                        .write_read(
+                            bam_record.fragment,
+                            bam_record.mate,
+                            bam_record.sequence.as_bytes(),
+                            record.qual(),
+                        )
+                        .context("Failed to write unplaced contig read to FASTA file")?;
+                }
+            }
+            nreads_unplaced_total += nreads_unplaced;
+            writeln!(
+                summary_writer,
+                "Unplaced contig [{}] good quality sequences\t{}",
+                contig_name, nreads_unplaced
+            )
+            .context("Failed to write bam summary")?;
+        }
+        eprintln!(
+            "\tgood quality sequences on unplaced contigs (sent to kraken): [{}]",
+            nreads_unplaced_total
+        );
+    }
 
-    if record.record.is_quality_check_failed()
-        | record.record.is_duplicate()
-        | (record.record.seq_len() < min_len)
+    // Full-bam scan for reads the passes above can't see:
+    // - One-end-anchored pairs: unmapped reads placed beside their mapped mate are missed by the unmapped fetch,
+    //   and their anchored mates are never looked at otherwise
+    // - Chimeric reads: a large soft-clip on a host alignment may be non-host sequence
+    // - Reads aligned to untrustworthy regions (see [ExtractionConfig::unmap_bed])
+    if config.include_mate_anchored || config.min_softclip_len.is_some() || unmap_regions.is_some()
     {
-        return false;
-    }
+        bam.fetch(FetchDefinition::All).map_err(|err| {
+            index_error(
+                err,
+                bam_path,
+                bam.header(),
+                "Failed to fetch all reads from bam",
+            )
+        })?;
+        let mut nreads_anchored: u64 = 0;
+        let mut nreads_anchored_good: u64 = 0;
+        let mut nreads_softclipped: u64 = 0;
+        let mut nsegments_softclip_good: u64 = 0;
+        let mut nreads_unmap_region: u64 = 0;
+        let mut nreads_unmap_region_good: u64 = 0;
+        for r in bam.records() {
+            let record = r.context("Failed to read bam record")?;
+            // Unplaced reads were covered by the unmapped pass
+            if record.tid() < 0 || handled_tids.contains(&(record.tid() as u32)) {
+                continue;
+            }
+            // Secondary/supplementary records (excluded by default) would duplicate their primary alignment
+            if !filters.passes_flags(record.flags()) {
+                continue;
+            }
 
-    // Run computationally intensive checks
-    // Ambiguous bases (N)
-    let has_ambiguous_bases: bool = seq_ambiguous(&record.sequence, max_n);
+            // The unmapped fetch only returns reads without a reference id, so none of these were written by it
+            if config.include_mate_anchored {
+                let anchored = record.is_paired()
+                    & (record.is_unmapped() != record.is_mate_unmapped())
+                    & (hard_clipped_bases(&record) == 0);
+                if anchored {
+                    nreads_anchored += 1;
+                    let bam_record = parse_record(&record)?;
+                    if is_good_quality_sequence(&bam_record, filters).is_pass() {
+                        nreads_anchored_good += 1;
+                        fasta_writer
+                            .write_read(
+                                bam_record.fragment,
+                                bam_record.mate,
+                                bam_record.sequence.as_bytes(),
+                                record.qual(),
+                            )
+                            .context("Failed to write mate-anchored read to FASTA file")?;
+                        // The whole read is already going to kraken
+                        continue;
+                    }
+                }
+            }
 
-    // Average Quality
-    let qual = record.record.qual();
-    let qual_average = calculate_average_phred(qual);
+            if let Some(regions) = &unmap_regions {
+                let in_region = !record.is_unmapped()
+                    && overlaps_any(
+                        &regions[record.tid() as usize],
+                        record.pos() as u64,
+                        record.cigar().end_pos() as u64,
+                    );
+                if in_region {
+                    nreads_unmap_region += 1;
+                    let bam_record = parse_record(&record)?;
+                    if is_good_quality_sequence(&bam_record, filters).is_pass() {
+                        nreads_unmap_region_good += 1;
+                        fasta_writer
+                            .write_read(
+                                bam_record.fragment,
+                                bam_record.mate,
+                                bam_record.sequence.as_bytes(),
+                                record.qual(),
+                            )
+                            .context("Failed to write read from an unmap region to FASTA file")?;
+                    }
+                    // Treated as unmapped, so its alignment (and any soft-clip) isn't looked at further
+                    continue;
+                }
+            }
 
-    if has_ambiguous_bases | (qual_average < min_phred) {
-        return false;
+            if let Some(min_softclip_len) = config.min_softclip_len {
+                if record.is_unmapped() {
+                    continue;
+                }
+                let segments = softclip_segments(&record, min_softclip_len);
+                if segments.is_empty() {
+                    continue;
+                }
+                nreads_softclipped += 1;
+                let sequence = sequence_in_ref_orientation(&record);
+                let qname = str::from_utf8(record.qname())
+                    .context("Failed to parse qname to string slice")?;
+                for (range, junction) in segments {
+                    let (clipped, qual) = (&sequence[range.clone()], &record.qual()[range]);
+                    if !is_good_quality_segment(&record, clipped, qual, filters) {
+                        continue;
+                    }
+                    nsegments_softclip_good += 1;
+                    fasta_writer
+                        .write_read(
+                            &format!("{}_SC{}", qname, junction),
+                            None,
+                            clipped.as_bytes(),
+                            qual,
+                        )
+                        .context("Failed to write soft-clipped segment to FASTA file")?;
+                }
+            }
+        }
+        if config.include_mate_anchored {
+            eprintln!("Mate-anchored Read Summary: ");
+            eprintln!(
+                "\treads from pairs with exactly one mate mapped: [{}]",
+                nreads_anchored
+            );
+            eprintln!(
+                "\tgood quality sequences (sent to kraken): [{}]",
+                nreads_anchored_good
+            );
+            writeln!(
+                summary_writer,
+                "mate-anchored reads good quality sequences\t{}",
+                nreads_anchored_good
+            )
+            .context("Failed to write bam summary")?;
+        }
+        if config.min_softclip_len.is_some() {
+            eprintln!("Soft-clipped Read Summary: ");
+            eprintln!(
+                "\tmapped reads with a large terminal soft-clip: [{}]",
+                nreads_softclipped
+            );
+            eprintln!(
+                "\tgood quality soft-clipped segments (sent to kraken): [{}]",
+                nsegments_softclip_good
+            );
+            writeln!(
+                summary_writer,
+                "soft-clipped segments good quality sequences\t{}",
+                nsegments_softclip_good
+            )
+            .context("Failed to write bam summary")?;
+        }
+        if unmap_regions.is_some() {
+            eprintln!("Unmap Region Read Summary: ");
+            eprintln!(
+                "\tmapped reads overlapping an unmap region: [{}]",
+                nreads_unmap_region
+            );
+            eprintln!(
+                "\tgood quality sequences (sent to kraken): [{}]",
+                nreads_unmap_region_good
+            );
+            writeln!(
+                summary_writer,
+                "unmap region reads good quality sequences\t{}",
+                nreads_unmap_region_good
+            )
+            .context("Failed to write bam summary")?;
+        }
     }
 
-    // TODO: Add a check based on sequence complexity
+    let reads_written =
+        fasta_writer.finish(Path::new(fasta_output_path), &mut summary_writer, config)?;
+    summary_writer
+        .flush()
+        .context("Failed to flush bam summary stats file")?;
+
+    if let Some(mut writer) = debug_writer {
+        writer.flush().context("Failed to flush debug reads file")?;
+    }
 
-    return true;
+    Ok(ExtractionSummary {
+        total_reads,
+        mapped_reads: total_mapped_reads,
+        unmapped_reads: total_unmapped_reads,
+        unmapped_good_quality_sequences,
+        unmapped_quality_failures,
+        species_good_alignments,
+        microbial_contigs_present,
+        reads_written,
+    })
 }
 
-/// Is the alignment convincing
-fn is_good_quality_alignment(
-    record: &BamRecordEnriched,
-    min_len: usize,
-    min_phred: f64,
-    max_n: usize,
-    min_mapq: u8,
-    min_alignment_score: i32,
-) -> bool {
-    // CHeck if sequence is good quality
-    let good_qual_sequence = is_good_quality_sequence(record, min_len, min_phred, max_n);
-    if !good_qual_sequence {
-        return false;
+/// Quality filter the reads of a FASTA/FASTQ file (e.g. host-depleted reads from an upstream step), writing the
+/// good quality sequences to `fasta_output_path` like [bam2unmappedreads] does for a bam.
+///
+/// Each read is wrapped in an unmapped [bam::Record] so [is_good_quality_sequence] judges it exactly like an
+/// unmapped read of a bam. FASTA reads have no qualities, so [ReadFilterConfig::min_phred] doesn't apply to them.
+/// The output options of `config` (FASTQ, paired output, dedup, compression) apply as for a bam;
+/// the bam-specific ones (contigs, regions, flags) don't. Every read counts as unmapped in the summary
+pub fn reads2goodreads(
+    reads_path: &str,
+    fasta_output_path: &str,
+    summary_output_path: &str,
+    filters: &ReadFilterConfig,
+    config: &ExtractionConfig,
+) -> Result<ExtractionSummary, Box<dyn std::error::Error>> {
+    let reader = crate::fastx::FastxReader::from_path(Path::new(reads_path))?;
+    let mut summary_writer = std::io::BufWriter::new(
+        std::fs::File::create(summary_output_path)
+            .context("failed to open connection to reads summary file")?,
+    );
+    let mut fasta_writer = ReadWriter::create(Path::new(fasta_output_path), config)?;
+
+    let mut total_reads: u64 = 0;
+    let mut good_quality_sequences: u64 = 0;
+    let mut quality_failures = QualityFailures::default();
+    let mut progress = ReadProgress::new("reads", 0, config.quiet);
+    let mut record = bam::Record::new();
+    for read in reader {
+        let read = read?;
+        progress.tick();
+        total_reads += 1;
+        // htslib's marker for absent (`*`) qualities
+        let qual = read.qual.unwrap_or_else(|| vec![0xFF; read.seq.len()]);
+        record.set(read.name.as_bytes(), None, &read.seq, &qual);
+        record.set_unmapped();
+        let bam_record = parse_record(&record)?;
+        let qc = is_good_quality_sequence(&bam_record, filters);
+        quality_failures.add(qc);
+        if qc.is_pass() {
+            good_quality_sequences += 1;
+            fasta_writer
+                .write_read(
+                    bam_record.fragment,
+                    bam_record.mate,
+                    bam_record.sequence.as_bytes(),
+                    record.qual(),
+                )
+                .context("Failed to write read to FASTA file")?;
+        }
     }
+    progress.finish();
+    eprintln!("Reads Summary: ");
+    eprintln!("\ttotal reads: [{}]", total_reads);
+    eprintln!("\tgood quality sequences: [{}]", good_quality_sequences);
+    eprintln!(
+        "\treads failing quality filters: {}",
+        quality_failures.describe()
+    );
+    writeln!(summary_writer, "total reads\t{}", total_reads)
+        .context("Failed to write reads summary")?;
+    writeln!(
+        summary_writer,
+        "good quality sequences\t{}",
+        good_quality_sequences
+    )
+    .context("Failed to write reads summary")?;
+    quality_failures
+        .write(&mut summary_writer, "reads")
+        .context("Failed to write reads summary")?;
+    let reads_written =
+        fasta_writer.finish(Path::new(fasta_output_path), &mut summary_writer, config)?;
+    summary_writer
+        .flush()
+        .context("Failed to flush reads summary file")?;
 
-    // Check if Alignment is good quality
-    //TODO: add an aditional check on absolute mapping quality between seq and ref (Maybe using AS tag)
-    !record.record.is_secondary()
-        & !record.record.is_quality_check_failed()
-        & !record.record.is_unmapped()
-        & (record.record.mapq() > min_mapq)
-        // Alignment Score 
-        & (record.alignment_score > min_alignment_score)
+    Ok(ExtractionSummary {
+        total_reads,
+        mapped_reads: 0,
+        unmapped_reads: total_reads,
+        unmapped_good_quality_sequences: good_quality_sequences,
+        unmapped_quality_failures: quality_failures,
+        species_good_alignments: std::collections::BTreeMap::new(),
+        microbial_contigs_present: false,
+        reads_written,
+    })
 }
 
-/// Check how many Ns in a string, and if greater than 'maxNs' return FALSE
-fn seq_ambiguous(seq: &str, max_n: usize) -> bool {
-    let number_of_ns = seq.chars().filter(|c| *c == 'N').count();
-    number_of_ns > max_n
+/// Read counts from [bam2unmappedreads] or [reads2goodreads] (the same numbers as the bam summary file)
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ExtractionSummary {
+    pub total_reads: u64,
+    pub mapped_reads: u64,
+    pub unmapped_reads: u64,
+    /// Unmapped reads passing the sequence quality filters
+    pub unmapped_good_quality_sequences: u64,
+    /// Unmapped reads failing the sequence quality filters, by reason
+    pub unmapped_quality_failures: QualityFailures,
+    /// Good quality alignments to known microbial contigs, by species (direct evidence, independent of kraken)
+    pub species_good_alignments: std::collections::BTreeMap<String, u64>,
+    /// Whether the bam header has any known microbial contigs. Without them (e.g. GRCh38 without decoys)
+    /// [ExtractionSummary::species_good_alignments] is always empty, whatever the sample contains
+    pub microbial_contigs_present: bool,
+    /// Records written to the reads file for kraken, from every pass
+    pub reads_written: u64,
 }
 
-fn calculate_average_phred(qual_scores: &[u8]) -> f64 {
-    let total: u32 = qual_scores.iter().map(|&score| score as u32).sum();
-    let count = qual_scores.len();
+/// A buffered reads file, gzip compressed if its path ends in `.gz`
+enum ReadsFile {
+    Plain(std::io::BufWriter<std::fs::File>),
+    Gzip(flate2::write::GzEncoder<std::io::BufWriter<std::fs::File>>),
+}
 
-    if count > 0 {
-        total as f64 / count as f64
+impl ReadsFile {
+    fn create(path: &Path) -> std::io::Result<Self> {
+        let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        if path.extension().is_some_and(|ext| ext == "gz") {
+            Ok(ReadsFile::Gzip(flate2::write::GzEncoder::new(
+                file,
+                flate2::Compression::default(),
+            )))
+        } else {
+            Ok(ReadsFile::Plain(file))
+        }
+    }
+
+    /// Flush buffered reads and write the gzip trailer
+    fn finish(self) -> std::io::Result<()> {
+        match self {
+            ReadsFile::Plain(mut file) => file.flush(),
+            ReadsFile::Gzip(encoder) => encoder.finish()?.flush(),
+        }
+    }
+}
+
+impl Write for ReadsFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            ReadsFile::Plain(file) => file.write(buf),
+            ReadsFile::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            ReadsFile::Plain(file) => file.flush(),
+            ReadsFile::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Writes extracted reads as FASTA, or as FASTQ when `fastq` is set
+struct ReadWriter {
+    writer: ReadsFile,
+    fastq: bool,
+    nreads_written: u64,
+    /// Reads written as FASTA in FASTQ mode because they have no base qualities
+    nreads_missing_qual: u64,
+    /// Paired output files and mates waiting for their partner (only when paired output is requested)
+    mates: Option<MateBuffer>,
+    /// Length and GC content of everything written
+    stats: ReadStats,
+    /// Hashes of the (uppercased) sequences written so far, when deduplicating by sequence
+    seen_sequences: Option<std::collections::HashSet<u64>>,
+    /// Reads skipped because their sequence was already written
+    nreads_duplicate: u64,
+}
+
+impl ReadWriter {
+    /// Open the reads file for [bam2unmappedreads] (or [reads2goodreads]) with the output options of `config`
+    fn create(path: &Path, config: &ExtractionConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(ReadWriter {
+            writer: ReadsFile::create(path)
+                .context("fasta file to output unmapped reads could not be created")?,
+            fastq: config.emit_fastq,
+            nreads_written: 0,
+            nreads_missing_qual: 0,
+            mates: config
+                .paired_output
+                .then(|| {
+                    let (r1, r2) = paired_output_paths(path);
+                    MateBuffer::create(&r1, &r2)
+                })
+                .transpose()
+                .context("Failed to create paired read files")?,
+            stats: ReadStats::default(),
+            seen_sequences: config.dedup_sequences.then(std::collections::HashSet::new),
+            nreads_duplicate: 0,
+        })
+    }
+
+    /// Write any paired output next to `path`, add the writer's counts to the summary and finish the reads file.
+    /// Returns the number of reads written
+    fn finish(
+        mut self,
+        path: &Path,
+        summary_writer: &mut impl Write,
+        config: &ExtractionConfig,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        if let Some(mates) = self.mates.take() {
+            let (r1, r2) = paired_output_paths(path);
+            let (orphans, duplicate_pairs) = mates
+                .finish()
+                .context("Failed to finish paired read files")?;
+            eprintln!(
+                "Wrote paired reads to {} and {} ({} orphan mates dropped)",
+                r1.display(),
+                r2.display(),
+                orphans
+            );
+            writeln!(
+                summary_writer,
+                "orphan mates dropped from paired output\t{}",
+                orphans
+            )
+            .context("Failed to write bam summary")?;
+            if config.dedup_sequences {
+                eprintln!(
+                    "Dropped [{}] pairs with a duplicate sequence from paired output",
+                    duplicate_pairs
+                );
+                writeln!(
+                    summary_writer,
+                    "pairs with a duplicate sequence dropped from paired output\t{}",
+                    duplicate_pairs
+                )
+                .context("Failed to write bam summary")?;
+            }
+        }
+
+        self.stats
+            .write(summary_writer)
+            .context("Failed to write bam summary")?;
+        if config.dedup_sequences {
+            eprintln!(
+                "Collapsed [{}] reads with duplicate sequences",
+                self.nreads_duplicate
+            );
+            writeln!(
+                summary_writer,
+                "duplicate sequences collapsed\t{}",
+                self.nreads_duplicate
+            )
+            .context("Failed to write bam summary")?;
+        }
+
+        if self.nreads_missing_qual > 0 {
+            eprintln!(
+                "Warning: {} reads have no base qualities and were written as FASTA records",
+                self.nreads_missing_qual
+            );
+        }
+
+        self.writer
+            .finish()
+            .context("Failed to finish writing unmapped reads")?;
+        Ok(self.nreads_written)
+    }
+
+    /// Write a read named `name`, suffixed `/1` or `/2` if it's a mate of a paired read
+    fn write_read(
+        &mut self,
+        name: &str,
+        mate: Option<u8>,
+        sequence: &[u8],
+        qual: &[u8],
+    ) -> std::io::Result<()> {
+        if let Some(seen) = self.seen_sequences.as_mut() {
+            use std::hash::{Hash, Hasher};
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            sequence.to_ascii_uppercase().hash(&mut hasher);
+            if !seen.insert(hasher.finish()) {
+                self.nreads_duplicate += 1;
+                // Drop the partner too, so the paired files stay in sync
+                if let (Some(mates), Some(mate)) = (self.mates.as_mut(), mate) {
+                    mates.discard(name, mate);
+                }
+                return Ok(());
+            }
+        }
+        let header = mate_header(name, mate);
+        let formatted = self.format_read(&header, sequence, qual)?;
+        self.writer.write_all(formatted.as_bytes())?;
+        self.nreads_written += 1;
+        self.stats.add(sequence);
+        if let (Some(mates), Some(mate)) = (self.mates.as_mut(), mate) {
+            mates.insert(name, mate, formatted)?;
+        }
+        Ok(())
+    }
+
+    fn format_read(
+        &mut self,
+        header: &str,
+        sequence: &[u8],
+        qual: &[u8],
+    ) -> std::io::Result<String> {
+        let sequence = str::from_utf8(sequence).map_err(|err| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Sequence of read [{}] is not valid ASCII: {}", header, err),
+            )
+        })?;
+        if !self.fastq {
+            return Ok(format!(">{}\n{}\n", header, sequence));
+        }
+        // htslib stores an absent (`*`) quality string as 0xFF
+        if qual.first() == Some(&0xFF) {
+            self.nreads_missing_qual += 1;
+            return Ok(format!(">{}\n{}\n", header, sequence));
+        }
+        // Phred 93 (`~`) is the highest FASTQ can encode; clamp so a malformed quality can't overflow
+        let qual: String = qual.iter().map(|&q| (q.min(93) + 33) as char).collect();
+        Ok(format!("@{}\n{}\n+\n{}\n", header, sequence, qual))
+    }
+}
+
+/// Minimum time between progress bar redraws
+const PROGRESS_REDRAW_INTERVAL: std::time::Duration = std::time::Duration::from_millis(200);
+
+/// Progress bar for a pass over bam records, drawn on stderr so piped reads aren't touched.
+/// Only drawn when stderr is a terminal
+struct ReadProgress {
+    label: String,
+    /// Expected number of records (from idxstats), 0 if unknown
+    total: u64,
+    done: u64,
+    start: std::time::Instant,
+    last_draw: std::time::Instant,
+    enabled: bool,
+}
+
+impl ReadProgress {
+    fn new(label: &str, total: u64, quiet: bool) -> Self {
+        let now = std::time::Instant::now();
+        ReadProgress {
+            label: label.to_string(),
+            total,
+            done: 0,
+            start: now,
+            last_draw: now,
+            enabled: !quiet && std::io::stderr().is_terminal(),
+        }
+    }
+
+    fn tick(&mut self) {
+        self.done += 1;
+        // Only look at the clock every so often: this runs once per record
+        if self.enabled
+            && self.done.is_multiple_of(1024)
+            && self.last_draw.elapsed() >= PROGRESS_REDRAW_INTERVAL
+        {
+            self.last_draw = std::time::Instant::now();
+            eprint!(
+                "\r\x1b[2K{}",
+                progress_line(&self.label, self.done, self.total, self.start.elapsed())
+            );
+        }
+    }
+
+    /// Clear the bar so it doesn't run into the log lines that follow
+    fn finish(&self) {
+        if self.enabled {
+            eprint!("\r\x1b[2K");
+        }
+    }
+}
+
+/// e.g. `unmapped reads [##########----------] 500/1000 (50%) 100 reads/s ETA 5s`
+fn progress_line(label: &str, done: u64, total: u64, elapsed: std::time::Duration) -> String {
+    const WIDTH: usize = 20;
+    let secs = elapsed.as_secs_f64();
+    let rate = if secs > 0.0 { done as f64 / secs } else { 0.0 };
+    if total == 0 {
+        return format!("{} {} reads {:.0} reads/s", label, done, rate);
+    }
+    let fraction = (done as f64 / total as f64).min(1.0);
+    let filled = (fraction * WIDTH as f64) as usize;
+    let eta = if rate > 0.0 {
+        total.saturating_sub(done) as f64 / rate
     } else {
         0.0
+    };
+    format!(
+        "{} [{}{}] {}/{} ({:.0}%) {:.0} reads/s ETA {:.0}s",
+        label,
+        "#".repeat(filled),
+        "-".repeat(WIDTH - filled),
+        done,
+        total,
+        fraction * 100.0,
+        rate,
+        eta
+    )
+}
+
+/// Width of the read length histogram buckets in the bam summary
+const READ_LENGTH_BUCKET: usize = 50;
+
+/// Read length distribution and GC content of the reads sent to kraken, accumulated as they are written
+#[derive(Default)]
+struct ReadStats {
+    /// Number of reads of each length
+    length_counts: std::collections::BTreeMap<usize, u64>,
+    gc_bases: u64,
+    /// A, C, G and T bases (ambiguous bases don't count towards GC content)
+    acgt_bases: u64,
+}
+
+impl ReadStats {
+    fn add(&mut self, sequence: &[u8]) {
+        *self.length_counts.entry(sequence.len()).or_insert(0) += 1;
+        for base in sequence {
+            match base.to_ascii_uppercase() {
+                b'G' | b'C' => {
+                    self.gc_bases += 1;
+                    self.acgt_bases += 1;
+                }
+                b'A' | b'T' => self.acgt_bases += 1,
+                _ => {}
+            }
+        }
+    }
+
+    fn nreads(&self) -> u64 {
+        self.length_counts.values().sum()
+    }
+
+    fn mean_length(&self) -> f64 {
+        let nbases: u64 = self
+            .length_counts
+            .iter()
+            .map(|(&len, &n)| len as u64 * n)
+            .sum();
+        nbases as f64 / self.nreads().max(1) as f64
+    }
+
+    /// Length of the `n`th shortest read (0-based)
+    fn nth_length(&self, n: u64) -> usize {
+        let mut seen = 0;
+        for (&len, &count) in &self.length_counts {
+            seen += count;
+            if seen > n {
+                return len;
+            }
+        }
+        0
+    }
+
+    fn median_length(&self) -> f64 {
+        let nreads = self.nreads();
+        if nreads == 0 {
+            return 0.0;
+        }
+        (self.nth_length((nreads - 1) / 2) + self.nth_length(nreads / 2)) as f64 / 2.0
+    }
+
+    /// Length such that reads at least this long hold half of all bases
+    fn n50(&self) -> usize {
+        let nbases: u64 = self
+            .length_counts
+            .iter()
+            .map(|(&len, &n)| len as u64 * n)
+            .sum();
+        let mut cumulative = 0;
+        for (&len, &count) in self.length_counts.iter().rev() {
+            cumulative += len as u64 * count;
+            if cumulative * 2 >= nbases {
+                return len;
+            }
+        }
+        0
+    }
+
+    fn gc_fraction(&self) -> f64 {
+        self.gc_bases as f64 / self.acgt_bases.max(1) as f64
+    }
+
+    /// Write summary statistics and a bucketed length histogram as bam summary lines
+    fn write(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        writeln!(
+            writer,
+            "passed reads mean length\t{:.2}",
+            self.mean_length()
+        )?;
+        writeln!(
+            writer,
+            "passed reads median length\t{}",
+            self.median_length()
+        )?;
+        writeln!(writer, "passed reads N50\t{}", self.n50())?;
+        writeln!(
+            writer,
+            "passed reads GC fraction\t{:.4}",
+            self.gc_fraction()
+        )?;
+        let mut buckets: std::collections::BTreeMap<usize, u64> = std::collections::BTreeMap::new();
+        for (&len, &count) in &self.length_counts {
+            *buckets.entry(len / READ_LENGTH_BUCKET).or_insert(0) += count;
+        }
+        for (bucket, count) in buckets {
+            writeln!(
+                writer,
+                "passed reads length [{}-{}]\t{}",
+                bucket * READ_LENGTH_BUCKET,
+                (bucket + 1) * READ_LENGTH_BUCKET - 1,
+                count
+            )?;
+        }
+        Ok(())
     }
 }
 
-struct SeqClassification {
-    ambiguous: bool,
-    low_complexity: bool,
+/// `<name>/1` or `<name>/2` for mates of paired reads, otherwise just `name`
+fn mate_header(name: &str, mate: Option<u8>) -> String {
+    match mate {
+        Some(mate) => format!("{}/{}", name, mate),
+        None => name.to_string(),
+    }
 }
-#[derive(Debug, serde::Deserialize)]
-struct MicrobialContigRecords {
-    taxid: String,
-    common_name: String,
-    contigs: String,
+
+/// Paired output (`_R1` and `_R2` files), written in sync.
+/// Mates are met at unrelated points in the bam (e.g. one unmapped, one on a microbial contig), so the first mate
+/// of each fragment is held until its partner turns up; the pair is then written and forgotten.
+/// Only mates still waiting when extraction finishes (orphans) are held to the end.
+/// A pair with a mate collapsed by sequence deduplication is dropped as a whole (see [MateBuffer::discard])
+struct MateBuffer {
+    r1: ReadsFile,
+    r2: ReadsFile,
+    pending: std::collections::HashMap<String, [Option<String>; 2]>,
+    /// Hashes of fragments already written or discarded, so a later record of either mate isn't taken for a new orphan
+    written: std::collections::HashSet<u64>,
+    /// Pairs discarded because a mate's sequence was a duplicate
+    nduplicate_pairs: u64,
 }
-struct Contig {
-    contig: String,
-    taxid: String,
-    species: String,
+
+impl MateBuffer {
+    fn create(r1: &Path, r2: &Path) -> std::io::Result<Self> {
+        Ok(MateBuffer {
+            r1: ReadsFile::create(r1)?,
+            r2: ReadsFile::create(r2)?,
+            pending: std::collections::HashMap::new(),
+            written: std::collections::HashSet::new(),
+            nduplicate_pairs: 0,
+        })
+    }
+
+    fn fragment_hash(fragment: &str) -> u64 {
+        use std::hash::{Hash, Hasher};
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        fragment.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn insert(&mut self, fragment: &str, mate: u8, formatted: String) -> std::io::Result<()> {
+        if self.written.contains(&Self::fragment_hash(fragment)) {
+            return Ok(());
+        }
+        let mates = self.pending.entry(fragment.to_string()).or_default();
+        // Keep the first record seen for each mate (e.g. ignore a later secondary alignment)
+        mates[mate as usize - 1].get_or_insert(formatted);
+        if let [Some(mate1), Some(mate2)] = mates {
+            self.r1.write_all(mate1.as_bytes())?;
+            self.r2.write_all(mate2.as_bytes())?;
+            self.pending.remove(fragment);
+            self.written.insert(Self::fragment_hash(fragment));
+        }
+        Ok(())
+    }
+
+    /// Drop the pair of a mate that wasn't written because its sequence was a duplicate,
+    /// along with its partner if that's already waiting
+    fn discard(&mut self, fragment: &str, mate: u8) {
+        let hash = Self::fragment_hash(fragment);
+        if self.written.contains(&hash) {
+            return;
+        }
+        // Another record of a mate already held is the same read seen again, not a duplicate
+        if self
+            .pending
+            .get(fragment)
+            .is_some_and(|mates| mates[mate as usize - 1].is_some())
+        {
+            return;
+        }
+        self.pending.remove(fragment);
+        self.written.insert(hash);
+        self.nduplicate_pairs += 1;
+    }
+
+    /// Finish both files. Returns the number of orphan mates dropped and of pairs discarded as duplicates
+    fn finish(self) -> std::io::Result<(u64, u64)> {
+        self.r1.finish()?;
+        self.r2.finish()?;
+        Ok((self.pending.len() as u64, self.nduplicate_pairs))
+    }
 }
 
-/// A collection of microbial contigs.
-/// Use the `contains` method to see if a particular contig name is in the list
-pub struct MicrobialContigs {
-    contigs: Vec<Contig>,
+/// `<dir>/<stem>.<ext>[.gz]` -> (`<dir>/<stem>_R1.<ext>[.gz]`, `<dir>/<stem>_R2.<ext>[.gz]`)
+fn paired_output_paths(reads: &Path) -> (PathBuf, PathBuf) {
+    let name = reads
+        .file_name()
+        .expect("failed to extract file name")
+        .to_string_lossy();
+    let (name, gz) = match name.strip_suffix(".gz") {
+        Some(name) => (name, ".gz"),
+        None => (name.as_ref(), ""),
+    };
+    let (stem, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+    let ext = if ext.is_empty() {
+        gz.to_string()
+    } else {
+        format!(".{}{}", ext, gz)
+    };
+    (
+        reads.with_file_name(format!("{}_R1{}", stem, ext)),
+        reads.with_file_name(format!("{}_R2{}", stem, ext)),
+    )
 }
 
-impl MicrobialContigs {
-    // Check if InterestingContigs contain a particular contig name
-    fn contains(&self, contig_name: &str) -> bool {
-        let contigs_in_set: Vec<&str> = self.contigs.iter().map(|c| c.contig.as_str()).collect();
-        contigs_in_set.contains(&contig_name)
+/// Name of one segment (`clip` or `aln`) of a soft-clip split read, keeping any mate suffix last
+fn segment_name(record: &BamRecordEnriched, segment: &str) -> String {
+    match record.mate {
+        Some(mate) => format!("{}_{}/{}", record.fragment, segment, mate),
+        None => format!("{}_{}", record.fragment, segment),
     }
+}
 
-    // If Taxid
-    fn contig_to_species(&self, contig_name: &str) -> Option<&str> {
-        let species = self
-            .contigs
+/// One row of the per-read debug TSV
+#[derive(Debug, serde::Serialize)]
+struct DebugRead<'a> {
+    qname: &'a str,
+    /// Name the read is written to the reads file (and so kraken's output) under
+    read_id: &'a str,
+    length: usize,
+    avg_phred: f64,
+    n_count: usize,
+    is_dup: bool,
+    is_qc_fail: bool,
+    complexity: f64,
+    longest_homopolymer: usize,
+    tandem_repeat_fraction: f64,
+    alignment_score: i32,
+    passed: bool,
+    fail_reason: &'a str,
+}
+
+/// Write one row of the per-read debug TSV, recording the value behind every quality criterion
+/// (not just the first one that failed)
+fn write_debug_read<W: Write>(
+    writer: &mut csv::Writer<W>,
+    record: &BamRecordEnriched,
+    filters: &ReadFilterConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let qc = is_good_quality_sequence(record, filters);
+    let avg_phred =
+        calculate_average_phred_called(record.sequence.as_bytes(), record.record.qual());
+    let round = |x: f64| (x * 1000.0).round() / 1000.0;
+    writer
+        .serialize(DebugRead {
+            qname: record.qname,
+            read_id: &mate_header(record.fragment, record.mate),
+            length: record.record.seq_len(),
+            avg_phred: (avg_phred * 100.0).round() / 100.0,
+            n_count: count_ns(&record.sequence),
+            is_dup: record.record.is_duplicate(),
+            is_qc_fail: record.record.is_quality_check_failed(),
+            complexity: round(sequence_complexity(&record.sequence)),
+            longest_homopolymer: longest_homopolymer(record.sequence.as_bytes()),
+            tandem_repeat_fraction: round(tandem_repeat_fraction(record.sequence.as_bytes())),
+            alignment_score: record.alignment_score,
+            passed: qc.is_pass(),
+            fail_reason: if qc.is_pass() { "" } else { qc.as_str() },
+        })
+        .context("Failed to write to debug reads file")
+}
+
+/// Add a `classified_taxid` column to the per-read debug TSV from kraken's per-read output:
+/// the taxid each read was classified to (0 if unclassified), empty for reads that weren't sent to kraken.
+/// Returns the number of debug rows matched to a kraken assignment
+pub fn add_classified_taxids(
+    debug_reads: &Path,
+    kout: &Path,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let taxids = crate::kraken::kout_taxids(kout)
+        .map_err(|err| format!("Failed to read kraken output [{}]: {}", kout.display(), err))?;
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .from_path(debug_reads)?;
+    let annotated = debug_reads.with_extension("annotated.tmp");
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .from_path(&annotated)?;
+
+    // A file annotated by an earlier (resumed) run has its old classifications replaced
+    let previous = reader
+        .headers()?
+        .iter()
+        .position(|column| column == "classified_taxid");
+    let without_previous = |record: &csv::StringRecord| -> csv::StringRecord {
+        record
             .iter()
-            .filter(|c| c.contig.as_str() == contig_name)
-            .map(|c| c.species.as_str())
-            .next();
+            .enumerate()
+            .filter(|(i, _)| Some(*i) != previous)
+            .map(|(_, field)| field)
+            .collect()
+    };
+    let mut header = without_previous(reader.headers()?);
+    let read_id = header
+        .iter()
+        .position(|column| column == "read_id")
+        .context("debug reads file has no read_id column")?;
+    header.push_field("classified_taxid");
+    writer.write_record(&header)?;
+    let mut nmatched = 0;
+    for row in reader.records() {
+        let mut row = without_previous(&row?);
+        let taxid = taxids.get(&row[read_id]).map(u64::to_string);
+        nmatched += taxid.is_some() as u64;
+        row.push_field(taxid.as_deref().unwrap_or(""));
+        writer.write_record(&row)?;
+    }
+    writer.flush()?;
+    std::fs::rename(&annotated, debug_reads)?;
+    Ok(nmatched)
+}
+
+/// Read-name conventions used to tell mates of the same fragment apart
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum MateSuffix {
+    /// Strip whichever recognised suffix is present (`/1`, `/2` or Illumina ` 1:...`, ` 2:...`)
+    #[default]
+    Auto,
+    /// Only strip Illumina (Casava 1.8+) style comments: ` 1:N:0:ACGT`
+    Illumina,
+    /// Mates share identical names and are distinguished only by their flags
+    None,
+}
 
-        species
+/// Split a read name into its fragment key and the mate number encoded in its suffix (if any)
+fn split_mate_suffix(qname: &str, mode: MateSuffix) -> (&str, Option<u8>) {
+    let mate = |c: u8| match c {
+        b'1' => Some(1),
+        b'2' => Some(2),
+        _ => None,
+    };
+    let bytes = qname.as_bytes();
+
+    if matches!(mode, MateSuffix::Auto | MateSuffix::Illumina) {
+        if let Some(space) = qname.find(' ') {
+            let comment = &bytes[space + 1..];
+            if comment.len() >= 2 && comment[1] == b':' {
+                if let Some(m) = mate(comment[0]) {
+                    return (&qname[..space], Some(m));
+                }
+            }
+        }
     }
+
+    if mode == MateSuffix::Auto && bytes.len() > 2 && bytes[bytes.len() - 2] == b'/' {
+        if let Some(m) = mate(bytes[bytes.len() - 1]) {
+            return (&qname[..qname.len() - 2], Some(m));
+        }
+    }
+
+    (qname, None)
 }
 
-pub fn common_microbial_contigs() -> MicrobialContigs {
-    MicrobialContigs {
-        contigs: vec![
-            //EBV
-            Contig {
-                contig: "chrEBV".to_string(),
-                taxid: "10376".to_string(),
-                species: "EBV".to_string(),
-            },
-            Contig {
-                contig: "NC_009334".to_string(),
-                taxid: "10376".to_string(),
-                species: "EBV".to_string(),
-            },
-            Contig {
-                contig: "NC_007605".to_string(),
-                taxid: "10376".to_string(),
-                species: "EBV".to_string(),
-            },
-            //HHV6B
-            Contig {
-                contig: "NC_000898".to_string(),
-                taxid: "10376".to_string(),
-                species: "HHV6B".to_string(),
-            },
-        ],
+/// Normalise a read name to a key shared by both mates of a fragment
+pub fn fragment_key(qname: &str, mode: MateSuffix) -> &str {
+    split_mate_suffix(qname, mode).0
+}
+
+/// Which mate (1 or 2) a record is.
+/// The first/last-in-template flags are authoritative; the read name suffix is only used when the flags are unset
+pub fn mate_number(record: &bam::Record, mode: MateSuffix) -> Option<u8> {
+    if record.is_paired() {
+        if record.is_first_in_template() {
+            return Some(1);
+        }
+        if record.is_last_in_template() {
+            return Some(2);
+        }
     }
+    let qname = str::from_utf8(record.qname()).ok()?;
+    split_mate_suffix(qname, mode).1
 }
 
-#[cfg(test)]
-mod tests {
+// A custom struct that adds a couple of key properties to bam::record
+pub(crate) struct BamRecordEnriched<'a> {
+    record: &'a rust_htslib::bam::Record,
+    qname: &'a str,
+    /// `qname` without any `/1` or `/2` mate suffix
+    fragment: &'a str,
+    /// Mate number (1 or 2) of paired reads
+    mate: Option<u8>,
+    sequence: String,
+    /// Raw `AS` tag (0 if absent)
+    alignment_score: i32,
+    /// `AS` per base of SEQ, so reads of different lengths are comparable
+    normalized_alignment_score: f64,
+}
 
-    #[test]
-    fn microbial_contigs() {
-        let microcontigs = crate::bam::common_microbial_contigs();
-        assert!(microcontigs.contains("NC_007605"));
-        assert_eq!(microcontigs.contig_to_species("NC_007605").unwrap(), "EBV");
-        assert_eq!(
-            microcontigs.contig_to_species("NC_000898").unwrap(),
-            "HHV6B"
-        );
-        assert!(microcontigs.contig_to_species("ADAWD").is_none());
+fn get_as_tag(record: &bam::Record) -> Option<i32> {
+    match record.aux(b"AS") {
+        Ok(Aux::I8(value)) => Some(value as i32),
+        Ok(Aux::U8(value)) => Some(value as i32),
+        Ok(Aux::I16(value)) => Some(value as i32),
+        Ok(Aux::U16(value)) => Some(value as i32),
+        Ok(Aux::I32(value)) => Some(value),
+        Ok(Aux::U32(value)) => Some(value as i32),
+        Ok(_) => None, // The AS tag exists but is of an unexpected type
+        Err(Error::BamAuxTagNotFound) => None, // AS tag not found
+        Err(e) => {
+            // Handle other potential errors
+            eprintln!("Error retrieving AS tag: {}", e);
+            None
+        }
+    }
+}
+
+/// Replace characters that are awkward in filenames (e.g. `|` in `gi|123|ref|...` contig names)
+pub(crate) fn sanitize_filename(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Contigs in the bam header that are in the microbial contig list, as (name, target id) pairs
+fn find_microbial_contigs(
+    header: &bam::HeaderView,
+    microbial_contigs: &MicrobialContigs,
+) -> Result<Vec<(String, u32)>, Box<dyn std::error::Error>> {
+    // Lookups are hashed, so intersecting with the header is linear, even for large panels
+    let mut found = vec![];
+    for (tid, name) in header.target_names().iter().enumerate() {
+        let name = str::from_utf8(name).context("Failed to parse contig name to string slice")?;
+        if microbial_contigs.contains(name) {
+            found.push((name.to_string(), tid as u32));
+        }
+    }
+    Ok(found)
+}
+
+/// Longest contig a BAI index can address. Longer contigs (some plant genomes, decoy-augmented references) need CSI
+const MAX_BAI_CONTIG_LEN: u64 = (1 << 29) - 1;
+
+/// Name and length of the first contig too long for a BAI index, if any
+fn contig_too_long_for_bai(header: &bam::HeaderView) -> Option<(String, u64)> {
+    (0..header.target_count())
+        .map(|tid| {
+            (
+                String::from_utf8_lossy(header.tid2name(tid)).into_owned(),
+                header.target_len(tid).unwrap_or(0),
+            )
+        })
+        .find(|(_, len)| *len > MAX_BAI_CONTIG_LEN)
+}
+
+/// Index to build for a bam with this header: BAI, or CSI when a contig is too long for BAI
+fn index_type(header: &bam::HeaderView) -> bam::index::Type {
+    if contig_too_long_for_bai(header).is_some() {
+        bam::index::Type::Csi(14)
+    } else {
+        bam::index::Type::Bai
+    }
+}
+
+/// Explain a failed index build or fetch. htslib refuses positions past 2^29 bp in a BAI index
+/// ("cannot be stored in a bai index"), which rust_htslib only reports as a bare error,
+/// so point at the CSI index the bam needs instead
+fn index_error(
+    err: Error,
+    bam_path: &str,
+    header: &bam::HeaderView,
+    what: &str,
+) -> Box<dyn std::error::Error> {
+    let has_csi = Path::new(&format!("{bam_path}.csi")).exists();
+    match contig_too_long_for_bai(header) {
+        Some((contig, len)) if !bam_path.ends_with(".cram") && !has_csi => format!(
+            "{}: {}. Contig [{}] ({} bp) is too long for a BAI index; please use a CSI index instead (samtools index -c {})",
+            what, err, contig, len, bam_path
+        )
+        .into(),
+        _ => format!("{}: {}", what, err).into(),
+    }
+}
+
+/// Build an index for `bam_path` if none exists alongside it.
+///
+/// Defaults to BAI, switching to CSI when any contig is too long for BAI (see [index_type]).
+/// Fails with an actionable message if the input isn't coordinate-sorted
+fn ensure_index(bam_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let candidates: &[&str] = if bam_path.ends_with(".cram") {
+        &["crai"]
+    } else {
+        &["bai", "csi"]
+    };
+    if candidates
+        .iter()
+        .any(|ext| Path::new(&format!("{bam_path}.{ext}")).exists())
+    {
+        return Ok(());
+    }
+
+    let reader = bam::Reader::from_path(bam_path)
+        .map_err(|err| format!("Failed to open [{}]: {}", bam_path, err))?;
+    let header = reader.header();
+    let sort_order = bam::Header::from_template(header)
+        .to_hashmap()
+        .get("HD")
+        .and_then(|hd| hd.first())
+        .and_then(|hd| hd.get("SO").cloned());
+    if sort_order.as_deref() != Some("coordinate") {
+        return Err(format!(
+            "[{}] has no index and is not coordinate-sorted (SO: {}). Please sort it first (e.g. samtools sort) and re-run",
+            bam_path,
+            sort_order.as_deref().unwrap_or("unknown")
+        )
+        .into());
+    }
+
+    eprintln!("No index found for [{}]: building one", bam_path);
+    let start = std::time::Instant::now();
+    bam::index::build(bam_path, None, index_type(header), 1).map_err(|err| {
+        index_error(
+            err,
+            bam_path,
+            header,
+            &format!("Failed to build index for [{}]", bam_path),
+        )
+    })?;
+    eprintln!("\tbuilt index in {:.2}s", start.elapsed().as_secs_f64());
+    Ok(())
+}
+
+/// idxstats rows: (tid, length, mapped, unmapped)
+type ContigReadCounts = Vec<(i64, u64, u64, u64)>;
+
+/// idxstats-style (tid, length, mapped, unmapped) counts computed by reading every record.
+///
+/// Used for CRAM, where rust_htslib's slow_idxstats fallback panics on unplaced unmapped reads (tid -1)
+fn count_reads_per_contig(
+    bam: &mut bam::IndexedReader,
+) -> Result<ContigReadCounts, Box<dyn std::error::Error>> {
+    let ncontigs = bam.header().target_count() as usize;
+    let lengths: Vec<u64> = (0..ncontigs as u32)
+        .map(|tid| bam.header().target_len(tid).unwrap_or(0))
+        .collect();
+
+    // Last slot holds unplaced reads (tid -1)
+    let mut counts = vec![(0u64, 0u64); ncontigs + 1];
+    bam.fetch(FetchDefinition::All)
+        .context("Failed to fetch all reads to compute read counts")?;
+    for r in bam.records() {
+        let record = r.context("Failed to read bam record")?;
+        let slot = if record.tid() < 0 {
+            ncontigs
+        } else {
+            record.tid() as usize
+        };
+        if record.is_unmapped() {
+            counts[slot].1 += 1
+        } else {
+            counts[slot].0 += 1
+        }
+    }
+
+    Ok(counts
+        .into_iter()
+        .enumerate()
+        .map(|(i, (mapped, unmapped))| {
+            if i == ncontigs {
+                (-1, 0, mapped, unmapped)
+            } else {
+                (i as i64, lengths[i], mapped, unmapped)
+            }
+        })
+        .collect())
+}
+
+/// Contigs in the bam header whose name contains any of `patterns`, as (name, target id) pairs
+fn find_unplaced_contigs(
+    header: &bam::HeaderView,
+    patterns: &[String],
+) -> Result<Vec<(String, u32)>, Box<dyn std::error::Error>> {
+    let mut found = vec![];
+    for (tid, name) in header.target_names().iter().enumerate() {
+        let name = str::from_utf8(name).context("Failed to parse contig name to string slice")?;
+        if patterns.iter().any(|p| name.contains(p.as_str())) {
+            found.push((name.to_string(), tid as u32));
+        }
+    }
+    Ok(found)
+}
+
+/// Number of hard-clipped bases (`H` in CIGAR). These bases are not present in SEQ
+fn hard_clipped_bases(record: &bam::Record) -> u32 {
+    record
+        .cigar()
+        .iter()
+        .map(|c| match c {
+            Cigar::HardClip(len) => *len,
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Lengths of the leading and trailing soft-clips (`S` in CIGAR). Soft-clipped bases are present in SEQ
+fn terminal_softclips(record: &bam::Record) -> (usize, usize) {
+    let cigar = record.cigar();
+    // Soft-clips can only be separated from the ends of the read by hard-clips
+    let leading = cigar
+        .iter()
+        .find(|c| !matches!(c, Cigar::HardClip(_)))
+        .map_or(0, |c| match c {
+            Cigar::SoftClip(len) => *len as usize,
+            _ => 0,
+        });
+    let trailing = cigar
+        .iter()
+        .rev()
+        .find(|c| !matches!(c, Cigar::HardClip(_)))
+        .map_or(0, |c| match c {
+            Cigar::SoftClip(len) => *len as usize,
+            _ => 0,
+        });
+    (leading, trailing)
+}
+
+/// Terminal soft-clips at least `min_len` long, as (offsets of the clipped bases in SEQ,
+/// 1-based reference position of the aligned base at the clip junction)
+fn softclip_segments(record: &bam::Record, min_len: usize) -> Vec<(std::ops::Range<usize>, i64)> {
+    let (leading, trailing) = terminal_softclips(record);
+    let seq_len = record.seq_len();
+    let mut segments = vec![];
+    if leading >= min_len {
+        segments.push((0..leading, record.pos() + 1));
+    }
+    // A read that is entirely soft-clip leaves nothing aligned to split off
+    if trailing >= min_len && leading + trailing < seq_len {
+        segments.push((seq_len - trailing..seq_len, record.cigar().end_pos()));
+    }
+    segments
+}
+
+/// Split a read at the boundary of its longest terminal soft-clip.
+///
+/// Returns (clipped segment, remainder of the read) if that soft-clip is at least `min_len` bases,
+/// otherwise None
+fn split_at_softclip<'s, T>(
+    record: &bam::Record,
+    sequence: &'s [T],
+    min_len: usize,
+) -> Option<(&'s [T], &'s [T])> {
+    let (leading, trailing) = terminal_softclips(record);
+    if leading.max(trailing) < min_len || leading + trailing >= sequence.len() {
+        return None;
+    }
+    if leading >= trailing {
+        let (clipped, aligned) = sequence.split_at(leading);
+        Some((clipped, aligned))
+    } else {
+        let (aligned, clipped) = sequence.split_at(sequence.len() - trailing);
+        Some((clipped, aligned))
+    }
+}
+
+/// The read's bases in reference (forward-strand) orientation.
+///
+/// SAM/BAM stores SEQ of reverse-strand alignments already reverse-complemented onto the forward strand,
+/// so this is the stored sequence as-is for both strands. Reverse-complementing reads with `is_reverse()` set
+/// would instead recover the orientation the read was sequenced in
+pub(crate) fn sequence_in_ref_orientation(record: &bam::Record) -> String {
+    record.seq().as_bytes().iter().map(|&b| b as char).collect()
+}
+
+pub(crate) fn parse_record(
+    record: &bam::Record,
+) -> Result<BamRecordEnriched<'_>, Box<dyn std::error::Error>> {
+    // Run computationally intensive checks
+    let sequence = sequence_in_ref_orientation(record);
+    let qname = str::from_utf8(record.qname()).context("Failed to parse qname to string slice")?;
+    let alignment_score = get_as_tag(record).unwrap_or(0);
+    let normalized_alignment_score = if record.seq_len() > 0 {
+        alignment_score as f64 / record.seq_len() as f64
+    } else {
+        0.0
+    };
+    let fragment = fragment_key(qname, MateSuffix::Auto);
+    let mate = mate_number(record, MateSuffix::Auto);
+
+    Ok(BamRecordEnriched {
+        record,
+        qname,
+        fragment,
+        mate,
+        sequence,
+        alignment_score,
+        normalized_alignment_score,
+    })
+}
+
+/// Check whether a bam sequence is considered 'good quality'.
+///
+/// A good quality *sequence* is likely to be a real biological
+/// sequence that should be fed into kraken downstream for read classification.
+/// Note a good quality sequence is not necessarily a good quality 'alignment'
+///
+/// A good quality sequence has the following properties
+/// 1. Reasonable length (>=`min_len`)
+/// 2. Good Average Phred Scores (>=`min_phred`)
+/// 3. Contains very few ambiguous/masked nucleotides (Number of Ns <= `max_n`)
+/// 4. Is not a PCR duplicate or flagged as 'is_quality_check_failed'
+/// 5. Has a reasonable sequence complexity (No homopolymer reads) (>=`min_complexity`)
+/// 6. Has no long homopolymer run (<=`max_homopolymer_run`) or dominant tandem repeat (<=`max_tandem_repeat_fraction`)
+///
+/// Returns the first criterion the sequence fails, or [ReadQc::Pass]
+pub(crate) fn is_good_quality_sequence(
+    record: &BamRecordEnriched,
+    filters: &ReadFilterConfig,
+) -> ReadQc {
+    // Start with the quick checks
+    if record.record.is_quality_check_failed() {
+        return ReadQc::QcFail;
+    }
+    if record.record.is_duplicate() {
+        return ReadQc::Duplicate;
+    }
+    if record.record.seq_len() < filters.min_len {
+        return ReadQc::TooShort;
+    }
+
+    // Run computationally intensive checks
+    // Ambiguous bases (N)
+    if seq_ambiguous(&record.sequence, filters.max_n) {
+        return ReadQc::TooManyN;
+    }
+
+    // Average Quality of the called bases (Ns were judged above)
+    let qual = record.record.qual();
+    let qual_average = calculate_average_phred_called(record.sequence.as_bytes(), qual);
+    if qual_average < filters.min_phred {
+        return ReadQc::LowPhred;
+    }
+
+    // Low complexity (homopolymers, dinucleotide repeats)
+    if sequence_complexity(&record.sequence) < filters.min_complexity {
+        return ReadQc::LowComplexity;
+    }
+
+    // Local repeats that global complexity misses
+    if longest_homopolymer(record.sequence.as_bytes()) > filters.max_homopolymer_run {
+        return ReadQc::Homopolymer;
+    }
+    if tandem_repeat_fraction(record.sequence.as_bytes()) > filters.max_tandem_repeat_fraction {
+        return ReadQc::TandemRepeat;
+    }
+
+    ReadQc::Pass
+}
+
+/// Outcome of the sequence quality filters: which criterion (if any) a read failed first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadQc {
+    Pass,
+    TooShort,
+    LowPhred,
+    TooManyN,
+    Duplicate,
+    QcFail,
+    LowComplexity,
+    Homopolymer,
+    TandemRepeat,
+}
+
+impl ReadQc {
+    pub fn is_pass(self) -> bool {
+        self == ReadQc::Pass
+    }
+
+    /// Name used in the bam summary, the run summary and the debug reads TSV
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ReadQc::Pass => "pass",
+            ReadQc::TooShort => "too_short",
+            ReadQc::LowPhred => "low_phred",
+            ReadQc::TooManyN => "too_many_n",
+            ReadQc::Duplicate => "duplicate",
+            ReadQc::QcFail => "qc_fail",
+            ReadQc::LowComplexity => "low_complexity",
+            ReadQc::Homopolymer => "homopolymer",
+            ReadQc::TandemRepeat => "tandem_repeat",
+        }
+    }
+}
+
+/// Reads failing the sequence quality filters, by the first criterion they failed
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct QualityFailures(std::collections::BTreeMap<String, u64>);
+
+impl QualityFailures {
+    pub fn add(&mut self, qc: ReadQc) {
+        if !qc.is_pass() {
+            *self.0.entry(qc.as_str().to_string()).or_insert(0) += 1;
+        }
+    }
+
+    pub fn get(&self, qc: ReadQc) -> u64 {
+        self.0.get(qc.as_str()).copied().unwrap_or(0)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.0.values().sum()
+    }
+
+    /// `[total] (reason: n, ...)` for the stderr summaries
+    fn describe(&self) -> String {
+        let reasons: Vec<String> = self
+            .0
+            .iter()
+            .map(|(reason, n)| format!("{}: {}", reason, n))
+            .collect();
+        if reasons.is_empty() {
+            format!("[{}]", self.total())
+        } else {
+            format!("[{}] ({})", self.total(), reasons.join(", "))
+        }
+    }
+
+    /// One `<prefix> failed quality [reason]\tn` line per reason
+    fn write<W: Write>(&self, writer: &mut W, prefix: &str) -> std::io::Result<()> {
+        for (reason, n) in &self.0 {
+            writeln!(writer, "{} failed quality [{}]\t{}", prefix, reason, n)?;
+        }
+        Ok(())
+    }
+}
+
+/// Shannon entropy of the trinucleotides in `seq`, scaled to [0, 1] by the maximum possible entropy (6 bits).
+///
+/// Homopolymers score 0, dinucleotide repeats (ATATAT...) ~0.17 and random sequence ~0.85-0.95
+/// (shorter reads can't reach the maximum). Trinucleotides containing ambiguous bases are ignored
+pub fn sequence_complexity(seq: &str) -> f64 {
+    const K: usize = 3;
+    let code = |b: u8| match b {
+        b'A' | b'a' => Some(0),
+        b'C' | b'c' => Some(1),
+        b'G' | b'g' => Some(2),
+        b'T' | b't' => Some(3),
+        _ => None,
+    };
+
+    let mut counts = [0u32; 64];
+    let mut total: u32 = 0;
+    for kmer in seq.as_bytes().windows(K) {
+        let index = kmer
+            .iter()
+            .try_fold(0usize, |index, &b| Some(index * 4 + code(b)?));
+        if let Some(index) = index {
+            counts[index] += 1;
+            total += 1;
+        }
+    }
+    if total == 0 {
+        return 0.0;
+    }
+
+    let entropy: f64 = counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / total as f64;
+            // Written as log(1/p) so a homopolymer scores 0 rather than -0
+            p * (1.0 / p).log2()
+        })
+        .sum();
+    entropy / (2 * K) as f64
+}
+
+/// Length of the longest run of one base (A, C, G or T, case-insensitive) in `seq`
+pub fn longest_homopolymer(seq: &[u8]) -> usize {
+    let mut longest = 0;
+    let mut run = 0;
+    let mut previous = None;
+    for base in seq.iter().map(u8::to_ascii_uppercase) {
+        if !matches!(base, b'A' | b'C' | b'G' | b'T') {
+            run = 0;
+            previous = None;
+            continue;
+        }
+        run = if previous == Some(base) { run + 1 } else { 1 };
+        previous = Some(base);
+        longest = longest.max(run);
+    }
+    longest
+}
+
+/// Fewest copies of a motif that count as a tandem repeat in [tandem_repeat_fraction]
+const MIN_TANDEM_COPIES: usize = 3;
+
+/// Fraction of `seq` spanned by its longest tandem repeat of a 2-6bp motif repeated at least [MIN_TANDEM_COPIES] times.
+///
+/// `CAGCAGCAG...` and `ATATAT...` reads score 1, random sequence rarely more than 0.1.
+/// Homopolymers count too (they repeat every motif length); see [longest_homopolymer] to catch them on their own
+pub fn tandem_repeat_fraction(seq: &[u8]) -> f64 {
+    if seq.is_empty() {
+        return 0.0;
+    }
+    let mut longest = 0;
+    for period in 2..=6 {
+        // Each base matching the one `period` before it extends the repeat by one
+        let mut matches = 0;
+        for i in period..seq.len() {
+            let (a, b) = (
+                seq[i].to_ascii_uppercase(),
+                seq[i - period].to_ascii_uppercase(),
+            );
+            matches = if a == b && a != b'N' { matches + 1 } else { 0 };
+            let span = matches + period;
+            if matches > 0 && span >= period * MIN_TANDEM_COPIES {
+                longest = longest.max(span);
+            }
+        }
+    }
+    longest as f64 / seq.len() as f64
+}
+
+/// The criteria of [is_good_quality_sequence] (other than length) applied to a segment of a read
+fn is_good_quality_segment(
+    record: &bam::Record,
+    sequence: &str,
+    qual: &[u8],
+    filters: &ReadFilterConfig,
+) -> bool {
+    !record.is_quality_check_failed()
+        && !record.is_duplicate()
+        && !seq_ambiguous(sequence, filters.max_n)
+        && calculate_average_phred_called(sequence.as_bytes(), qual) >= filters.min_phred
+        && sequence_complexity(sequence) >= filters.min_complexity
+        && longest_homopolymer(sequence.as_bytes()) <= filters.max_homopolymer_run
+        && tandem_repeat_fraction(sequence.as_bytes()) <= filters.max_tandem_repeat_fraction
+}
+
+/// Is the alignment convincing
+pub(crate) fn is_good_quality_alignment(
+    record: &BamRecordEnriched,
+    filters: &ReadFilterConfig,
+    min_mapq: u8,
+    min_as_per_base: f64,
+) -> bool {
+    // CHeck if sequence is good quality
+    let good_qual_sequence = is_good_quality_sequence(record, filters).is_pass();
+    if !good_qual_sequence {
+        return false;
+    }
+
+    // Check if Alignment is good quality
+    !record.record.is_secondary()
+        & !record.record.is_quality_check_failed()
+        & !record.record.is_unmapped()
+        & (record.record.mapq() > min_mapq)
+        // Alignment Score (length independent)
+        & (record.normalized_alignment_score >= min_as_per_base)
+}
+
+/// Check how many Ns in a string, and if greater than 'maxNs' return FALSE
+pub(crate) fn seq_ambiguous(seq: &str, max_n: usize) -> bool {
+    count_ns(seq) > max_n
+}
+
+/// Number of ambiguous/masked bases (N) in a sequence
+pub(crate) fn count_ns(seq: &str) -> usize {
+    seq.chars().filter(|c| *c == 'N').count()
+}
+
+/// Mean of raw phred scores, including those of ambiguous (N) positions. Returns 0 for an empty quality array.
+///
+/// The quality filters use [calculate_average_phred_called]
+pub fn calculate_average_phred(qual_scores: &[u8]) -> f64 {
+    let total: u32 = qual_scores.iter().map(|&score| score as u32).sum();
+    let count = qual_scores.len();
+
+    if count > 0 {
+        total as f64 / count as f64
+    } else {
+        0.0
+    }
+}
+
+/// Mean phred score of the called bases, skipping ambiguous (N) positions so base quality is judged
+/// independently of the [seq_ambiguous] filter. Returns 0 if no bases are called
+pub fn calculate_average_phred_called(seq: &[u8], qual_scores: &[u8]) -> f64 {
+    let (total, count) = seq
+        .iter()
+        .zip(qual_scores)
+        .filter(|(&base, _)| base != b'N')
+        .fold((0u32, 0usize), |(total, count), (_, &score)| {
+            (total + score as u32, count + 1)
+        });
+
+    if count > 0 {
+        total as f64 / count as f64
+    } else {
+        0.0
+    }
+}
+
+#[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+struct MicrobialContigRecords {
+    taxid: String,
+    common_name: String,
+    contigs: String,
+}
+struct Contig {
+    contig: String,
+    taxid: String,
+    species: String,
+}
+
+/// A collection of microbial contigs, indexed by contig name.
+/// Use the `contains` method to see if a particular contig name is in the list
+pub struct MicrobialContigs {
+    contigs: std::collections::HashMap<String, Contig>,
+}
+
+impl MicrobialContigs {
+    // If a contig name is listed more than once, the first entry wins
+    fn new(contigs: Vec<Contig>) -> Self {
+        let mut index = std::collections::HashMap::with_capacity(contigs.len());
+        for contig in contigs {
+            index.entry(contig.contig.clone()).or_insert(contig);
+        }
+        MicrobialContigs { contigs: index }
+    }
+
+    /// Check if a contig name is one of the microbial contigs
+    pub fn contains(&self, contig_name: &str) -> bool {
+        self.contigs.contains_key(contig_name)
+    }
+
+    /// Species (common name) a contig belongs to
+    pub fn contig_to_species(&self, contig_name: &str) -> Option<&str> {
+        self.contigs.get(contig_name).map(|c| c.species.as_str())
+    }
+
+    /// NCBI taxid of the species a contig belongs to
+    pub fn contig_to_taxid(&self, contig_name: &str) -> Option<&str> {
+        self.contigs.get(contig_name).map(|c| c.taxid.as_str())
+    }
+
+    /// Distinct taxids of every species with a contig, in sorted order
+    pub fn taxids(&self) -> impl Iterator<Item = &str> {
+        self.contigs
+            .values()
+            .map(|c| c.taxid.as_str())
+            .collect::<std::collections::BTreeSet<_>>()
+            .into_iter()
+    }
+}
+
+/// Read microbial contigs from a CSV with header `taxid,common_name,contigs`.
+/// The contigs column lists every contig name of a species, separated by `;`
+pub fn read_microbial_contigs(path: &Path) -> Result<MicrobialContigs, Box<dyn std::error::Error>> {
+    let mut reader = csv::Reader::from_path(path)?;
+    let expected = ["taxid", "common_name", "contigs"];
+    let header = reader.headers()?.clone();
+    if header.iter().ne(expected) {
+        return Err(format!(
+            "expected header [{}] but found [{}]",
+            expected.join(","),
+            header.iter().collect::<Vec<_>>().join(",")
+        )
+        .into());
+    }
+
+    let mut contigs = vec![];
+    for (i, row) in reader.deserialize::<MicrobialContigRecords>().enumerate() {
+        // Line 1 is the header
+        let line = i + 2;
+        let row = row.map_err(|err| format!("malformed row on line {}: {}", line, err))?;
+        if row.taxid.trim().parse::<u64>().is_err() {
+            return Err(format!(
+                "malformed row on line {}: taxid [{}] is not a number",
+                line, row.taxid
+            )
+            .into());
+        }
+        let names: Vec<&str> = row
+            .contigs
+            .split(';')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .collect();
+        if names.is_empty() {
+            return Err(format!("malformed row on line {}: no contigs listed", line).into());
+        }
+        contigs.extend(names.into_iter().map(|name| Contig {
+            contig: name.to_string(),
+            taxid: row.taxid.trim().to_string(),
+            species: row.common_name.clone(),
+        }));
+    }
+    Ok(MicrobialContigs::new(contigs))
+}
+
+pub fn common_microbial_contigs() -> MicrobialContigs {
+    MicrobialContigs::new(vec![
+        //EBV
+        Contig {
+            contig: "chrEBV".to_string(),
+            taxid: "10376".to_string(),
+            species: "EBV".to_string(),
+        },
+        Contig {
+            contig: "NC_009334".to_string(),
+            taxid: "10376".to_string(),
+            species: "EBV".to_string(),
+        },
+        Contig {
+            contig: "NC_007605".to_string(),
+            taxid: "10376".to_string(),
+            species: "EBV".to_string(),
+        },
+        //HHV6B
+        Contig {
+            contig: "NC_000898".to_string(),
+            taxid: "32604".to_string(),
+            species: "HHV6B".to_string(),
+        },
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_htslib::bam::record::{Cigar, CigarString};
+
+    #[test]
+    fn microbial_contigs() {
+        let microcontigs = crate::bam::common_microbial_contigs();
+        assert!(microcontigs.contains("NC_007605"));
+        assert_eq!(microcontigs.contig_to_species("NC_007605").unwrap(), "EBV");
+        assert_eq!(
+            microcontigs.contig_to_species("NC_000898").unwrap(),
+            "HHV6B"
+        );
+        assert!(microcontigs.contig_to_species("ADAWD").is_none());
+    }
+
+    #[test]
+    fn microbial_contig_lookups_match_list() {
+        let contigs = crate::bam::common_microbial_contigs();
+        let expected = [
+            ("chrEBV", "EBV", "10376"),
+            ("NC_009334", "EBV", "10376"),
+            ("NC_007605", "EBV", "10376"),
+            ("NC_000898", "HHV6B", "32604"),
+        ];
+        assert_eq!(contigs.contigs.len(), expected.len());
+        for (contig, species, taxid) in expected {
+            assert!(contigs.contains(contig));
+            assert_eq!(contigs.contig_to_species(contig), Some(species));
+            assert_eq!(contigs.contig_to_taxid(contig), Some(taxid));
+        }
+        assert!(!contigs.contains("chrebv"));
+        assert!(!contigs.contains("chr1"));
+        assert_eq!(contigs.contig_to_taxid("chr1"), None);
+        let taxids: Vec<&str> = contigs.taxids().collect();
+        assert_eq!(taxids.len(), 2);
+        assert!(taxids.contains(&"10376"));
+        assert!(taxids.contains(&"32604"));
+    }
+
+    #[test]
+    fn hard_clipped_reads() {
+        let mut record = rust_htslib::bam::Record::new();
+        let cigar = CigarString(vec![Cigar::HardClip(20), Cigar::Match(30)]);
+        record.set(b"read1", Some(&cigar), &[b'A'; 30], &[30; 30]);
+        assert_eq!(record.seq_len(), 30);
+        assert_eq!(crate::bam::hard_clipped_bases(&record), 20);
+
+        let cigar = CigarString(vec![Cigar::SoftClip(20), Cigar::Match(30)]);
+        record.set(b"read2", Some(&cigar), &[b'A'; 50], &[30; 50]);
+        assert_eq!(crate::bam::hard_clipped_bases(&record), 0);
+    }
+
+    fn make_record(seq: &[u8], qual: u8) -> rust_htslib::bam::Record {
+        let mut record = rust_htslib::bam::Record::new();
+        let cigar = CigarString(vec![Cigar::Match(seq.len() as u32)]);
+        record.set(b"read", Some(&cigar), seq, &vec![qual; seq.len()]);
+        record
+    }
+
+    #[test]
+    fn average_phred() {
+        assert_eq!(crate::bam::calculate_average_phred(&[]), 0.0);
+        assert_eq!(crate::bam::calculate_average_phred(&[10, 20, 30]), 20.0);
+    }
+
+    #[test]
+    fn average_phred_ignores_ns() {
+        use crate::bam::{calculate_average_phred, calculate_average_phred_called};
+        use rust_htslib::bam::record::{Cigar, CigarString};
+
+        // Q30 read with a run of two Q2 Ns (base callers often assign Ns their lowest quality)
+        let mut seq = crate::test_bam::sequence(20, 7);
+        seq[8..10].copy_from_slice(b"NN");
+        let mut qual = vec![30; 20];
+        qual[8..10].copy_from_slice(&[2, 2]);
+        assert_eq!(calculate_average_phred(&qual), 27.2);
+        assert_eq!(calculate_average_phred_called(&seq, &qual), 30.0);
+        assert_eq!(calculate_average_phred_called(b"NNN", &[2, 2, 2]), 0.0);
+
+        let mut record = rust_htslib::bam::Record::new();
+        let cigar = CigarString(vec![Cigar::Match(20)]);
+        record.set(b"read", Some(&cigar), &seq, &qual);
+        let enriched = crate::bam::parse_record(&record).unwrap();
+        let filters = crate::bam::ReadFilterConfig {
+            min_len: 20,
+            min_phred: 28.0,
+            min_complexity: 0.0,
+            ..Default::default()
+        };
+        assert!(crate::bam::is_good_quality_sequence(&enriched, &filters).is_pass());
+    }
+
+    #[test]
+    fn ambiguous_bases() {
+        assert_eq!(crate::bam::count_ns("ANNCGN"), 3);
+        assert!(!crate::bam::seq_ambiguous("ACNNG", 2));
+        assert!(crate::bam::seq_ambiguous("ACNNNG", 2));
+    }
+
+    #[test]
+    fn sequence_quality_length_boundary() {
+        let record = make_record(&crate::test_bam::sequence(50, 1), 30);
+        let enriched = crate::bam::parse_record(&record).unwrap();
+        assert!(crate::bam::is_good_quality_sequence(
+            &enriched,
+            &crate::bam::ReadFilterConfig::default()
+        )
+        .is_pass());
+
+        let record = make_record(&crate::test_bam::sequence(49, 1), 30);
+        let enriched = crate::bam::parse_record(&record).unwrap();
+        assert_eq!(
+            crate::bam::is_good_quality_sequence(
+                &enriched,
+                &crate::bam::ReadFilterConfig::default()
+            ),
+            crate::bam::ReadQc::TooShort
+        );
+    }
+
+    #[test]
+    fn sequence_quality_phred_boundary() {
+        // Exactly at threshold passes
+        let record = make_record(&crate::test_bam::sequence(50, 1), 17);
+        let enriched = crate::bam::parse_record(&record).unwrap();
+        assert!(crate::bam::is_good_quality_sequence(
+            &enriched,
+            &crate::bam::ReadFilterConfig::default()
+        )
+        .is_pass());
+
+        let record = make_record(&crate::test_bam::sequence(50, 1), 16);
+        let enriched = crate::bam::parse_record(&record).unwrap();
+        assert_eq!(
+            crate::bam::is_good_quality_sequence(
+                &enriched,
+                &crate::bam::ReadFilterConfig::default()
+            ),
+            crate::bam::ReadQc::LowPhred
+        );
+    }
+
+    #[test]
+    fn sequence_quality_max_n_boundary() {
+        let mut seq = crate::test_bam::sequence(50, 1);
+        seq[..2].copy_from_slice(b"NN");
+        let record = make_record(&seq, 30);
+        let enriched = crate::bam::parse_record(&record).unwrap();
+        assert!(crate::bam::is_good_quality_sequence(
+            &enriched,
+            &crate::bam::ReadFilterConfig::default()
+        )
+        .is_pass());
+
+        seq[2] = b'N';
+        let record = make_record(&seq, 30);
+        let enriched = crate::bam::parse_record(&record).unwrap();
+        assert_eq!(
+            crate::bam::is_good_quality_sequence(
+                &enriched,
+                &crate::bam::ReadFilterConfig::default()
+            ),
+            crate::bam::ReadQc::TooManyN
+        );
+    }
+
+    #[test]
+    fn sequence_quality_flag_short_circuits() {
+        // Flags are checked before length, so even a too-short read reports the flag
+        let mut record = make_record(&[b'A'; 10], 30);
+        record.set_duplicate();
+        let enriched = crate::bam::parse_record(&record).unwrap();
+        assert_eq!(
+            crate::bam::is_good_quality_sequence(
+                &enriched,
+                &crate::bam::ReadFilterConfig::default()
+            ),
+            crate::bam::ReadQc::Duplicate
+        );
+
+        let mut record = make_record(&[b'A'; 50], 30);
+        record.set_quality_check_failed();
+        let enriched = crate::bam::parse_record(&record).unwrap();
+        assert_eq!(
+            crate::bam::is_good_quality_sequence(
+                &enriched,
+                &crate::bam::ReadFilterConfig::default()
+            ),
+            crate::bam::ReadQc::QcFail
+        );
+        assert!(!crate::bam::is_good_quality_alignment(
+            &enriched,
+            &crate::bam::ReadFilterConfig::default(),
+            10,
+            0.5
+        ));
+    }
+
+    #[test]
+    fn microbial_contig_records_round_trip() {
+        let record = crate::bam::MicrobialContigRecords {
+            taxid: "10376".to_string(),
+            common_name: "Human gammaherpesvirus 4 (EBV), \"B95-8\"".to_string(),
+            contigs: "chrEBV;NC_007605".to_string(),
+        };
+        let mut writer = csv::Writer::from_writer(vec![]);
+        writer.serialize(&record).unwrap();
+        let bytes = writer.into_inner().unwrap();
+
+        let mut reader = csv::Reader::from_reader(bytes.as_slice());
+        let parsed: Vec<crate::bam::MicrobialContigRecords> =
+            reader.deserialize().map(|r| r.unwrap()).collect();
+        assert_eq!(parsed, vec![record]);
+    }
+
+    #[test]
+    fn mate_suffixes() {
+        use crate::bam::{fragment_key, MateSuffix};
+        assert_eq!(fragment_key("read1/1", MateSuffix::Auto), "read1");
+        assert_eq!(fragment_key("read1/2", MateSuffix::Auto), "read1");
+        assert_eq!(fragment_key("read1 2:N:0:ACGT", MateSuffix::Auto), "read1");
+        assert_eq!(fragment_key("read1/3", MateSuffix::Auto), "read1/3");
+
+        assert_eq!(
+            fragment_key("read1 1:N:0:ACGT", MateSuffix::Illumina),
+            "read1"
+        );
+        assert_eq!(fragment_key("read1/1", MateSuffix::Illumina), "read1/1");
+
+        assert_eq!(fragment_key("read1/1", MateSuffix::None), "read1/1");
+        assert_eq!(fragment_key("read1", MateSuffix::Auto), "read1");
+    }
+
+    #[test]
+    fn mate_number_prefers_flags() {
+        use crate::bam::{mate_number, MateSuffix};
+        // Name says mate 1 but flags say mate 2
+        let mut record = make_record(&[b'A'; 10], 30);
+        record.set_qname(b"read/1");
+        record.set_paired();
+        record.set_last_in_template();
+        assert_eq!(mate_number(&record, MateSuffix::Auto), Some(2));
+
+        // No flags: fall back to the suffix
+        let mut record = make_record(&[b'A'; 10], 30);
+        record.set_qname(b"read/1");
+        assert_eq!(mate_number(&record, MateSuffix::Auto), Some(1));
+        assert_eq!(mate_number(&record, MateSuffix::None), None);
+    }
+
+    #[test]
+    fn softclip_split() {
+        let mut record = rust_htslib::bam::Record::new();
+        let cigar = CigarString(vec![
+            Cigar::HardClip(5),
+            Cigar::SoftClip(4),
+            Cigar::Match(6),
+            Cigar::SoftClip(2),
+        ]);
+        record.set(b"read", Some(&cigar), b"CCCCAAAAAAGG", &[30; 12]);
+        assert_eq!(crate::bam::terminal_softclips(&record), (4, 2));
+
+        let sequence = b"CCCCAAAAAAGG";
+        assert_eq!(
+            crate::bam::split_at_softclip(&record, sequence, 4),
+            Some((&b"CCCC"[..], &b"AAAAAAGG"[..]))
+        );
+        assert_eq!(crate::bam::split_at_softclip(&record, sequence, 5), None);
+
+        let cigar = CigarString(vec![Cigar::Match(6), Cigar::SoftClip(6)]);
+        record.set(b"read", Some(&cigar), b"AAAAAAGGGGGG", &[30; 12]);
+        assert_eq!(
+            crate::bam::split_at_softclip(&record, b"AAAAAAGGGGGG", 3),
+            Some((&b"GGGGGG"[..], &b"AAAAAA"[..]))
+        );
+    }
+
+    #[test]
+    fn microbial_contigs_with_special_characters() {
+        let mut header = rust_htslib::bam::Header::new();
+        for (name, len) in [("chr1", 1000), ("gi|333031|ref|NC_001526.4|", 7906)] {
+            let mut sq = rust_htslib::bam::header::HeaderRecord::new(b"SQ");
+            sq.push_tag(b"SN", name).push_tag(b"LN", len);
+            header.push_record(&sq);
+        }
+        let header = rust_htslib::bam::HeaderView::from_header(&header);
+        let microbial_contigs = crate::bam::MicrobialContigs::new(vec![crate::bam::Contig {
+            contig: "gi|333031|ref|NC_001526.4|".to_string(),
+            taxid: "333760".to_string(),
+            species: "HPV16".to_string(),
+        }]);
+        assert_eq!(
+            crate::bam::find_microbial_contigs(&header, &microbial_contigs).unwrap(),
+            vec![("gi|333031|ref|NC_001526.4|".to_string(), 1)]
+        );
+    }
+
+    const TEST_BAM: &str =
+        "testfiles/humanGRCh38_9000_ebv_1000_hpv16_1000_hpylori_1000.grch38_noalt.bam";
+
+    #[test]
+    fn contig_bams_are_indexed() {
+        use rust_htslib::bam::Read;
+
+        let outdir = tempfile::tempdir().unwrap();
+        let config = crate::bam::ExtractionConfig {
+            contig_bam_dir: Some(outdir.path().to_path_buf()),
+            ..Default::default()
+        };
+        crate::bam::bam2unmappedreads(
+            TEST_BAM,
+            outdir.path().join("reads.fasta").to_str().unwrap(),
+            outdir
+                .path()
+                .join("reads.bam_summary.txt")
+                .to_str()
+                .unwrap(),
+            &crate::bam::ReadFilterConfig::default(),
+            &config,
+        )
+        .unwrap();
+
+        let contig_bam = outdir
+            .path()
+            .join("humanGRCh38_9000_ebv_1000_hpv16_1000_hpylori_1000.grch38_noalt.chrEBV.bam");
+        let mut reader = rust_htslib::bam::IndexedReader::from_path(&contig_bam).unwrap();
+        reader.fetch("chrEBV").unwrap();
+        assert_eq!(reader.records().count(), 2000);
+
+        assert_eq!(
+            crate::bam::sanitize_filename("gi|123|ref|NC_1.4|"),
+            "gi_123_ref_NC_1.4_"
+        );
+    }
+
+    #[test]
+    fn contigs_with_low_mean_mapq_are_suppressed() {
+        let outdir = tempfile::tempdir().unwrap();
+        let summary = outdir.path().join("reads.bam_summary.txt");
+        let config = crate::bam::ExtractionConfig {
+            // Above the maximum MAPQ bwa reports
+            contig_min_mean_mapq: Some(61.0),
+            ..Default::default()
+        };
+        crate::bam::bam2unmappedreads(
+            TEST_BAM,
+            outdir.path().join("reads.fasta").to_str().unwrap(),
+            summary.to_str().unwrap(),
+            &crate::bam::ReadFilterConfig::default(),
+            &config,
+        )
+        .unwrap();
+        let summary = std::fs::read_to_string(summary).unwrap();
+        assert!(summary.contains("Contig [chrEBV] mean MAPQ\t"));
+        assert!(summary.contains("Contig [chrEBV] suppressed"));
+        assert!(!summary.contains("Species [EBV]"));
+    }
+
+    #[test]
+    fn unplaced_contigs() {
+        let mut header = rust_htslib::bam::Header::new();
+        for name in [
+            "chr1",
+            "chrUn_KI270302v1",
+            "chr1_KI270706v1_random",
+            "chrEBV",
+        ] {
+            let mut sq = rust_htslib::bam::header::HeaderRecord::new(b"SQ");
+            sq.push_tag(b"SN", name).push_tag(b"LN", 1000);
+            header.push_record(&sq);
+        }
+        let header = rust_htslib::bam::HeaderView::from_header(&header);
+        let patterns: Vec<String> = crate::bam::DEFAULT_UNPLACED_PATTERNS
+            .iter()
+            .map(|p| p.to_string())
+            .collect();
+        assert_eq!(
+            crate::bam::find_unplaced_contigs(&header, &patterns).unwrap(),
+            vec![
+                ("chrUn_KI270302v1".to_string(), 1),
+                ("chr1_KI270706v1_random".to_string(), 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn unmapped_extraction_counts() {
+        use crate::test_bam::{sequence, TestBam, TestRead};
+
+        let dir = tempfile::tempdir().unwrap();
+        let bam = TestBam::new()
+            .contig("chr1", 10_000)
+            .read(TestRead::mapped("mapped1", "chr1", 100, &sequence(100, 1)))
+            .read(TestRead::mapped("mapped2", "chr1", 200, &sequence(100, 2)))
+            .read(TestRead::unmapped("good1", &sequence(100, 3)))
+            .read(TestRead::unmapped("good2", &sequence(100, 4)))
+            .read(TestRead::unmapped("short", &sequence(30, 5)))
+            .read(TestRead::unmapped("lowqual", &sequence(100, 6)).qual(10))
+            .read(TestRead::unmapped("dup", &sequence(100, 7)).flags(0x400))
+            .write(dir.path(), "sample");
+
+        let fasta = dir.path().join("sample.fasta");
+        let summary = dir.path().join("sample.bam_summary.txt");
+        let extraction = crate::bam::bam2unmappedreads(
+            bam.to_str().unwrap(),
+            fasta.to_str().unwrap(),
+            summary.to_str().unwrap(),
+            &crate::bam::ReadFilterConfig::default(),
+            &crate::bam::ExtractionConfig::default(),
+        )
+        .unwrap();
+
+        let fasta = std::fs::read_to_string(fasta).unwrap();
+        let headers: Vec<&str> = fasta.lines().filter(|l| l.starts_with('>')).collect();
+        assert_eq!(headers, vec![">good1", ">good2"]);
+
+        // Each failing read is tallied under the first criterion it failed
+        let failures = &extraction.unmapped_quality_failures;
+        assert_eq!(failures.total(), 3);
+        assert_eq!(failures.get(crate::bam::ReadQc::TooShort), 1);
+        assert_eq!(failures.get(crate::bam::ReadQc::LowPhred), 1);
+        assert_eq!(failures.get(crate::bam::ReadQc::Duplicate), 1);
+        assert_eq!(failures.get(crate::bam::ReadQc::LowComplexity), 0);
+
+        let summary = std::fs::read_to_string(summary).unwrap();
+        assert!(summary.contains("total depth (number of reads)\t7\n"));
+        assert!(summary.contains("total mapped reads\t2\n"));
+        assert!(summary.contains("total unmapped reads\t5\n"));
+        assert!(summary.contains("Contig [chr1] mapped reads\t2\n"));
+        assert!(summary.contains("Contig [chr1] unmapped reads\t0\n"));
+        assert!(summary.contains("Contig [*] unmapped reads\t5\n"));
+        assert!(summary.contains("passed reads median length\t100\n"));
+        assert!(summary.contains("passed reads length [100-149]\t2\n"));
+        assert!(summary.contains("unmapped reads failed quality [too_short]\t1\n"));
+    }
+
+    #[test]
+    fn mixed_sample_end_to_end() {
+        let dir = tempfile::tempdir().unwrap();
+        let bam = crate::test_bam::TestBam::mixed_sample().write(dir.path(), "mixed");
+        let fasta = dir.path().join("mixed.fasta");
+        let summary = dir.path().join("mixed.bam_summary.txt");
+        let extraction = crate::bam::bam2unmappedreads(
+            bam.to_str().unwrap(),
+            fasta.to_str().unwrap(),
+            summary.to_str().unwrap(),
+            &crate::bam::ReadFilterConfig::default(),
+            &crate::bam::ExtractionConfig::default(),
+        )
+        .unwrap();
+
+        // Good unmapped reads, then the microbial read whose alignment isn't convincing (in reference orientation).
+        // Host reads and convincing microbial alignments aren't sent to kraken
+        let ebv = crate::test_bam::sequence(2_000, 100);
+        let fasta = std::fs::read_to_string(fasta).unwrap();
+        let records: Vec<&str> = fasta.lines().collect();
+        assert_eq!(
+            records,
+            vec![
+                ">good1",
+                std::str::from_utf8(&crate::test_bam::sequence(100, 5)).unwrap(),
+                ">good2",
+                std::str::from_utf8(&crate::test_bam::sequence(100, 6)).unwrap(),
+                ">ebv_rev",
+                std::str::from_utf8(&ebv[400..500]).unwrap(),
+            ]
+        );
+
+        assert_eq!(extraction.total_reads, 14);
+        assert_eq!(extraction.mapped_reads, 7);
+        assert_eq!(extraction.unmapped_reads, 7);
+        assert_eq!(extraction.unmapped_good_quality_sequences, 2);
+        assert_eq!(extraction.reads_written, 3);
+        assert!(extraction.microbial_contigs_present);
+        assert_eq!(
+            extraction.species_good_alignments,
+            [("EBV".to_string(), 1)].into_iter().collect()
+        );
+        let failures = &extraction.unmapped_quality_failures;
+        assert_eq!(failures.total(), 5);
+        for qc in [
+            crate::bam::ReadQc::TooShort,
+            crate::bam::ReadQc::LowPhred,
+            crate::bam::ReadQc::Duplicate,
+            crate::bam::ReadQc::QcFail,
+            crate::bam::ReadQc::LowComplexity,
+        ] {
+            assert_eq!(failures.get(qc), 1, "{}", qc.as_str());
+        }
+
+        let summary = std::fs::read_to_string(summary).unwrap();
+        for line in [
+            "total depth (number of reads)\t14",
+            "Contig [chr1] mapped reads\t4",
+            "Contig [chrEBV] mapped reads (*microbial*)\t3",
+            "Contig [*] unmapped reads\t7",
+            "unmapped reads failed quality [qc_fail]\t1",
+            "Contig [chrEBV] reads failed quality [low_phred]\t1",
+            "Contig [chrEBV] good quality alignments\t1",
+            "Species [EBV] reads mapped to known microbial contigs\t1",
+            "passed reads length [100-149]\t3",
+        ] {
+            assert!(summary.contains(&format!("{}\n", line)), "{}", line);
+        }
+    }
+
+    #[test]
+    fn flag_filters_apply_to_unplaced_and_anchored_reads() {
+        use crate::test_bam::{sequence, TestBam, TestRead};
+
+        let dir = tempfile::tempdir().unwrap();
+        let bam = TestBam::new()
+            .contig("chr1", 10_000)
+            .contig("chrUn_test", 10_000)
+            .read(TestRead::mapped(
+                "unplaced",
+                "chrUn_test",
+                100,
+                &sequence(100, 1),
+            ))
+            .read(
+                TestRead::mapped("unplaced_dup", "chrUn_test", 300, &sequence(100, 2)).flags(0x400),
+            )
+            .read(
+                TestRead::mapped("unplaced_rev", "chrUn_test", 500, &sequence(100, 3)).flags(0x10),
+            )
+            .read(TestRead::mapped("anchored", "chr1", 100, &sequence(100, 4)).flags(0x1 | 0x8))
+            .read(
+                TestRead::mapped("anchored_dup", "chr1", 300, &sequence(100, 5))
+                    .flags(0x1 | 0x8 | 0x400),
+            )
+            .read(
+                TestRead::mapped("anchored_rev", "chr1", 500, &sequence(100, 6))
+                    .flags(0x1 | 0x8 | 0x10),
+            )
+            .write(dir.path(), "sample");
+        let headers = |filters: &crate::bam::ReadFilterConfig| {
+            let fasta = dir.path().join("sample.fasta");
+            crate::bam::bam2unmappedreads(
+                bam.to_str().unwrap(),
+                fasta.to_str().unwrap(),
+                dir.path().join("sample.bam_summary.txt").to_str().unwrap(),
+                filters,
+                &crate::bam::ExtractionConfig {
+                    unplaced_contig_patterns: Some(vec!["chrUn_".to_string()]),
+                    include_mate_anchored: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            let fasta = std::fs::read_to_string(fasta).unwrap();
+            let mut headers: Vec<String> = fasta
+                .lines()
+                .filter_map(|l| l.strip_prefix('>'))
+                .map(|l| l.trim_end_matches("/1").to_string())
+                .collect();
+            headers.sort();
+            headers
+        };
+
+        // Duplicates are excluded by flag, and reverse-strand reads too once 0x10 is excluded
+        let filters = crate::bam::ReadFilterConfig {
+            exclude_flags: 0x100 | 0x800 | 0x400,
+            ..Default::default()
+        };
+        assert_eq!(
+            headers(&filters),
+            vec!["anchored", "anchored_rev", "unplaced", "unplaced_rev"]
+        );
+        let filters = crate::bam::ReadFilterConfig {
+            exclude_flags: 0x100 | 0x800 | 0x400 | 0x10,
+            ..Default::default()
+        };
+        assert_eq!(headers(&filters), vec!["anchored", "unplaced"]);
+    }
+
+    #[test]
+    fn dedup_sequences() {
+        use crate::test_bam::{sequence, TestBam, TestRead};
+
+        let dir = tempfile::tempdir().unwrap();
+        let duplicated = sequence(100, 1);
+        let lowercase = String::from_utf8(duplicated.clone())
+            .unwrap()
+            .to_lowercase()
+            .into_bytes();
+        let bam = TestBam::new()
+            .contig("chr1", 10_000)
+            .read(TestRead::unmapped("original", &duplicated))
+            .read(TestRead::unmapped("duplicate", &duplicated))
+            .read(TestRead::unmapped("lowercase", &lowercase))
+            .read(TestRead::unmapped("distinct", &sequence(100, 2)))
+            .write(dir.path(), "sample");
+
+        let fasta = dir.path().join("sample.fasta");
+        let summary = dir.path().join("sample.bam_summary.txt");
+        let extraction = crate::bam::bam2unmappedreads(
+            bam.to_str().unwrap(),
+            fasta.to_str().unwrap(),
+            summary.to_str().unwrap(),
+            &crate::bam::ReadFilterConfig::default(),
+            &crate::bam::ExtractionConfig {
+                dedup_sequences: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let fasta = std::fs::read_to_string(fasta).unwrap();
+        let headers: Vec<&str> = fasta.lines().filter(|l| l.starts_with('>')).collect();
+        assert_eq!(headers.len(), 2);
+        assert!(headers.contains(&">distinct"));
+        assert_eq!(extraction.reads_written, 2);
+        let summary = std::fs::read_to_string(summary).unwrap();
+        assert!(summary.contains("duplicate sequences collapsed\t2\n"));
+    }
+
+    #[test]
+    fn debug_reads_with_classified_taxids() {
+        use crate::test_bam::{sequence, TestBam, TestRead};
+
+        let dir = tempfile::tempdir().unwrap();
+        let bam = TestBam::new()
+            .contig("chr1", 10_000)
+            .read(TestRead::unmapped("readA", &sequence(100, 1)))
+            .read(TestRead::unmapped("readB", &sequence(100, 2)))
+            .read(TestRead::unmapped("short", &sequence(30, 3)))
+            .read(TestRead::unmapped("polyA", &[b'A'; 100]))
+            .write(dir.path(), "sample");
+        let debug_reads = dir.path().join("sample.debug_reads.tsv");
+        crate::bam::bam2unmappedreads(
+            bam.to_str().unwrap(),
+            dir.path().join("sample.fasta").to_str().unwrap(),
+            dir.path().join("sample.bam_summary.txt").to_str().unwrap(),
+            &crate::bam::ReadFilterConfig::default(),
+            &crate::bam::ExtractionConfig {
+                debug_reads: Some(debug_reads.clone()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let kout = dir.path().join("sample.kout");
+        std::fs::write(
+            &kout,
+            "C\treadA\t10376\t100\t10376:66\nU\treadB\t0\t100\t0:66\n",
+        )
+        .unwrap();
+        let nmatched = crate::bam::add_classified_taxids(&debug_reads, &kout).unwrap();
+        assert_eq!(nmatched, 2);
+
+        let tsv = std::fs::read_to_string(&debug_reads).unwrap();
+        let rows: Vec<Vec<&str>> = tsv.lines().map(|l| l.split('\t').collect()).collect();
+        let column = |name: &str| rows[0].iter().position(|c| *c == name).unwrap();
+        let (qname, passed, reason, taxid) = (
+            column("qname"),
+            column("passed"),
+            column("fail_reason"),
+            column("classified_taxid"),
+        );
+        let summary: Vec<(&str, &str, &str, &str)> = rows[1..]
+            .iter()
+            .map(|r| (r[qname], r[passed], r[reason], r[taxid]))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("readA", "true", "", "10376"),
+                ("readB", "true", "", "0"),
+                ("short", "false", "too_short", ""),
+                ("polyA", "false", "low_complexity", ""),
+            ]
+        );
+
+        // The metrics behind the complexity and repeat filters are recorded for every read
+        let (complexity, homopolymer, tandem) = (
+            column("complexity"),
+            column("longest_homopolymer"),
+            column("tandem_repeat_fraction"),
+        );
+        assert_eq!(rows[4][complexity], "0.0");
+        assert_eq!(rows[4][homopolymer], "100");
+        assert_eq!(rows[4][tandem], "1.0");
+        assert!(rows[1][complexity].parse::<f64>().unwrap() > 0.5);
+    }
+
+    #[test]
+    fn progress_line() {
+        use crate::bam::progress_line;
+        use std::time::Duration;
+        assert_eq!(
+            progress_line("unmapped reads", 500, 1000, Duration::from_secs(5)),
+            "unmapped reads [##########----------] 500/1000 (50%) 100 reads/s ETA 5s"
+        );
+        // More records than idxstats promised (e.g. stale index) shouldn't overflow the bar
+        assert_eq!(
+            progress_line("chrEBV", 30, 20, Duration::from_secs(1)),
+            "chrEBV [####################] 30/20 (100%) 30 reads/s ETA 0s"
+        );
+        assert_eq!(
+            progress_line("chrEBV", 7, 0, Duration::ZERO),
+            "chrEBV 7 reads 0 reads/s"
+        );
+    }
+
+    #[test]
+    fn read_length_and_gc_stats() {
+        let mut stats = crate::bam::ReadStats::default();
+        for seq in [&b"GGCC"[..], b"ATATAT", b"GCATNN", b"ACGTACGTAC"] {
+            stats.add(seq);
+        }
+        assert_eq!(stats.nreads(), 4);
+        assert_eq!(stats.mean_length(), 6.5);
+        assert_eq!(stats.median_length(), 6.0);
+        // 26 bases in total: the 10bp read alone has 10, adding a 6bp read reaches half
+        assert_eq!(stats.n50(), 6);
+        // 11 G/C out of 24 unambiguous bases
+        assert!((stats.gc_fraction() - 11.0 / 24.0).abs() < 1e-9);
+
+        let empty = crate::bam::ReadStats::default();
+        assert_eq!((empty.mean_length(), empty.median_length()), (0.0, 0.0));
+        assert_eq!((empty.n50(), empty.gc_fraction()), (0, 0.0));
+    }
+
+    #[test]
+    fn microbial_contig_pass_counts() {
+        use crate::test_bam::{sequence, TestBam, TestRead};
+
+        let dir = tempfile::tempdir().unwrap();
+        let bam = TestBam::new()
+            .contig("chrEBV", 10_000)
+            .read(
+                TestRead::mapped("confident", "chrEBV", 100, &sequence(150, 1))
+                    .alignment_score(150)
+                    .edit_distance(0),
+            )
+            .read(
+                TestRead::mapped("low_as", "chrEBV", 200, &sequence(150, 2))
+                    .alignment_score(60)
+                    .edit_distance(20),
+            )
+            .read(
+                TestRead::mapped("low_mapq", "chrEBV", 300, &sequence(150, 3))
+                    .alignment_score(150)
+                    .mapq(0),
+            )
+            .read(
+                TestRead::mapped("hard_clipped", "chrEBV", 400, &sequence(100, 4))
+                    .cigar(vec![Cigar::HardClip(50), Cigar::Match(100)])
+                    .alignment_score(100),
+            )
+            .write(dir.path(), "sample");
+
+        let fasta = dir.path().join("sample.fasta");
+        let summary_path = dir.path().join("sample.bam_summary.txt");
+        let extraction = crate::bam::bam2unmappedreads(
+            bam.to_str().unwrap(),
+            fasta.to_str().unwrap(),
+            summary_path.to_str().unwrap(),
+            &crate::bam::ReadFilterConfig::default(),
+            &crate::bam::ExtractionConfig::default(),
+        )
+        .unwrap();
+        assert!(extraction.microbial_contigs_present);
+
+        // Poor alignments are re-classified, confident ones are reported directly
+        let fasta = std::fs::read_to_string(fasta).unwrap();
+        let headers: Vec<&str> = fasta.lines().filter(|l| l.starts_with('>')).collect();
+        assert_eq!(headers, vec![">low_as", ">low_mapq"]);
+
+        let summary = std::fs::read_to_string(&summary_path).unwrap();
+        assert!(summary.contains("Contig [chrEBV] good quality alignments\t1\n"));
+        assert!(summary.contains("Contig [chrEBV] hard-clipped reads skipped\t1\n"));
+        assert!(summary.contains("Contig [chrEBV] mapped reads (*microbial*)\t4\n"));
+        assert!(summary.contains("Species [EBV] reads mapped to known microbial contigs\t1\n"));
+
+        // A lower AS threshold (e.g. for an aligner with a different scoring scale) accepts [low_as] as evidence
+        let fasta = dir.path().join("sample.fasta");
+        crate::bam::bam2unmappedreads(
+            bam.to_str().unwrap(),
+            fasta.to_str().unwrap(),
+            summary_path.to_str().unwrap(),
+            &crate::bam::ReadFilterConfig {
+                microbial_min_as_per_base: 0.3,
+                ..Default::default()
+            },
+            &crate::bam::ExtractionConfig::default(),
+        )
+        .unwrap();
+        let fasta = std::fs::read_to_string(fasta).unwrap();
+        let headers: Vec<&str> = fasta.lines().filter(|l| l.starts_with('>')).collect();
+        assert_eq!(headers, vec![">low_mapq"]);
+        let summary = std::fs::read_to_string(summary_path).unwrap();
+        assert!(summary.contains("Contig [chrEBV] good quality alignments\t2\n"));
+    }
+
+    #[test]
+    fn confident_softclipped_reads_are_not_split() {
+        use crate::test_bam::{sequence, TestBam, TestRead};
+
+        let dir = tempfile::tempdir().unwrap();
+        let cigar = vec![Cigar::SoftClip(50), Cigar::Match(100)];
+        let bam = TestBam::new()
+            .contig("chrEBV", 10_000)
+            .read(
+                TestRead::mapped("confident", "chrEBV", 100, &sequence(150, 1))
+                    .cigar(cigar.clone())
+                    .alignment_score(150),
+            )
+            .read(
+                TestRead::mapped("poor", "chrEBV", 300, &sequence(150, 2))
+                    .cigar(cigar)
+                    .alignment_score(30),
+            )
+            .write(dir.path(), "sample");
+
+        let fasta = dir.path().join("sample.fasta");
+        let summary_path = dir.path().join("sample.bam_summary.txt");
+        crate::bam::bam2unmappedreads(
+            bam.to_str().unwrap(),
+            fasta.to_str().unwrap(),
+            summary_path.to_str().unwrap(),
+            &crate::bam::ReadFilterConfig::default(),
+            &crate::bam::ExtractionConfig {
+                split_softclips_min_len: Some(30),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        // The confident read is direct evidence only; just the poor alignment is split for kraken
+        let fasta = std::fs::read_to_string(fasta).unwrap();
+        let headers: Vec<&str> = fasta.lines().filter(|l| l.starts_with('>')).collect();
+        assert_eq!(headers, vec![">poor_clip", ">poor_aln"]);
+        let summary = std::fs::read_to_string(summary_path).unwrap();
+        assert!(summary.contains("Contig [chrEBV] good quality alignments\t1\n"));
+    }
+
+    #[test]
+    fn cram_input() {
+        use crate::test_bam::{sequence, write_reference, TestBam, TestRead};
+
+        let dir = tempfile::tempdir().unwrap();
+        let chr1 = sequence(1000, 42);
+        let reference = write_reference(dir.path(), "ref", "chr1", &chr1);
+        let cram = TestBam::new()
+            .contig("chr1", 1000)
+            .read(TestRead::mapped("mapped", "chr1", 100, &chr1[100..200]))
+            .read(TestRead::unmapped("unmapped", &sequence(100, 3)))
+            .write_cram(dir.path(), "sample", &reference);
+
+        let fasta = dir.path().join("sample.fasta");
+        let summary = dir.path().join("sample.bam_summary.txt");
+        crate::bam::bam2unmappedreads(
+            cram.to_str().unwrap(),
+            fasta.to_str().unwrap(),
+            summary.to_str().unwrap(),
+            &crate::bam::ReadFilterConfig::default(),
+            &crate::bam::ExtractionConfig {
+                reference: Some(reference),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let fasta = std::fs::read_to_string(fasta).unwrap();
+        assert_eq!(
+            fasta,
+            format!(
+                ">unmapped\n{}\n",
+                String::from_utf8(sequence(100, 3)).unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn cram_input_requires_reference() {
+        use crate::test_bam::{sequence, write_reference, TestBam, TestRead};
+
+        let dir = tempfile::tempdir().unwrap();
+        let chr1 = sequence(1000, 42);
+        let reference = write_reference(dir.path(), "ref", "chr1", &chr1);
+        let cram = TestBam::new()
+            .contig("chr1", 1000)
+            .read(TestRead::unmapped("unmapped", &sequence(100, 3)))
+            .write_cram(dir.path(), "sample", &reference);
+
+        let err = crate::bam::bam2unmappedreads(
+            cram.to_str().unwrap(),
+            dir.path().join("sample.fasta").to_str().unwrap(),
+            dir.path().join("sample.bam_summary.txt").to_str().unwrap(),
+            &crate::bam::ReadFilterConfig::default(),
+            &crate::bam::ExtractionConfig::default(),
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("please supply the reference FASTA"));
+    }
+
+    #[test]
+    fn missing_index_is_built() {
+        use crate::test_bam::{sequence, TestBam, TestRead};
+
+        let dir = tempfile::tempdir().unwrap();
+        let bam = TestBam::new()
+            .contig("chr1", 10_000)
+            .read(TestRead::unmapped("unmapped", &sequence(100, 1)))
+            .write(dir.path(), "sample");
+        let bai = dir.path().join("sample.bam.bai");
+        std::fs::remove_file(&bai).unwrap();
+
+        crate::bam::ensure_index(bam.to_str().unwrap()).unwrap();
+        assert!(bai.exists());
+    }
+
+    #[test]
+    fn long_contigs_use_csi() {
+        use crate::test_bam::{sequence, TestBam, TestRead};
+
+        let dir = tempfile::tempdir().unwrap();
+        let bam = TestBam::new()
+            .contig("chr1", 10_000)
+            .contig("chrEBV", 600_000_000)
+            .read(TestRead::mapped("ebv1", "chrEBV", 100, &sequence(100, 1)))
+            .read(TestRead::unmapped("unmapped", &sequence(100, 2)))
+            .write(dir.path(), "sample");
+        let csi = dir.path().join("sample.bam.csi");
+        std::fs::remove_file(&csi).unwrap();
+
+        crate::bam::ensure_index(bam.to_str().unwrap()).unwrap();
+        assert!(csi.exists());
+        assert!(!dir.path().join("sample.bam.bai").exists());
+
+        let fasta = dir.path().join("sample.fasta");
+        let summary = dir.path().join("sample.bam_summary.txt");
+        let run = || {
+            crate::bam::bam2unmappedreads(
+                bam.to_str().unwrap(),
+                fasta.to_str().unwrap(),
+                summary.to_str().unwrap(),
+                &crate::bam::ReadFilterConfig::default(),
+                &crate::bam::ExtractionConfig::default(),
+            )
+        };
+        // The chrEBV read is fetched and (lacking an alignment score) sent to kraken with the unmapped read
+        assert_eq!(run().unwrap().reads_written, 2);
+
+        // Past 2^29 bp a BAI can't be built at all; the bare htslib error gets a pointer to CSI
+        let far = TestBam::new()
+            .contig("chr1", 600_000_000)
+            .read(TestRead::mapped(
+                "far",
+                "chr1",
+                550_000_000,
+                &sequence(100, 3),
+            ))
+            .write(dir.path(), "far");
+        std::fs::remove_file(dir.path().join("far.bam.csi")).unwrap();
+        let err = rust_htslib::bam::index::build(&far, None, rust_htslib::bam::index::Type::Bai, 1)
+            .unwrap_err();
+        let reader = rust_htslib::bam::Reader::from_path(&far).unwrap();
+        let err = crate::bam::index_error(
+            err,
+            far.to_str().unwrap(),
+            rust_htslib::bam::Read::header(&reader),
+            "Failed to build index",
+        )
+        .to_string();
+        assert!(err.contains("Contig [chr1] (600000000 bp) is too long for a BAI index"));
+        assert!(err.contains("samtools index -c"));
+    }
+
+    #[test]
+    fn missing_index_on_unsorted_bam() {
+        use crate::test_bam::{sequence, TestBam, TestRead};
+
+        let dir = tempfile::tempdir().unwrap();
+        let bam = TestBam::new()
+            .sort_order("unsorted")
+            .contig("chr1", 10_000)
+            .read(TestRead::unmapped("unmapped", &sequence(100, 1)))
+            .write(dir.path(), "sample");
+        std::fs::remove_file(dir.path().join("sample.bam.bai")).unwrap();
+
+        let err = crate::bam::ensure_index(bam.to_str().unwrap()).unwrap_err();
+        assert!(err.to_string().contains("not coordinate-sorted"));
+    }
+
+    #[test]
+    fn max_n_threshold_is_honored() {
+        let mut seq = crate::test_bam::sequence(100, 1);
+        seq[10..13].copy_from_slice(b"NNN");
+        let record = make_record(&seq, 30);
+        let enriched = crate::bam::parse_record(&record).unwrap();
+
+        let filters = crate::bam::ReadFilterConfig {
+            max_n: 2,
+            ..Default::default()
+        };
+        assert!(!crate::bam::is_good_quality_sequence(&enriched, &filters).is_pass());
+
+        let filters = crate::bam::ReadFilterConfig {
+            max_n: 3,
+            ..Default::default()
+        };
+        assert!(crate::bam::is_good_quality_sequence(&enriched, &filters).is_pass());
+    }
+
+    #[test]
+    fn fastq_output() {
+        use crate::test_bam::{sequence, TestBam, TestRead};
+
+        let dir = tempfile::tempdir().unwrap();
+        let bam = TestBam::new()
+            .contig("chr1", 10_000)
+            .read(TestRead::unmapped("good1", &sequence(60, 1)))
+            // 0xFF qualities are how htslib stores an absent (`*`) quality string
+            .read(TestRead::unmapped("noqual", &sequence(60, 2)).qual(0xFF))
+            // Malformed: beyond what FASTQ can encode
+            .read(TestRead::unmapped("highqual", &sequence(60, 3)).qual(240))
+            .write(dir.path(), "sample");
+
+        let fastq = dir.path().join("sample.fastq");
+        let config = crate::bam::ExtractionConfig {
+            emit_fastq: true,
+            ..Default::default()
+        };
+        crate::bam::bam2unmappedreads(
+            bam.to_str().unwrap(),
+            fastq.to_str().unwrap(),
+            dir.path().join("sample.bam_summary.txt").to_str().unwrap(),
+            &crate::bam::ReadFilterConfig::default(),
+            &config,
+        )
+        .unwrap();
+
+        let fastq = std::fs::read_to_string(fastq).unwrap();
+        let lines: Vec<&str> = fastq.lines().collect();
+        let seq1 = String::from_utf8(sequence(60, 1)).unwrap();
+        let seq2 = String::from_utf8(sequence(60, 2)).unwrap();
+        let seq3 = String::from_utf8(sequence(60, 3)).unwrap();
+        let (qual, high_qual) = ("?".repeat(60), "~".repeat(60));
+        assert_eq!(
+            lines,
+            vec![
+                "@good1",
+                &seq1,
+                "+",
+                &qual,
+                ">noqual",
+                &seq2,
+                "@highqual",
+                &seq3,
+                "+",
+                &high_qual
+            ]
+        );
+    }
+
+    #[test]
+    fn paired_output() {
+        use crate::test_bam::{sequence, TestBam, TestRead};
+
+        let dir = tempfile::tempdir().unwrap();
+        let bam = TestBam::new()
+            .contig("chr1", 10_000)
+            .read(TestRead::unmapped("pairA", &sequence(60, 1)).flags(0x1 | 0x40))
+            .read(TestRead::unmapped("pairA", &sequence(60, 2)).flags(0x1 | 0x80))
+            .read(TestRead::unmapped("pairB", &sequence(60, 3)).flags(0x1 | 0x40))
+            .read(TestRead::unmapped("pairB", &sequence(30, 4)).flags(0x1 | 0x80))
+            .read(TestRead::unmapped("single", &sequence(60, 5)))
+            .write(dir.path(), "sample");
+
+        let fasta = dir.path().join("sample.fasta");
+        let summary = dir.path().join("sample.bam_summary.txt");
+        let config = crate::bam::ExtractionConfig {
+            paired_output: true,
+            ..Default::default()
+        };
+        crate::bam::bam2unmappedreads(
+            bam.to_str().unwrap(),
+            fasta.to_str().unwrap(),
+            summary.to_str().unwrap(),
+            &crate::bam::ReadFilterConfig::default(),
+            &config,
+        )
+        .unwrap();
+
+        let headers = |path: std::path::PathBuf| -> Vec<String> {
+            std::fs::read_to_string(path)
+                .unwrap()
+                .lines()
+                .filter(|l| l.starts_with('>'))
+                .map(String::from)
+                .collect()
+        };
+        assert_eq!(
+            headers(fasta),
+            vec![">pairA/1", ">pairA/2", ">pairB/1", ">single"]
+        );
+        assert_eq!(
+            headers(dir.path().join("sample_R1.fasta")),
+            vec![">pairA/1"]
+        );
+        assert_eq!(
+            headers(dir.path().join("sample_R2.fasta")),
+            vec![">pairA/2"]
+        );
+
+        let summary = std::fs::read_to_string(&summary).unwrap();
+        assert!(summary.contains("orphan mates dropped from paired output\t1\n"));
+
+        // A mate collapsed as a duplicate sequence takes its partner out of the paired files with it
+        let bam = TestBam::new()
+            .contig("chr1", 10_000)
+            .read(TestRead::unmapped("pairA", &sequence(60, 1)).flags(0x1 | 0x40))
+            .read(TestRead::unmapped("pairA", &sequence(60, 2)).flags(0x1 | 0x80))
+            .read(TestRead::unmapped("pairC", &sequence(60, 3)).flags(0x1 | 0x40))
+            .read(TestRead::unmapped("pairC", &sequence(60, 1)).flags(0x1 | 0x80))
+            .write(dir.path(), "dedup");
+        let fasta = dir.path().join("dedup.fasta");
+        let summary = dir.path().join("dedup.bam_summary.txt");
+        crate::bam::bam2unmappedreads(
+            bam.to_str().unwrap(),
+            fasta.to_str().unwrap(),
+            summary.to_str().unwrap(),
+            &crate::bam::ReadFilterConfig::default(),
+            &crate::bam::ExtractionConfig {
+                dedup_sequences: true,
+                ..config
+            },
+        )
+        .unwrap();
+        assert_eq!(headers(fasta), vec![">pairA/1", ">pairA/2", ">pairC/1"]);
+        assert_eq!(headers(dir.path().join("dedup_R1.fasta")), vec![">pairA/1"]);
+        assert_eq!(headers(dir.path().join("dedup_R2.fasta")), vec![">pairA/2"]);
+        let summary = std::fs::read_to_string(summary).unwrap();
+        assert!(summary.contains("orphan mates dropped from paired output\t0\n"));
+        assert!(summary.contains("pairs with a duplicate sequence dropped from paired output\t1\n"));
+    }
+
+    #[test]
+    fn low_complexity_sequences() {
+        use crate::bam::sequence_complexity;
+        let homopolymer = "A".repeat(100);
+        let dinucleotide = "AT".repeat(50);
+        let random = String::from_utf8(crate::test_bam::sequence(100, 1)).unwrap();
+        assert_eq!(sequence_complexity(&homopolymer), 0.0);
+        assert!(sequence_complexity(&dinucleotide) < 0.2);
+        assert!(sequence_complexity(&random) > 0.8);
+
+        let filters = crate::bam::ReadFilterConfig::default();
+        for seq in [&homopolymer, &dinucleotide] {
+            let record = make_record(seq.as_bytes(), 30);
+            let enriched = crate::bam::parse_record(&record).unwrap();
+            assert_eq!(
+                crate::bam::is_good_quality_sequence(&enriched, &filters),
+                crate::bam::ReadQc::LowComplexity
+            );
+        }
+        let record = make_record(random.as_bytes(), 30);
+        let enriched = crate::bam::parse_record(&record).unwrap();
+        assert!(crate::bam::is_good_quality_sequence(&enriched, &filters).is_pass());
+    }
+
+    #[test]
+    fn homopolymer_and_tandem_repeats() {
+        use crate::bam::{longest_homopolymer, tandem_repeat_fraction, ReadQc};
+        let random = crate::test_bam::sequence(100, 1);
+        let poly_a_tail = [&random[..75], &[b'A'; 25][..]].concat();
+        let cag_repeat = [&random[..40], "CAG".repeat(20).as_bytes()].concat();
+
+        assert_eq!(longest_homopolymer(b"ACGTTTTGNNNNNNNNA"), 4);
+        assert_eq!(longest_homopolymer(&poly_a_tail), 25);
+        assert!((tandem_repeat_fraction(&"CAG".repeat(33).into_bytes()) - 1.0).abs() < 1e-9);
+        assert!((tandem_repeat_fraction(&cag_repeat) - 0.6).abs() < 0.05);
+        assert!(tandem_repeat_fraction(&random) < 0.1);
+        // Two copies aren't a repeat
+        assert_eq!(tandem_repeat_fraction(b"ACGTACGT"), 0.0);
+
+        // Both reads are diverse enough overall to pass the entropy filter
+        let filters = crate::bam::ReadFilterConfig::default();
+        let qc = |seq: &[u8], filters: &crate::bam::ReadFilterConfig| {
+            let record = make_record(seq, 30);
+            crate::bam::is_good_quality_sequence(
+                &crate::bam::parse_record(&record).unwrap(),
+                filters,
+            )
+        };
+        assert_eq!(qc(&poly_a_tail, &filters), ReadQc::Homopolymer);
+        assert_eq!(qc(&cag_repeat, &filters), ReadQc::TandemRepeat);
+        assert!(qc(&random, &filters).is_pass());
+
+        let lenient = crate::bam::ReadFilterConfig {
+            max_homopolymer_run: 30,
+            max_tandem_repeat_fraction: 0.7,
+            ..Default::default()
+        };
+        assert!(qc(&poly_a_tail, &lenient).is_pass());
+        assert!(qc(&cag_repeat, &lenient).is_pass());
+    }
+
+    #[test]
+    fn reverse_strand_reads_keep_reference_orientation() {
+        use crate::test_bam::{sequence, TestBam, TestRead};
+
+        let reference = sequence(1000, 9);
+        let aligned = &reference[100..200];
+        let mut record = make_record(aligned, 30);
+        record.set_reverse();
+        assert_eq!(
+            crate::bam::sequence_in_ref_orientation(&record).as_bytes(),
+            aligned
+        );
+
+        // Without an AS tag the alignment isn't convincing, so the read is sent to kraken
+        let dir = tempfile::tempdir().unwrap();
+        let bam = TestBam::new()
+            .contig("chrEBV", 1000)
+            .read(TestRead::mapped("reverse", "chrEBV", 100, aligned).flags(0x10))
+            .write(dir.path(), "sample");
+        let fasta = dir.path().join("sample.fasta");
+        crate::bam::bam2unmappedreads(
+            bam.to_str().unwrap(),
+            fasta.to_str().unwrap(),
+            dir.path().join("sample.bam_summary.txt").to_str().unwrap(),
+            &crate::bam::ReadFilterConfig::default(),
+            &crate::bam::ExtractionConfig::default(),
+        )
+        .unwrap();
+        let fasta = std::fs::read_to_string(fasta).unwrap();
+        assert_eq!(
+            fasta,
+            format!(">reverse\n{}\n", std::str::from_utf8(aligned).unwrap())
+        );
+    }
+
+    #[test]
+    fn compressed_output() {
+        use crate::test_bam::{sequence, TestBam, TestRead};
+        use std::io::Read;
+
+        let dir = tempfile::tempdir().unwrap();
+        let bam = TestBam::new()
+            .contig("chr1", 10_000)
+            .read(TestRead::mapped("mapped1", "chr1", 100, &sequence(100, 1)))
+            .read(TestRead::unmapped("good1", &sequence(100, 2)).flags(0x1 | 0x40))
+            .read(TestRead::unmapped("good1", &sequence(100, 3)).flags(0x1 | 0x80))
+            .read(TestRead::unmapped("short", &sequence(30, 4)))
+            .write(dir.path(), "sample");
+
+        let fasta = dir.path().join("sample.fasta.gz");
+        let summary = dir.path().join("sample.bam_summary.txt");
+        let config = crate::bam::ExtractionConfig {
+            paired_output: true,
+            ..Default::default()
+        };
+        crate::bam::bam2unmappedreads(
+            bam.to_str().unwrap(),
+            fasta.to_str().unwrap(),
+            summary.to_str().unwrap(),
+            &crate::bam::ReadFilterConfig::default(),
+            &config,
+        )
+        .unwrap();
+
+        let gunzip = |path: std::path::PathBuf| -> String {
+            let mut contents = String::new();
+            flate2::read::GzDecoder::new(std::fs::File::open(path).unwrap())
+                .read_to_string(&mut contents)
+                .unwrap();
+            contents
+        };
+        let headers: Vec<String> = gunzip(fasta)
+            .lines()
+            .filter(|l| l.starts_with('>'))
+            .map(String::from)
+            .collect();
+        assert_eq!(headers, vec![">good1/1", ">good1/2"]);
+        assert!(gunzip(dir.path().join("sample_R1.fasta.gz")).starts_with(">good1/1\n"));
+        assert!(gunzip(dir.path().join("sample_R2.fasta.gz")).starts_with(">good1/2\n"));
+
+        let summary = std::fs::read_to_string(summary).unwrap();
+        assert!(summary.contains("total depth (number of reads)\t4\n"));
+        assert!(summary.contains("total unmapped reads\t3\n"));
+    }
+
+    #[test]
+    fn mate_anchored_reads() {
+        use crate::test_bam::{sequence, TestBam, TestRead};
+
+        let dir = tempfile::tempdir().unwrap();
+        // 0x1 paired, 0x4 unmapped, 0x8 mate unmapped, 0x40/0x80 first/last in template
+        let bam = TestBam::new()
+            .contig("chr1", 10_000)
+            .read(
+                TestRead::mapped("anchored", "chr1", 100, &sequence(100, 1))
+                    .flags(0x1 | 0x8 | 0x40),
+            )
+            .read(
+                TestRead::mapped("anchored", "chr1", 100, &sequence(100, 2))
+                    .flags(0x1 | 0x4 | 0x80),
+            )
+            .read(TestRead::mapped("proper", "chr1", 300, &sequence(100, 3)).flags(0x1 | 0x40))
+            .read(TestRead::mapped("proper", "chr1", 500, &sequence(100, 4)).flags(0x1 | 0x80))
+            .read(TestRead::unmapped("unmapped", &sequence(100, 5)).flags(0x1 | 0x8 | 0x40))
+            .read(TestRead::unmapped("unmapped", &sequence(100, 6)).flags(0x1 | 0x8 | 0x80))
+            .write(dir.path(), "sample");
+
+        let run = |include_mate_anchored: bool| -> Vec<String> {
+            let fasta = dir.path().join("sample.fasta");
+            let config = crate::bam::ExtractionConfig {
+                include_mate_anchored,
+                ..Default::default()
+            };
+            crate::bam::bam2unmappedreads(
+                bam.to_str().unwrap(),
+                fasta.to_str().unwrap(),
+                dir.path().join("sample.bam_summary.txt").to_str().unwrap(),
+                &crate::bam::ReadFilterConfig::default(),
+                &config,
+            )
+            .unwrap();
+            std::fs::read_to_string(fasta)
+                .unwrap()
+                .lines()
+                .filter(|l| l.starts_with('>'))
+                .map(String::from)
+                .collect()
+        };
+
+        assert_eq!(run(false), vec![">unmapped/1", ">unmapped/2"]);
+        assert_eq!(
+            run(true),
+            vec![">unmapped/1", ">unmapped/2", ">anchored/1", ">anchored/2"]
+        );
+    }
+
+    #[test]
+    fn softclipped_segments_are_rescued() {
+        use crate::test_bam::{sequence, TestBam, TestRead};
+
+        let dir = tempfile::tempdir().unwrap();
+        let read = sequence(100, 1);
+        let bam = TestBam::new()
+            .contig("chr1", 10_000)
+            // 40S60M at 0-based 1000: junction at 1-based 1001, clip is SEQ[0..40]
+            .read(
+                TestRead::mapped("leading", "chr1", 1000, &read)
+                    .cigar(vec![Cigar::SoftClip(40), Cigar::Match(60)]),
+            )
+            // 70M30S at 0-based 2000: last aligned base at 1-based 2070, clip is SEQ[70..100]
+            .read(
+                TestRead::mapped("trailing", "chr1", 2000, &read)
+                    .cigar(vec![Cigar::Match(70), Cigar::SoftClip(30)]),
+            )
+            .read(
+                TestRead::mapped("short_clip", "chr1", 3000, &read)
+                    .cigar(vec![Cigar::SoftClip(10), Cigar::Match(90)]),
+            )
+            .write(dir.path(), "sample");
+
+        let fasta = dir.path().join("sample.fasta");
+        let config = crate::bam::ExtractionConfig {
+            min_softclip_len: Some(25),
+            ..Default::default()
+        };
+        crate::bam::bam2unmappedreads(
+            bam.to_str().unwrap(),
+            fasta.to_str().unwrap(),
+            dir.path().join("sample.bam_summary.txt").to_str().unwrap(),
+            &crate::bam::ReadFilterConfig::default(),
+            &config,
+        )
+        .unwrap();
+
+        let read = std::str::from_utf8(&read).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(fasta).unwrap(),
+            format!(
+                ">leading_SC1001\n{}\n>trailing_SC2070\n{}\n",
+                &read[..40],
+                &read[70..]
+            )
+        );
+    }
+
+    #[test]
+    fn sam_flag_filters() {
+        use crate::test_bam::{sequence, TestBam, TestRead};
+
+        let defaults = crate::bam::ReadFilterConfig::default();
+        assert!(defaults.passes_flags(0x4));
+        assert!(!defaults.passes_flags(0x4 | 0x100));
+        assert!(!defaults.passes_flags(0x800));
+
+        let dir = tempfile::tempdir().unwrap();
+        let bam = TestBam::new()
+            .contig("chrEBV", 10_000)
+            .read(TestRead::unmapped("single", &sequence(100, 1)))
+            .read(TestRead::unmapped("r1", &sequence(100, 2)).flags(0x1 | 0x40))
+            .read(TestRead::unmapped("r2", &sequence(100, 3)).flags(0x1 | 0x80))
+            .read(TestRead::mapped("ebv", "chrEBV", 100, &sequence(100, 4)))
+            .read(TestRead::mapped("ebv_secondary", "chrEBV", 300, &sequence(100, 5)).flags(0x100))
+            .write(dir.path(), "sample");
+
+        let run = |filters: &crate::bam::ReadFilterConfig| {
+            let fasta = dir.path().join("sample.fasta");
+            let summary = dir.path().join("sample.bam_summary.txt");
+            crate::bam::bam2unmappedreads(
+                bam.to_str().unwrap(),
+                fasta.to_str().unwrap(),
+                summary.to_str().unwrap(),
+                filters,
+                &crate::bam::ExtractionConfig::default(),
+            )
+            .unwrap();
+            let fasta = std::fs::read_to_string(fasta).unwrap();
+            let headers: Vec<String> = fasta
+                .lines()
+                .filter_map(|l| l.strip_prefix('>'))
+                .map(String::from)
+                .collect();
+            (headers, std::fs::read_to_string(summary).unwrap())
+        };
+
+        // By default only the secondary alignment is left out
+        let (headers, summary) = run(&defaults);
+        assert_eq!(headers, vec!["single", "r1/1", "r2/2", "ebv"]);
+        assert!(summary.contains("unmapped reads excluded by flag filter\t0\n"));
+        assert!(summary.contains("Contig [chrEBV] reads excluded by flag filter\t1\n"));
+
+        // -f 0x1 -F 0x80: paired reads, but not the second mate
+        let filters = crate::bam::ReadFilterConfig {
+            require_flags: 0x1,
+            exclude_flags: 0x80,
+            ..Default::default()
+        };
+        let (headers, summary) = run(&filters);
+        assert_eq!(headers, vec!["r1/1"]);
+        assert!(summary.contains("unmapped reads excluded by flag filter\t2\n"));
+        assert!(summary.contains("Contig [chrEBV] reads excluded by flag filter\t2\n"));
+    }
+
+    #[test]
+    fn reads_in_unmap_regions_are_reclassified() {
+        use crate::test_bam::{sequence, TestBam, TestRead};
+
+        let dir = tempfile::tempdir().unwrap();
+        let bam = TestBam::new()
+            .contig("chr1", 10_000)
+            .contig("chr2", 10_000)
+            // Ends just inside the region at 1000-2000
+            .read(TestRead::mapped("herv", "chr1", 901, &sequence(100, 1)))
+            .read(TestRead::mapped("herv_lowqual", "chr1", 1500, &sequence(100, 2)).qual(10))
+            .read(TestRead::mapped("host", "chr1", 900, &sequence(100, 3)))
+            .read(TestRead::mapped(
+                "other_contig",
+                "chr2",
+                1500,
+                &sequence(100, 4),
+            ))
+            .write(dir.path(), "sample");
+        let bed = dir.path().join("unmap.bed");
+        std::fs::write(&bed, "chr1\t1000\t2000\nchrUnknown\t0\t100\n").unwrap();
+
+        let fasta = dir.path().join("sample.fasta");
+        let summary = dir.path().join("sample.bam_summary.txt");
+        let config = crate::bam::ExtractionConfig {
+            unmap_bed: Some(bed),
+            ..Default::default()
+        };
+        crate::bam::bam2unmappedreads(
+            bam.to_str().unwrap(),
+            fasta.to_str().unwrap(),
+            summary.to_str().unwrap(),
+            &crate::bam::ReadFilterConfig::default(),
+            &config,
+        )
+        .unwrap();
+
+        let fasta = std::fs::read_to_string(fasta).unwrap();
+        let headers: Vec<&str> = fasta.lines().filter(|l| l.starts_with('>')).collect();
+        assert_eq!(headers, vec![">herv"]);
+        let summary = std::fs::read_to_string(summary).unwrap();
+        assert!(summary.contains("unmap region reads good quality sequences\t1\n"));
+    }
+
+    #[test]
+    fn alignment_score_is_length_normalized() {
+        use rust_htslib::bam::record::Aux;
+        let aligned = |seq: Vec<u8>, alignment_score: i32| {
+            let mut record = make_record(&seq, 30);
+            record.set_mapq(60);
+            record.push_aux(b"AS", Aux::I32(alignment_score)).unwrap();
+            record
+        };
+        let filters = crate::bam::ReadFilterConfig::default();
+
+        // 55/60 per base beats 70/150 even though the raw score is lower
+        let short_high = aligned(crate::test_bam::sequence(60, 1), 55);
+        let enriched = crate::bam::parse_record(&short_high).unwrap();
+        assert_eq!(enriched.alignment_score, 55);
+        assert!(crate::bam::is_good_quality_alignment(
+            &enriched, &filters, 10, 0.5
+        ));
+
+        let long_low = aligned(crate::test_bam::sequence(150, 2), 70);
+        let enriched = crate::bam::parse_record(&long_low).unwrap();
+        assert!((enriched.normalized_alignment_score - 70.0 / 150.0).abs() < 1e-9);
+        assert!(!crate::bam::is_good_quality_alignment(
+            &enriched, &filters, 10, 0.5
+        ));
+    }
+
+    #[test]
+    fn microbial_contigs_from_csv() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("contigs.csv");
+
+        std::fs::write(
+            &path,
+            "taxid,common_name,contigs\n10376,EBV,chrEBV; NC_007605\n333760,HPV16,HPV16\n",
+        )
+        .unwrap();
+        let contigs = crate::bam::read_microbial_contigs(&path).unwrap();
+        assert_eq!(contigs.contig_to_species("NC_007605"), Some("EBV"));
+        assert_eq!(contigs.contig_to_taxid("HPV16"), Some("333760"));
+        assert!(!contigs.contains("NC_000898"));
+
+        std::fs::write(&path, "taxid,name,contigs\n10376,EBV,chrEBV\n").unwrap();
+        let err = crate::bam::read_microbial_contigs(&path).err().unwrap();
+        assert!(err.to_string().contains("expected header"));
+
+        std::fs::write(
+            &path,
+            "taxid,common_name,contigs\n10376,EBV,chrEBV\nEBV,10376,\n",
+        )
+        .unwrap();
+        let err = crate::bam::read_microbial_contigs(&path).err().unwrap();
+        assert!(err.to_string().contains("line 3"));
     }
 }
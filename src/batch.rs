@@ -0,0 +1,365 @@
+use std::path::{Path, PathBuf};
+
+use crate::bam::{bam2microbes, sanitize_filename, ExtractionConfig, ReadFilterConfig};
+use crate::kraken::KrakenConfig;
+use crate::summary::RunStatus;
+
+/// Options for `micrite batch`
+#[derive(Debug, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BatchConfig {
+    pub layout: Layout,
+    /// Samples screened at once (`--jobs N` on the command line). Each job runs its own kraken2 process,
+    /// which loads its own copy of the database: memory use is `jobs` times the database size
+    /// unless [KrakenConfig::memory_mapping] is set
+    pub jobs: usize,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        BatchConfig {
+            layout: Layout::default(),
+            jobs: 1,
+        }
+    }
+}
+
+impl BatchConfig {
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.jobs == 0 {
+            return Err("jobs must be at least 1".into());
+        }
+        Ok(())
+    }
+}
+
+/// Where the results of each sample in a batch are written
+#[derive(Debug, Default, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    /// One directory per sample: `<outdir>/<sample>/<sample>.*`
+    #[default]
+    Nested,
+    /// Every sample in `<outdir>`: `<outdir>/<sample>.*`
+    Flat,
+}
+
+/// Outcome of screening one bam in a batch
+pub struct SampleResult {
+    pub bam: PathBuf,
+    /// Sample id prefixing every output file: the bam stem with characters awkward in filenames replaced
+    pub sample: String,
+    /// Where this sample's results were written (`<outdir>/<sample>`, or `<outdir>` with [Layout::Flat])
+    pub outdir: PathBuf,
+    /// How far screening got (None if it failed or never started)
+    pub status: Option<RunStatus>,
+    /// Why screening failed (None on success)
+    pub error: Option<String>,
+}
+
+/// Read a newline separated list of bam paths, skipping blank lines and `#` comments
+pub fn read_bam_list(path: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let contents = std::fs::read_to_string(path)?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(PathBuf::from)
+        .collect())
+}
+
+/// Screen each bam into `outdir` (laid out as `layout`), running up to `jobs` samples at once.
+///
+/// Each sample runs its own kraken2 process with `config_kraken.threads` threads (and its own copy of the
+/// kraken database in memory), so up to `jobs * threads` cores are used.
+/// [ExtractionConfig::debug_reads] only switches the debug TSV on: each sample writes `<sample>.debug_reads.tsv`
+/// into its own output directory. A sample that fails (missing file, duplicate sample id, or a panic
+/// anywhere in [bam2microbes]) is recorded and the batch moves on. Results are in input order
+pub fn screen_bams(
+    bams: &[PathBuf],
+    outdir: &str,
+    jobs: usize,
+    layout: Layout,
+    config_kraken: &KrakenConfig,
+    config_filters: &ReadFilterConfig,
+    config_extraction: &ExtractionConfig,
+) -> Vec<SampleResult> {
+    // Validate up front: samples sharing an id (e.g. `a/tumour.bam` and `b/tumour.bam`)
+    // would write to the same files concurrently
+    let mut seen_samples = std::collections::HashSet::new();
+    let mut results: Vec<SampleResult> = bams
+        .iter()
+        .map(|bam| {
+            let sample = sanitize_filename(
+                &bam.file_stem()
+                    .map(|stem| stem.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+            );
+            let error = if !bam.is_file() {
+                Some(format!("Could not find BAM file [{}]", bam.display()))
+            } else if !seen_samples.insert(sample.clone()) {
+                Some(format!(
+                    "Another bam in the batch also has sample id [{}]; results would overwrite each other",
+                    sample
+                ))
+            } else {
+                None
+            };
+            SampleResult {
+                bam: bam.clone(),
+                outdir: match layout {
+                    Layout::Nested => Path::new(outdir).join(&sample),
+                    Layout::Flat => PathBuf::from(outdir),
+                },
+                sample,
+                status: None,
+                error,
+            }
+        })
+        .collect();
+    let runnable: Vec<usize> = (0..results.len())
+        .filter(|&i| results[i].error.is_none())
+        .collect();
+
+    // Workers pull the next sample off a shared counter until none are left
+    let next = std::sync::atomic::AtomicUsize::new(0);
+    let outcomes: Vec<(usize, Result<RunStatus, String>)> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs.clamp(1, runnable.len().max(1)))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut outcomes = vec![];
+                    loop {
+                        let n = next.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                        let Some(&i) = runnable.get(n) else {
+                            break;
+                        };
+                        let result = &results[i];
+                        eprintln!(
+                            "Screening sample {}/{}: {}",
+                            n + 1,
+                            runnable.len(),
+                            result.bam.display()
+                        );
+                        let outcome = screen_sample(
+                            &result.bam,
+                            &result.sample,
+                            &result.outdir,
+                            config_kraken,
+                            config_filters,
+                            config_extraction,
+                        );
+                        outcomes.push((i, outcome));
+                    }
+                    outcomes
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("batch worker panicked"))
+            .collect()
+    });
+    for (i, outcome) in outcomes {
+        match outcome {
+            Ok(status) => results[i].status = Some(status),
+            Err(error) => results[i].error = Some(error),
+        }
+    }
+
+    for result in &results {
+        if let Some(error) = &result.error {
+            eprintln!("Failed to screen [{}]: {}", result.bam.display(), error);
+        }
+    }
+    results
+}
+
+/// Run [bam2microbes] on one sample, turning its error (or a panic, e.g. from a failed kraken run) into an error message.
+/// Panics are also logged by the panic hook
+fn screen_sample(
+    bam: &Path,
+    sample: &str,
+    outdir: &Path,
+    config_kraken: &KrakenConfig,
+    config_filters: &ReadFilterConfig,
+    config_extraction: &ExtractionConfig,
+) -> Result<RunStatus, String> {
+    let bam = bam.to_str().ok_or("bam path is not valid UTF-8")?;
+    let outdir = outdir.to_str().ok_or("output path is not valid UTF-8")?;
+    let mut config_kraken = config_kraken.clone();
+    config_kraken.outdir = outdir.to_string();
+    // One debug TSV per sample: samples sharing a path would overwrite (and, with several jobs, race) each other
+    let config_extraction = ExtractionConfig {
+        sample_id: Some(sample.to_string()),
+        debug_reads: config_extraction
+            .debug_reads
+            .as_ref()
+            .map(|_| Path::new(outdir).join(format!("{}.debug_reads.tsv", sample))),
+        ..config_extraction.clone()
+    };
+
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        bam2microbes(
+            bam,
+            outdir,
+            config_kraken,
+            config_filters,
+            &config_extraction,
+        )
+    }))
+    .map_err(|payload| {
+        payload
+            .downcast_ref::<String>()
+            .cloned()
+            .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+            .unwrap_or_else(|| "unknown error".to_string())
+    })?
+    .map_err(|err| err.to_string())
+}
+
+/// Print a per-sample success/failure table to stderr. Returns the number of failed samples
+pub fn report_batch(results: &[SampleResult]) -> usize {
+    eprintln!("Batch Summary:");
+    for result in results {
+        match (&result.error, result.status) {
+            (Some(error), _) => eprintln!("\tfailed\t{}\t{}", result.bam.display(), error),
+            (None, Some(RunStatus::NoReads)) => {
+                eprintln!("\tno-reads\t{}", result.bam.display())
+            }
+            (None, _) => eprintln!("\tok\t{}", result.bam.display()),
+        }
+    }
+    let failed = results.iter().filter(|r| r.error.is_some()).count();
+    eprintln!(
+        "\t[{}] of [{}] samples screened successfully",
+        results.len() - failed,
+        results.len()
+    );
+    failed
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    #[test]
+    fn bam_list_skips_blanks_and_comments() {
+        let dir = tempfile::tempdir().unwrap();
+        let list = dir.path().join("bams.txt");
+        std::fs::write(&list, "# cohort\na.bam\n\n  b.bam  \n#c.bam\n").unwrap();
+        assert_eq!(
+            crate::batch::read_bam_list(&list).unwrap(),
+            vec![PathBuf::from("a.bam"), PathBuf::from("b.bam")]
+        );
+    }
+
+    #[test]
+    fn batch_continues_past_failures() {
+        use crate::test_bam::{sequence, TestBam, TestRead};
+
+        let dir = tempfile::tempdir().unwrap();
+        let bam = TestBam::new()
+            .contig("chr1", 10_000)
+            .read(TestRead::unmapped("good1", &sequence(100, 1)))
+            .write(dir.path(), "sample1");
+        let bam2 = TestBam::new()
+            .contig("chr1", 10_000)
+            .read(TestRead::unmapped("good2", &sequence(100, 2)))
+            .write(dir.path(), "sample2");
+        let empty = TestBam::new()
+            .contig("chr1", 10_000)
+            .read(TestRead::unmapped("short", &sequence(30, 3)))
+            .write(dir.path(), "empty");
+        let bams = vec![
+            dir.path().join("missing.bam"),
+            bam.clone(),
+            bam,
+            bam2,
+            empty,
+        ];
+
+        let outdir = dir.path().join("results");
+        let config_kraken = crate::kraken::KrakenConfig {
+            outdir: String::new(),
+            ..Default::default()
+        };
+        let config_extraction = crate::bam::ExtractionConfig {
+            tmpdir: Some(dir.path().join("tmp")),
+            stop_after: Some(crate::bam::Stage::Extract),
+            debug_reads: Some(dir.path().join("debug_reads.tsv")),
+            ..Default::default()
+        };
+        let results = crate::batch::screen_bams(
+            &bams,
+            outdir.to_str().unwrap(),
+            2,
+            crate::batch::Layout::Nested,
+            &config_kraken,
+            &crate::bam::ReadFilterConfig::default(),
+            &config_extraction,
+        );
+
+        let errors: Vec<bool> = results.iter().map(|r| r.error.is_some()).collect();
+        assert_eq!(errors, vec![true, false, true, false, false]);
+        assert_eq!(results[4].status, Some(crate::summary::RunStatus::NoReads));
+        assert!(outdir.join("sample1/sample1.bam_summary.txt").exists());
+        assert!(outdir.join("sample2/sample2.bam_summary.txt").exists());
+        assert!(outdir.join("sample1/sample1.debug_reads.tsv").exists());
+        assert!(outdir.join("sample2/sample2.debug_reads.tsv").exists());
+        assert!(!dir.path().join("debug_reads.tsv").exists());
+        assert_eq!(crate::batch::report_batch(&results), 2);
+
+        // Flat: every sample's outputs side by side, prefixed with its (sanitized) sample id
+        std::fs::create_dir(dir.path().join("rerun")).unwrap();
+        for (from, to) in [("sample1", "rerun/sample 1"), ("sample2", "sample_1")] {
+            for ext in ["bam", "bam.bai"] {
+                std::fs::copy(
+                    dir.path().join(format!("{}.{}", from, ext)),
+                    dir.path().join(format!("{}.{}", to, ext)),
+                )
+                .unwrap();
+            }
+        }
+        let bams = vec![
+            dir.path().join("rerun/sample 1.bam"),
+            dir.path().join("sample2.bam"),
+            // Sanitizes to the same sample id as the first bam
+            dir.path().join("sample_1.bam"),
+        ];
+        let flat = dir.path().join("flat");
+        let results = crate::batch::screen_bams(
+            &bams,
+            flat.to_str().unwrap(),
+            2,
+            crate::batch::Layout::Flat,
+            &config_kraken,
+            &crate::bam::ReadFilterConfig::default(),
+            &config_extraction,
+        );
+        let samples: Vec<&str> = results.iter().map(|r| r.sample.as_str()).collect();
+        assert_eq!(samples, vec!["sample_1", "sample2", "sample_1"]);
+        assert!(results[2]
+            .error
+            .as_ref()
+            .unwrap()
+            .contains("also has sample id [sample_1]"));
+        assert!(flat.join("sample_1.bam_summary.txt").exists());
+        assert!(flat.join("sample2.bam_summary.txt").exists());
+        assert!(!flat.join("sample2").exists());
+    }
+
+    #[test]
+    fn layout_from_config() {
+        let config: crate::batch::BatchConfig =
+            serde_json::from_str(r#"{ "layout": "flat" }"#).unwrap();
+        assert_eq!(config.layout, crate::batch::Layout::Flat);
+        let config: crate::batch::BatchConfig = serde_json::from_str("{}").unwrap();
+        assert_eq!(config.layout, crate::batch::Layout::Nested);
+        assert_eq!(config.jobs, 1);
+        let config: crate::batch::BatchConfig = serde_json::from_str(r#"{ "jobs": 0 }"#).unwrap();
+        assert!(config.validate().is_err());
+        assert!(
+            serde_json::from_str::<crate::batch::BatchConfig>(r#"{ "layout": "tree" }"#).is_err()
+        );
+    }
+}
@@ -0,0 +1,127 @@
+use std::path::Path;
+
+use crate::bam::{ExtractionConfig, ReadFilterConfig};
+use crate::batch::BatchConfig;
+use crate::kraken::KrakenConfig;
+
+/// Every option of a screen, grouped by the config struct it belongs to, as read from a JSON file e.g.
+///
+/// ```json
+/// {
+///   "kraken": { "krakendb": "~/databases/kraken2/k2_standard", "threads": 16, "confidence": "0.05" },
+///   "filters": { "min_len": 60 },
+///   "extraction": { "tmpdir": "/scratch", "emit_fastq": true, "stop_after": "Extract" },
+///   "batch": { "layout": "flat", "jobs": 4 }
+/// }
+/// ```
+///
+/// Sections (and any key within them) may be left out to keep their defaults. Section keys are the fields of
+/// [KrakenConfig], [ReadFilterConfig], [ExtractionConfig] and [BatchConfig]. Unknown keys are an error rather than silently ignored
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScreenConfig {
+    pub kraken: KrakenConfig,
+    pub filters: ReadFilterConfig,
+    pub extraction: ExtractionConfig,
+    pub batch: BatchConfig,
+}
+
+impl ScreenConfig {
+    /// Check every section's values (see [KrakenConfig::validate], [ReadFilterConfig::validate],
+    /// [ExtractionConfig::validate] and [BatchConfig::validate]) so a bad value fails the run before any reads are read
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.kraken
+            .validate()
+            .map_err(|err| format!("kraken: {}", err))?;
+        self.filters
+            .validate()
+            .map_err(|err| format!("filters: {}", err))?;
+        self.extraction
+            .validate()
+            .map_err(|err| format!("extraction: {}", err))?;
+        self.batch
+            .validate()
+            .map_err(|err| format!("batch: {}", err))?;
+        Ok(())
+    }
+}
+
+/// Read a [ScreenConfig] from a JSON file. Fails on malformed JSON, wrongly typed values or unknown keys
+pub fn read_screen_config(path: &Path) -> Result<ScreenConfig, Box<dyn std::error::Error>> {
+    let file = std::fs::File::open(path)
+        .map_err(|err| format!("Failed to open config [{}]: {}", path.display(), err))?;
+    let config: ScreenConfig = serde_json::from_reader(std::io::BufReader::new(file))
+        .map_err(|err| format!("Invalid config [{}]: {}", path.display(), err))?;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn screen_config_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("micrite.json");
+        std::fs::write(
+            &path,
+            r#"{
+                "kraken": { "threads": 2, "confidence": "0.1", "bracken": { "db": "db", "read_len": 150 } },
+                "filters": { "min_len": 60 },
+                "extraction": { "emit_fastq": true, "stop_after": "Extract" }
+            }"#,
+        )
+        .unwrap();
+        let config = crate::config::read_screen_config(&path).unwrap();
+        assert_eq!(config.kraken.threads, 2);
+        assert_eq!(config.kraken.confidence, "0.1");
+        assert_eq!(config.kraken.bracken.unwrap().read_len, 150);
+        assert_eq!(config.kraken.outdir, "outdir");
+        assert_eq!(config.filters.min_len, 60);
+        assert_eq!(config.filters.max_n, 2);
+        assert!(config.extraction.emit_fastq);
+        assert_eq!(
+            config.extraction.stop_after,
+            Some(crate::bam::Stage::Extract)
+        );
+
+        // Typos are reported rather than ignored
+        std::fs::write(&path, r#"{ "filters": { "min_lenght": 60 } }"#).unwrap();
+        let err = crate::config::read_screen_config(&path).unwrap_err();
+        assert!(err.to_string().contains("unknown field `min_lenght`"));
+        std::fs::write(&path, r#"{ "deacon": {} }"#).unwrap();
+        assert!(crate::config::read_screen_config(&path).is_err());
+    }
+
+    #[test]
+    fn out_of_range_values_are_rejected() {
+        let mut config = crate::config::ScreenConfig::default();
+        assert!(config.validate().is_ok());
+
+        // Fractions are fractions: 5 isn't 5%
+        config.extraction.warn_unmapped_fraction = Some(5.0);
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.starts_with("extraction: warn_unmapped_fraction [5] is out of range"));
+        config.extraction.warn_unmapped_fraction = Some(0.05);
+        assert!(config.validate().is_ok());
+
+        config.filters.min_complexity = 50.0;
+        assert!(config.validate().is_err());
+        config.filters.min_complexity = f64::NAN;
+        assert!(config.validate().is_err());
+        config.filters.min_complexity = 0.5;
+
+        config.filters.require_flags = 0x400;
+        config.filters.exclude_flags = 0x400;
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("no read could pass"));
+        config.filters.exclude_flags = 0x900;
+        assert!(config.validate().is_ok());
+
+        config.extraction.min_softclip_len = Some(0);
+        assert!(config.validate().is_err());
+        config.extraction.min_softclip_len = None;
+
+        config.kraken.confidence = "5".to_string();
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.starts_with("kraken: "));
+    }
+}
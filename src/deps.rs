@@ -0,0 +1,197 @@
+use std::path::Path;
+
+use crate::kraken::KrakenConfig;
+
+/// An external tool micrite shells out to
+struct Dependency {
+    name: &'static str,
+    required: bool,
+}
+
+/// The tools `config_kraken` needs: kraken2 or KrakenUniq (whichever classifies), bracken only if configured.
+/// minimap2 (for sleuth and subtype) is always optional
+fn dependencies(config_kraken: &KrakenConfig) -> [Dependency; 4] {
+    let krakenuniq = config_kraken.krakenuniq.is_some();
+    [
+        Dependency {
+            name: "kraken2",
+            required: !krakenuniq,
+        },
+        Dependency {
+            name: "krakenuniq",
+            required: krakenuniq,
+        },
+        Dependency {
+            name: "bracken",
+            required: config_kraken.bracken.is_some(),
+        },
+        Dependency {
+            name: "minimap2",
+            required: false,
+        },
+    ]
+}
+
+/// Files every kraken2 database directory must contain
+const KRAKEN_DB_FILES: [&str; 3] = ["hash.k2d", "opts.k2d", "taxo.k2d"];
+
+/// Files every KrakenUniq database directory must contain
+const KRAKENUNIQ_DB_FILES: [&str; 3] = ["database.kdb", "database.idx", "taxDB"];
+
+/// The databases `config_kraken` needs: a label, the directory and the files it must contain
+fn databases(config_kraken: &KrakenConfig) -> Vec<(&'static str, &Path, Vec<String>)> {
+    let files = |files: &[&str]| files.iter().map(|f| f.to_string()).collect();
+    let mut databases = vec![match &config_kraken.krakenuniq {
+        Some(krakenuniq) => (
+            "krakenuniq db",
+            krakenuniq.db.as_path(),
+            files(&KRAKENUNIQ_DB_FILES),
+        ),
+        None => (
+            "kraken db",
+            config_kraken.krakendb.as_path(),
+            files(&KRAKEN_DB_FILES),
+        ),
+    }];
+    if let Some(bracken) = &config_kraken.bracken {
+        databases.push((
+            "bracken db",
+            bracken.db.as_path(),
+            vec![format!("database{}mers.kmer_distrib", bracken.read_len)],
+        ));
+    }
+    databases
+}
+
+/// Probe external tools and databases, printing a readiness table to stderr.
+///
+/// Returns true if everything required is present
+pub fn check_deps(config_kraken: &KrakenConfig) -> bool {
+    let mut ready = true;
+
+    eprintln!(
+        "{:<14}{:<10}{:<10}detail",
+        "dependency", "required", "status"
+    );
+    for dep in &dependencies(config_kraken) {
+        let (ok, detail) = match which::which(dep.name) {
+            Ok(path) => (
+                true,
+                format!("{} ({})", path.display(), tool_version(&path)),
+            ),
+            Err(_) => (false, "not found on PATH".to_string()),
+        };
+        eprintln!(
+            "{:<14}{:<10}{:<10}{}",
+            dep.name,
+            if dep.required { "yes" } else { "no" },
+            if ok { "ok" } else { "MISSING" },
+            detail
+        );
+        ready &= ok | !dep.required;
+    }
+
+    for (label, db, files) in databases(config_kraken) {
+        let db = shellexpand::full(db.to_str().expect("failed to_str()"))
+            .expect("Failed expansion of DB filepath");
+        let missing = missing_db_files(Path::new(db.as_ref()), &files);
+        eprintln!(
+            "{:<14}{:<10}{:<10}{}",
+            label,
+            "yes",
+            if missing.is_empty() { "ok" } else { "MISSING" },
+            if missing.is_empty() {
+                db.to_string()
+            } else {
+                format!("{} (missing {})", db, missing.join(", "))
+            }
+        );
+        ready &= missing.is_empty();
+    }
+
+    ready
+}
+
+/// First line of `<tool> --version`
+fn tool_version(path: &Path) -> String {
+    std::process::Command::new(path)
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|out| {
+            String::from_utf8_lossy(&out.stdout)
+                .lines()
+                .next()
+                .map(|l| l.trim().to_string())
+        })
+        .unwrap_or_else(|| "unknown version".to_string())
+}
+
+/// Which of `files` are absent from the database directory `db`
+fn missing_db_files<'a>(db: &Path, files: &'a [String]) -> Vec<&'a str> {
+    files
+        .iter()
+        .filter(|f| !db.join(f).exists())
+        .map(String::as_str)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn kraken_db_validation() {
+        let db = tempfile::tempdir().unwrap();
+        let config = crate::kraken::KrakenConfig {
+            krakendb: db.path().to_path_buf(),
+            ..Default::default()
+        };
+        let (label, _, files) = crate::deps::databases(&config).remove(0);
+        assert_eq!(label, "kraken db");
+        assert_eq!(
+            crate::deps::missing_db_files(db.path(), &files),
+            vec!["hash.k2d", "opts.k2d", "taxo.k2d"]
+        );
+        for f in ["hash.k2d", "opts.k2d", "taxo.k2d"] {
+            std::fs::write(db.path().join(f), "").unwrap();
+        }
+        assert!(crate::deps::missing_db_files(db.path(), &files).is_empty());
+    }
+
+    #[test]
+    fn requirements_follow_the_classifier() {
+        let required = |config: &crate::kraken::KrakenConfig| -> Vec<&str> {
+            crate::deps::dependencies(config)
+                .iter()
+                .filter(|dep| dep.required)
+                .map(|dep| dep.name)
+                .collect()
+        };
+        let kraken2 = crate::kraken::KrakenConfig::default();
+        assert_eq!(required(&kraken2), vec!["kraken2"]);
+        assert_eq!(crate::deps::databases(&kraken2).len(), 1);
+
+        let krakenuniq = crate::kraken::KrakenConfig {
+            krakenuniq: Some(crate::kraken::KrakenUniqConfig {
+                db: "uniqdb".into(),
+                preload: false,
+            }),
+            ..Default::default()
+        };
+        assert_eq!(required(&krakenuniq), vec!["krakenuniq"]);
+        let databases = crate::deps::databases(&krakenuniq);
+        assert_eq!(databases.len(), 1);
+        assert_eq!(databases[0].0, "krakenuniq db");
+        assert_eq!(databases[0].1, std::path::Path::new("uniqdb"));
+
+        let bracken = crate::kraken::KrakenConfig {
+            bracken: Some(crate::kraken::BrackenConfig {
+                db: "k2db".into(),
+                read_len: 150,
+            }),
+            ..Default::default()
+        };
+        assert_eq!(required(&bracken), vec!["kraken2", "bracken"]);
+        let databases = crate::deps::databases(&bracken);
+        assert_eq!(databases[1].2, vec!["database150mers.kmer_distrib"]);
+    }
+}
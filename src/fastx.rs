@@ -0,0 +1,186 @@
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+
+/// One FASTA or FASTQ record
+#[derive(Debug, PartialEq)]
+pub struct FastxRecord {
+    /// Header up to the first whitespace
+    pub name: String,
+    pub seq: Vec<u8>,
+    /// Phred scores (not ASCII encoded). None for FASTA records
+    pub qual: Option<Vec<u8>>,
+}
+
+/// Records of a FASTA or FASTQ file, gunzipped if the path ends in `.gz`.
+///
+/// The format is decided per record by its header (`>` or `@`). FASTA sequences may span lines;
+/// FASTQ records must be the usual four lines
+pub struct FastxReader {
+    path: PathBuf,
+    lines: std::io::Lines<Box<dyn BufRead>>,
+    line_number: usize,
+    /// A header line read while looking for the end of the previous FASTA record
+    pending: Option<String>,
+}
+
+impl FastxReader {
+    pub fn from_path(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let file = std::fs::File::open(path)
+            .map_err(|err| format!("Failed to open [{}]: {}", path.display(), err))?;
+        let reader: Box<dyn BufRead> = if path.extension().is_some_and(|ext| ext == "gz") {
+            Box::new(std::io::BufReader::new(flate2::read::MultiGzDecoder::new(
+                file,
+            )))
+        } else {
+            Box::new(std::io::BufReader::new(file))
+        };
+        Ok(FastxReader {
+            path: path.to_path_buf(),
+            lines: reader.lines(),
+            line_number: 0,
+            pending: None,
+        })
+    }
+
+    fn next_line(&mut self) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        if let Some(line) = self.pending.take() {
+            return Ok(Some(line));
+        }
+        self.line_number += 1;
+        match self.lines.next().transpose() {
+            Ok(line) => Ok(line.map(|line| line.trim_end_matches('\r').to_string())),
+            Err(err) => Err(format!("Failed to read [{}]: {}", self.path.display(), err).into()),
+        }
+    }
+
+    fn error(&self, message: &str) -> Box<dyn std::error::Error> {
+        format!(
+            "Line {} of [{}]: {}",
+            self.line_number,
+            self.path.display(),
+            message
+        )
+        .into()
+    }
+
+    fn read_record(&mut self) -> Result<Option<FastxRecord>, Box<dyn std::error::Error>> {
+        let header = loop {
+            match self.next_line()? {
+                Some(line) if line.is_empty() => continue,
+                Some(line) => break line,
+                None => return Ok(None),
+            }
+        };
+        let name = |header: &str| header.split_whitespace().next().unwrap_or("").to_string();
+
+        if let Some(header) = header.strip_prefix('@') {
+            let seq = self
+                .next_line()?
+                .ok_or_else(|| self.error("FASTQ record has no sequence"))?;
+            if !self.next_line()?.is_some_and(|line| line.starts_with('+')) {
+                return Err(self.error("expected a FASTQ '+' separator line"));
+            }
+            let qual = self
+                .next_line()?
+                .ok_or_else(|| self.error("FASTQ record has no qualities"))?;
+            if qual.len() != seq.len() {
+                return Err(self.error("FASTQ sequence and quality lengths differ"));
+            }
+            let qual = qual
+                .bytes()
+                .map(|q| q.checked_sub(33))
+                .collect::<Option<Vec<u8>>>()
+                .ok_or_else(|| self.error("FASTQ qualities must be phred+33"))?;
+            return Ok(Some(FastxRecord {
+                name: name(header),
+                seq: seq.into_bytes(),
+                qual: Some(qual),
+            }));
+        }
+
+        if let Some(header) = header.strip_prefix('>') {
+            let mut seq = vec![];
+            while let Some(line) = self.next_line()? {
+                if line.starts_with('>') || line.starts_with('@') {
+                    self.pending = Some(line);
+                    break;
+                }
+                seq.extend_from_slice(line.trim().as_bytes());
+            }
+            return Ok(Some(FastxRecord {
+                name: name(header),
+                seq,
+                qual: None,
+            }));
+        }
+
+        Err(self.error("expected a FASTA ('>') or FASTQ ('@') header"))
+    }
+}
+
+impl Iterator for FastxReader {
+    type Item = Result<FastxRecord, Box<dyn std::error::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+/// File name of `path` without a reads extension (`.fa`, `.fasta`, `.fq` or `.fastq`, optionally `.gz`)
+pub fn reads_stem(path: &Path) -> Option<&str> {
+    let name = path.file_name()?.to_str()?;
+    let name = name.strip_suffix(".gz").unwrap_or(name);
+    Some(
+        [".fasta", ".fastq", ".fa", ".fq", ".fna"]
+            .iter()
+            .find_map(|ext| name.strip_suffix(ext))
+            .unwrap_or(name),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn reads_fasta_and_fastq() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("reads.fasta");
+        std::fs::write(
+            &path,
+            ">read1 some description\nACGT\nTTGG\n\n@read2\nACG\n+\nII#\n>read3\nGG\n",
+        )
+        .unwrap();
+        let records: Vec<_> = crate::fastx::FastxReader::from_path(&path)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].name, "read1");
+        assert_eq!(records[0].seq, b"ACGTTTGG");
+        assert_eq!(records[0].qual, None);
+        assert_eq!(records[1].qual.as_deref(), Some(&[40, 40, 2][..]));
+        assert_eq!(records[2].seq, b"GG");
+
+        std::fs::write(&path, "@read1\nACGT\n+\nIII\n").unwrap();
+        let err = crate::fastx::FastxReader::from_path(&path)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap_err();
+        assert!(err.to_string().contains("lengths differ"));
+        std::fs::write(&path, "ACGT\n").unwrap();
+        assert!(crate::fastx::FastxReader::from_path(&path)
+            .unwrap()
+            .next()
+            .unwrap()
+            .is_err());
+    }
+
+    #[test]
+    fn reads_stems() {
+        use crate::fastx::reads_stem;
+        use std::path::Path;
+        assert_eq!(reads_stem(Path::new("dir/sample.fastq.gz")), Some("sample"));
+        assert_eq!(reads_stem(Path::new("sample.R1.fq")), Some("sample.R1"));
+        assert_eq!(reads_stem(Path::new("sample.txt")), Some("sample.txt"));
+    }
+}
@@ -1,20 +1,262 @@
-use std::{path::PathBuf, string};
+use std::path::{Path, PathBuf};
 
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct KrakenConfig {
     pub krakendb: PathBuf,
     pub threads: u8,
     pub confidence: String,
     pub outdir: String,
+    /// Pass `--memory-mapping` so kraken2 reads the database from disk instead of loading it into RAM
+    pub memory_mapping: bool,
+    /// Classify a tiny synthetic FASTA before real work so the database is resident (see [warmup_kraken])
+    pub warmup: bool,
+    /// Keep a FASTA (`<prefix>.classified.fasta`) of only the reads kraken2 classified to these taxids
+    pub classified_out_taxids: Option<Vec<u64>>,
+    /// Keep kraken2's per-read assignments (`<prefix>.kout`) so reads of a hit can be pulled out later (see [extract_reads])
+    pub keep_kout: bool,
+    /// Re-estimate species abundances from the report with Bracken (see [run_bracken]). Raw kraken counts are used when None
+    pub bracken: Option<BrackenConfig>,
+    /// Also write the report as a Krona text file (`<prefix>.krona.txt`, see [write_krona])
+    pub krona: bool,
+    /// Classify with KrakenUniq instead of kraken2 (see [run_krakenuniq]). kraken2 is used when None
+    pub krakenuniq: Option<KrakenUniqConfig>,
+    /// NCBI taxonomy used to give each species in the run summary its full lineage.
+    /// When None, lineages are reconstructed from the kraken report (see [crate::taxonomy::kreport_lineage_names])
+    pub taxonomy: Option<crate::taxonomy::TaxonomyConfig>,
+    /// Spike-in control whose recovery is checked and reported in the run summary (see [crate::summary::PositiveControl])
+    pub positive_control: Option<PositiveControlConfig>,
 }
-pub fn run_kraken(fasta: std::path::PathBuf, config: KrakenConfig) {
+
+impl Default for KrakenConfig {
+    fn default() -> Self {
+        KrakenConfig {
+            krakendb: PathBuf::from("~/databases/kraken2/k2_standard_08gb_20240605"),
+            threads: 8,
+            confidence: "0.01".to_string(),
+            outdir: "outdir".to_string(),
+            memory_mapping: false,
+            warmup: false,
+            classified_out_taxids: None,
+            keep_kout: false,
+            bracken: None,
+            krona: false,
+            krakenuniq: None,
+            taxonomy: None,
+            positive_control: None,
+        }
+    }
+}
+
+impl KrakenConfig {
+    /// Check settings kraken2 would otherwise reject cryptically (or silently misuse)
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let confidence: f64 = self.confidence.trim().parse().map_err(|_| {
+            format!(
+                "kraken confidence [{}] is not a number (expected a value between 0 and 1)",
+                self.confidence
+            )
+        })?;
+        if !(0.0..=1.0).contains(&confidence) {
+            return Err(format!(
+                "kraken confidence [{}] is out of range (expected a value between 0 and 1)",
+                self.confidence
+            )
+            .into());
+        }
+        if self.krakenuniq.is_some() {
+            if self.bracken.is_some() {
+                return Err("bracken can't re-estimate abundances from a KrakenUniq report".into());
+            }
+            if self.classified_out_taxids.is_some() {
+                return Err("classified_out_taxids is only supported with kraken2".into());
+            }
+        }
+        Ok(())
+    }
+
+    /// The classifier these options select: KrakenUniq if [KrakenConfig::krakenuniq] is set, otherwise kraken2
+    pub fn classifier(&self) -> Box<dyn Classifier + '_> {
+        match &self.krakenuniq {
+            Some(krakenuniq) => Box::new(KrakenUniq {
+                config: self,
+                krakenuniq,
+            }),
+            None => Box::new(Kraken2(self)),
+        }
+    }
+}
+
+/// A read classifier writing a kraken-style report to [KrakenConfig::outdir]
+pub trait Classifier {
+    fn run(&self, fasta: PathBuf) -> KrakenOutput;
+}
+
+/// kraken2 (see [run_kraken])
+pub struct Kraken2<'a>(pub &'a KrakenConfig);
+
+impl Classifier for Kraken2<'_> {
+    fn run(&self, fasta: PathBuf) -> KrakenOutput {
+        run_kraken(fasta, self.0.clone())
+    }
+}
+
+/// KrakenUniq (see [run_krakenuniq])
+pub struct KrakenUniq<'a> {
+    pub config: &'a KrakenConfig,
+    pub krakenuniq: &'a KrakenUniqConfig,
+}
+
+impl Classifier for KrakenUniq<'_> {
+    fn run(&self, fasta: PathBuf) -> KrakenOutput {
+        run_krakenuniq(fasta, self.config, self.krakenuniq)
+    }
+}
+
+/// Options for classifying with KrakenUniq. Threads, output directory, `keep_kout` and `krona` come from the [KrakenConfig]
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KrakenUniqConfig {
+    /// KrakenUniq database directory
+    pub db: PathBuf,
+    /// Pass `--preload` so the whole database is loaded into RAM before classifying
+    #[serde(default)]
+    pub preload: bool,
+}
+
+/// A synthetic sequence (or organism) spiked into libraries as a run-validity check
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PositiveControlConfig {
+    /// Taxid the control is classified to
+    pub taxid: u64,
+    /// Clade reads needed to call the control detected. A library that loses its spike-in has likely lost sample too
+    #[serde(default = "default_control_min_reads")]
+    pub min_reads: u64,
+}
+
+fn default_control_min_reads() -> u64 {
+    1
+}
+
+/// Options for Bracken abundance re-estimation
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BrackenConfig {
+    /// Kraken2 database directory holding the Bracken k-mer distribution (`database<read_len>mers.kmer_distrib`)
+    pub db: PathBuf,
+    /// Read length the Bracken k-mer distribution was built for
+    pub read_len: u32,
+}
+
+/// Classify a tiny synthetic FASTA to pull the kraken database into the page cache.
+///
+/// With `--memory-mapping`, the first real classification otherwise pays the cost of faulting the whole database in from disk.
+/// Returns how long the warmup took.
+pub fn warmup_kraken(config: &KrakenConfig) -> std::time::Duration {
+    let kraken_command = which::which("kraken2")
+        .expect("Kraken2 not found. Please ensure it is installed and added to your PATH.");
+    let db: std::borrow::Cow<'_, str> =
+        shellexpand::full(config.krakendb.to_str().expect("failed to_str()"))
+            .expect("Failed expansion of DB filepath");
+
+    let warmup_fasta = std::env::temp_dir().join(format!(
+        "micrite_kraken_warmup_{}.fasta",
+        std::process::id()
+    ));
+    std::fs::write(
+        &warmup_fasta,
+        ">warmup\nACGTTGCAAGGCTTACCGATGCATGCCGTAAGTCCGATAGCTTAGGCATCGATCGGATTACAGCTAGCTAGGCTA\n",
+    )
+    .expect("Failed to write kraken warmup fasta");
+
+    eprintln!("\nWarming up Kraken database");
+    let start = std::time::Instant::now();
+    let mut command = std::process::Command::new(kraken_command);
+    command
+        .args(["--db", db.as_ref()])
+        .args(["--threads", &config.threads.to_string()])
+        .args(["--output", "-"])
+        .args(["--report", "/dev/null"]);
+    if config.memory_mapping {
+        command.arg("--memory-mapping");
+    }
+    let output = command
+        .arg(&warmup_fasta)
+        .output()
+        .expect("Failed to run Kraken2 warmup");
+    let elapsed = start.elapsed();
+    std::fs::remove_file(&warmup_fasta).expect("Failed to remove kraken warmup fasta");
+
+    if !output.status.success() {
+        let stderr_str = String::from_utf8_lossy(&output.stderr);
+        panic!(
+            "\tKraken Warmup Failed. Stderr\n========\n{}\n========",
+            stderr_str
+        )
+    }
+    eprintln!("\tKraken warmup took {:.2}s", elapsed.as_secs_f64());
+    elapsed
+}
+
+/// Results of a [run_kraken] (or [run_krakenuniq]) classification
+pub struct KrakenOutput {
+    /// `kraken2` or `krakenuniq`
+    pub classifier: &'static str,
+    /// Per-read assignments (`<outdir>/<prefix>.kout`), if kept (see [KrakenConfig::keep_kout])
+    pub kout: Option<PathBuf>,
+    /// The parsed report (`<outdir>/<prefix>.kreport`)
+    pub kreport: Vec<KreportRecord>,
+    /// Run statistics kraken2 printed to stderr (None if they couldn't be parsed)
+    pub sequences_processed: Option<u64>,
+    pub sequences_classified: Option<u64>,
+    pub sequences_unclassified: Option<u64>,
+    /// Bracken species abundances (None if Bracken isn't configured)
+    pub bracken: Option<Vec<BrackenRecord>>,
+}
+
+/// The kraken2 flag needed to read `reads`, based on its extension (`.gz` or `.bz2`). None for uncompressed input
+fn kraken_compression_flag(reads: &Path) -> Option<&'static str> {
+    match reads.extension().and_then(|ext| ext.to_str()) {
+        Some("gz") => Some("--gzip-compressed"),
+        Some("bz2") => Some("--bzip2-compressed"),
+        _ => None,
+    }
+}
+
+/// Classify `fasta` with kraken2.
+///
+/// Gzip (`.gz`) and bzip2 (`.bz2`) input is read directly. kraken2 can't read zstd, so `.zst` input must be
+/// decompressed first (e.g. with `zstd -dc`)
+pub fn run_kraken(fasta: std::path::PathBuf, config: KrakenConfig) -> KrakenOutput {
     std::fs::create_dir_all(&config.outdir).expect("Failed to create output directory");
-    let filename = fasta.file_stem().expect("Failed to extract fasta file stem (are you sure you supplied a filepath and not a directory?)").to_str().expect("failed filepath to str conversion");
+    if fasta.extension().is_some_and(|ext| ext == "zst") {
+        panic!(
+            "kraken2 can't read zstd compressed input [{}]: please decompress it first (e.g. zstd -dc)",
+            fasta.display()
+        );
+    }
+    let compression_flag = kraken_compression_flag(&fasta);
+    let mut filename = fasta.file_stem().expect("Failed to extract fasta file stem (are you sure you supplied a filepath and not a directory?)").to_str().expect("failed filepath to str conversion");
+    if compression_flag.is_some() {
+        // <prefix>.fasta.gz -> <prefix>
+        filename = Path::new(filename)
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or(filename);
+    }
     let outfile_prefix = format!("{}/{}", config.outdir, filename);
     let outfile_report = format!("{}.kreport", outfile_prefix);
     // let outfile_unclassified = format!("{}.unclassified", outfile_prefix);
-    // let outfile_classified = format!("{}.classified", outfile_prefix);
-    // let outfile_output = format!("{}.output.tsv", outfile_prefix);
-    let outfile_output = "-";
+    let outfile_classified_all = format!("{}.classified.unfiltered.fasta", outfile_prefix);
+    let outfile_classified = format!("{}.classified.fasta", outfile_prefix);
+    let outfile_kout = format!("{}.kout", outfile_prefix);
+    let outfile_krona = format!("{}.krona.txt", outfile_prefix);
+    let outfile_output = if config.keep_kout {
+        outfile_kout.as_str()
+    } else {
+        "-"
+    };
     let kraken_command = which::which("kraken2")
         .expect("Kraken2 not found. Please ensure it is installed and added to your PATH.");
 
@@ -23,12 +265,21 @@ pub fn run_kraken(fasta: std::path::PathBuf, config: KrakenConfig) {
             .expect("Failed expansion of DB filepath");
 
     eprintln!("\nRunning Kraken");
-    let output = std::process::Command::new(kraken_command)
+    let mut command = std::process::Command::new(kraken_command);
+    if config.memory_mapping {
+        command.arg("--memory-mapping");
+    }
+    if let Some(flag) = compression_flag {
+        command.arg(flag);
+    }
+    if config.classified_out_taxids.is_some() {
+        command.args(["--classified-out", &outfile_classified_all]);
+    }
+    let output = command
         .args(["--db", db.as_ref()])
         .args(["--threads", &config.threads.to_string()])
         .args(["--confidence", &config.confidence])
         // .args(["--unclassified-out", &outfile_unclassified])
-        // .args(["--classified-out", &outfile_classified])
         .args(["--output", outfile_output])
         .args(["--report", &outfile_report])
         .arg(fasta)
@@ -42,5 +293,938 @@ pub fn run_kraken(fasta: std::path::PathBuf, config: KrakenConfig) {
             stderr_str
         )
     }
+    // e.g. "1000 sequences (0.15 Mbp) processed in 0.030s ..." then "  800 sequences classified (80.00%)"
+    let stderr_str = String::from_utf8_lossy(&output.stderr);
+    let sequences_processed = kraken_sequence_count(&stderr_str, "processed");
+    let sequences_classified = kraken_sequence_count(&stderr_str, "classified");
+    let sequences_unclassified = kraken_sequence_count(&stderr_str, "unclassified");
+    match (sequences_processed, sequences_classified) {
+        (Some(processed), Some(classified)) => eprintln!(
+            "\tKraken2 classified [{}] of [{}] sequences processed ([{}] unclassified)",
+            classified,
+            processed,
+            sequences_unclassified.map_or("?".to_string(), |n| n.to_string())
+        ),
+        _ => eprintln!("\tCould not parse run statistics from kraken2 stderr"),
+    }
     eprintln!("\tKraken report saved to: {}", outfile_report);
+    if config.keep_kout {
+        eprintln!("\tKraken per-read assignments saved to: {}", outfile_kout);
+    }
+
+    // Distinguish a real null result from kraken2 silently producing nothing
+    let kreport = read_kreport(Path::new(&outfile_report)).unwrap_or_else(|err| {
+        panic!(
+            "Kraken2 exited successfully but its report [{}] could not be read: {}",
+            outfile_report, err
+        )
+    });
+    if kreport.is_empty() {
+        panic!(
+            "Kraken2 exited successfully but produced an empty report [{}]. This usually indicates a kraken2 error",
+            outfile_report
+        )
+    }
+    if kreport_nreads_classified(&kreport) == 0 {
+        eprintln!("\tKraken2 ran successfully but classified no reads (the input may be tiny or contain nothing in the database)");
+    }
+
+    if config.krona {
+        write_krona(&kreport, Path::new(&outfile_krona)).expect("Failed to write krona text file");
+        eprintln!("\tKrona text file saved to: {}", outfile_krona);
+    }
+
+    // Subset classified reads to just the taxids of interest
+    if let Some(taxids) = &config.classified_out_taxids {
+        let taxids: std::collections::HashSet<u64> = taxids.iter().copied().collect();
+        let nreads = filter_classified_by_taxid(
+            Path::new(&outfile_classified_all),
+            Path::new(&outfile_classified),
+            &taxids,
+        )
+        .expect("Failed to subset kraken classified reads by taxid");
+        std::fs::remove_file(&outfile_classified_all)
+            .expect("Failed to remove unfiltered kraken classified reads");
+        eprintln!(
+            "\t{} reads classified to taxids of interest saved to: {}",
+            nreads, outfile_classified
+        );
+    }
+
+    let bracken = config
+        .bracken
+        .as_ref()
+        .map(|bracken| run_bracken(Path::new(&outfile_report), bracken));
+
+    KrakenOutput {
+        classifier: "kraken2",
+        kout: config.keep_kout.then(|| PathBuf::from(&outfile_kout)),
+        kreport,
+        sequences_processed,
+        sequences_classified,
+        sequences_unclassified,
+        bracken,
+    }
+}
+
+/// Classify `fasta` with KrakenUniq, writing `<outdir>/<prefix>.kreport`.
+///
+/// Besides read counts, KrakenUniq reports the distinct k-mers behind each taxon ([KreportRecord::unique_kmers]):
+/// reads sharing a handful of k-mers (a conserved or contaminating fragment) are told apart from reads spread across a genome
+pub fn run_krakenuniq(
+    fasta: std::path::PathBuf,
+    config: &KrakenConfig,
+    krakenuniq: &KrakenUniqConfig,
+) -> KrakenOutput {
+    std::fs::create_dir_all(&config.outdir).expect("Failed to create output directory");
+    let compression_flag = kraken_compression_flag(&fasta);
+    let mut filename = fasta.file_stem().expect("Failed to extract fasta file stem (are you sure you supplied a filepath and not a directory?)").to_str().expect("failed filepath to str conversion");
+    if compression_flag.is_some() {
+        filename = Path::new(filename)
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or(filename);
+    }
+    let outfile_prefix = format!("{}/{}", config.outdir, filename);
+    let outfile_report = format!("{}.kreport", outfile_prefix);
+    let outfile_kout = format!("{}.kout", outfile_prefix);
+    let outfile_krona = format!("{}.krona.txt", outfile_prefix);
+    let outfile_output = if config.keep_kout {
+        outfile_kout.as_str()
+    } else {
+        "/dev/null"
+    };
+    let krakenuniq_command = which::which("krakenuniq")
+        .expect("KrakenUniq not found. Please ensure it is installed and added to your PATH.");
+    let db: std::borrow::Cow<'_, str> =
+        shellexpand::full(krakenuniq.db.to_str().expect("failed to_str()"))
+            .expect("Failed expansion of DB filepath");
+
+    eprintln!("\nRunning KrakenUniq");
+    let mut command = std::process::Command::new(krakenuniq_command);
+    if krakenuniq.preload {
+        command.arg("--preload");
+    }
+    // KrakenUniq (like kraken 1) takes kraken2's compression flags, but needs to be told about FASTQ
+    if let Some(flag) = compression_flag {
+        command.arg(flag);
+    }
+    if Path::new(filename)
+        .extension()
+        .is_some_and(|ext| ext == "fastq" || ext == "fq")
+    {
+        command.arg("--fastq-input");
+    }
+    let output = command
+        .args(["--db", db.as_ref()])
+        .args(["--threads", &config.threads.to_string()])
+        .args(["--output", outfile_output])
+        .args(["--report-file", &outfile_report])
+        .arg(fasta)
+        .output()
+        .expect("Failed to run KrakenUniq classification");
+
+    let stderr_str = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        panic!(
+            "\tKrakenUniq Run Failed. Stderr\n========\n{}\n========",
+            stderr_str
+        )
+    }
+    let sequences_processed = kraken_sequence_count(&stderr_str, "processed");
+    let sequences_classified = kraken_sequence_count(&stderr_str, "classified");
+    let sequences_unclassified = kraken_sequence_count(&stderr_str, "unclassified");
+    match (sequences_processed, sequences_classified) {
+        (Some(processed), Some(classified)) => eprintln!(
+            "\tKrakenUniq classified [{}] of [{}] sequences processed",
+            classified, processed
+        ),
+        _ => eprintln!("\tCould not parse run statistics from KrakenUniq stderr"),
+    }
+    eprintln!("\tKrakenUniq report saved to: {}", outfile_report);
+    if config.keep_kout {
+        eprintln!(
+            "\tKrakenUniq per-read assignments saved to: {}",
+            outfile_kout
+        );
+    }
+
+    let kreport = read_kreport(Path::new(&outfile_report)).unwrap_or_else(|err| {
+        panic!(
+            "KrakenUniq exited successfully but its report [{}] could not be read: {}",
+            outfile_report, err
+        )
+    });
+    if kreport.is_empty() {
+        panic!(
+            "KrakenUniq exited successfully but produced an empty report [{}]",
+            outfile_report
+        )
+    }
+
+    if config.krona {
+        write_krona(&kreport, Path::new(&outfile_krona)).expect("Failed to write krona text file");
+        eprintln!("\tKrona text file saved to: {}", outfile_krona);
+    }
+
+    KrakenOutput {
+        classifier: "krakenuniq",
+        kout: config.keep_kout.then(|| PathBuf::from(&outfile_kout)),
+        kreport,
+        sequences_processed,
+        sequences_classified,
+        sequences_unclassified,
+        bracken: None,
+    }
+}
+
+/// Redistribute the reads of a kraken2 report to species level with Bracken.
+/// Writes `<kreport prefix>.bracken` (and Bracken's own report, `<kreport prefix>.breport`) beside the report
+pub fn run_bracken(kreport: &Path, config: &BrackenConfig) -> Vec<BrackenRecord> {
+    let outfile_bracken = kreport.with_extension("bracken");
+    let outfile_breport = kreport.with_extension("breport");
+    let bracken_command = which::which("bracken")
+        .expect("Bracken not found. Please ensure it is installed and added to your PATH.");
+    let db: std::borrow::Cow<'_, str> =
+        shellexpand::full(config.db.to_str().expect("failed to_str()"))
+            .expect("Failed expansion of Bracken DB filepath");
+
+    eprintln!("\nRunning Bracken");
+    let output = std::process::Command::new(bracken_command)
+        .args(["-d", db.as_ref()])
+        .arg("-i")
+        .arg(kreport)
+        .arg("-o")
+        .arg(&outfile_bracken)
+        .arg("-w")
+        .arg(&outfile_breport)
+        .args(["-r", &config.read_len.to_string()])
+        .args(["-l", "S"])
+        .output()
+        .expect("Failed to run Bracken");
+    if !output.status.success() {
+        let stderr_str = String::from_utf8_lossy(&output.stderr);
+        panic!(
+            "\tBracken Run Failed. Stderr\n========\n{}\n========",
+            stderr_str
+        )
+    }
+    eprintln!(
+        "\tBracken abundances saved to: {}",
+        outfile_bracken.display()
+    );
+    read_bracken(&outfile_bracken).unwrap_or_else(|err| {
+        panic!(
+            "Bracken exited successfully but its output [{}] could not be read: {}",
+            outfile_bracken.display(),
+            err
+        )
+    })
+}
+
+/// The count from a kraken2 stderr statistics line like `800 sequences classified (80.00%)`, where `outcome`
+/// is `processed`, `classified` or `unclassified`. None if no line matches or the count isn't a plain integer
+fn kraken_sequence_count(stderr: &str, outcome: &str) -> Option<u64> {
+    stderr.lines().find_map(|line| {
+        let mut words = line.split_whitespace();
+        let count = words.next()?.parse().ok()?;
+        (words.next()? == "sequences" && words.any(|word| word == outcome)).then_some(count)
+    })
+}
+
+/// Copy records from a kraken2 `--classified-out` FASTA whose `kraken:taxid|<taxid>` header tag is in `taxids`.
+///
+/// Only exact taxid matches are kept (descendant taxa are not included). Returns the number of reads written
+pub fn filter_classified_by_taxid(
+    classified: &Path,
+    output: &Path,
+    taxids: &std::collections::HashSet<u64>,
+) -> std::io::Result<u64> {
+    use std::io::{BufRead, Write};
+
+    let reader = std::io::BufReader::new(std::fs::File::open(classified)?);
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(output)?);
+    let mut keep = false;
+    let mut nreads: u64 = 0;
+    for line in reader.lines() {
+        let line = line?;
+        if line.starts_with('>') {
+            keep = line
+                .split_once("kraken:taxid|")
+                .and_then(|(_, rest)| rest.split_whitespace().next())
+                .and_then(|taxid| taxid.parse::<u64>().ok())
+                .is_some_and(|taxid| taxids.contains(&taxid));
+            if keep {
+                nreads += 1;
+            }
+        }
+        if keep {
+            writeln!(writer, "{}", line)?;
+        }
+    }
+    writer.flush()?;
+    Ok(nreads)
+}
+
+/// Read ids assigned to any of `taxids` in a kraken2 per-read output file (kout)
+fn kout_read_ids(
+    kout: &Path,
+    taxids: &std::collections::HashSet<u64>,
+) -> std::io::Result<std::collections::HashSet<String>> {
+    use std::io::BufRead;
+
+    let reader = std::io::BufReader::new(std::fs::File::open(kout)?);
+    let mut ids = std::collections::HashSet::new();
+    for line in reader.lines() {
+        let line = line?;
+        if let Some((id, taxid)) = parse_kout_line(&line) {
+            if taxids.contains(&taxid) {
+                ids.insert(id.to_string());
+            }
+        }
+    }
+    Ok(ids)
+}
+
+/// Read id and assigned taxid (0 if unclassified) of one line of kraken2's per-read output
+fn parse_kout_line(line: &str) -> Option<(&str, u64)> {
+    let mut fields = line.split('\t');
+    let (Some(_status), Some(id), Some(taxid)) = (fields.next(), fields.next(), fields.next())
+    else {
+        return None;
+    };
+    // Plain `<taxid>`, or `<name> (taxid <taxid>)` when kraken2 was run with --use-names
+    let taxid = taxid
+        .rsplit_once("(taxid ")
+        .map_or(taxid, |(_, rest)| rest.trim_end_matches(')'));
+    Some((id, taxid.trim().parse().ok()?))
+}
+
+/// Assigned taxid (0 if unclassified) of every read in kraken2's per-read output, keyed by read id
+pub fn kout_taxids(kout: &Path) -> std::io::Result<std::collections::HashMap<String, u64>> {
+    use std::io::BufRead;
+
+    let reader = std::io::BufReader::new(std::fs::File::open(kout)?);
+    let mut taxids = std::collections::HashMap::new();
+    for line in reader.lines() {
+        let line = line?;
+        if let Some((id, taxid)) = parse_kout_line(&line) {
+            taxids.insert(id.to_string(), taxid);
+        }
+    }
+    Ok(taxids)
+}
+
+/// Stream FASTA/FASTQ (optionally gzipped) records of `reads` whose id is in `ids` to `output` as FASTA,
+/// one record at a time. Returns the number of reads written and the number of `ids` never seen in `reads`
+fn write_reads_with_ids(
+    reads: &Path,
+    ids: &std::collections::HashSet<String>,
+    output: &Path,
+) -> std::io::Result<(u64, usize)> {
+    use std::io::{BufRead, Write};
+
+    let file = std::fs::File::open(reads)?;
+    let reader: Box<dyn BufRead> = if reads.extension().is_some_and(|ext| ext == "gz") {
+        Box::new(std::io::BufReader::new(flate2::read::MultiGzDecoder::new(
+            file,
+        )))
+    } else {
+        Box::new(std::io::BufReader::new(file))
+    };
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(output)?);
+    let mut lines = reader.lines();
+    let mut found: std::collections::HashSet<&String> = std::collections::HashSet::new();
+    let mut wanted = |header: &str| {
+        ids.get(header.split_whitespace().next().unwrap_or(""))
+            .map(|id| found.insert(id))
+            .is_some()
+    };
+    let mut keep = false;
+    let mut nreads: u64 = 0;
+    while let Some(line) = lines.next() {
+        let line = line?;
+        if let Some(header) = line.strip_prefix('@') {
+            // FASTQ: header, sequence, '+', qualities
+            let sequence = lines.next().transpose()?.unwrap_or_default();
+            lines.next().transpose()?;
+            lines.next().transpose()?;
+            keep = wanted(header);
+            if keep {
+                nreads += 1;
+                writeln!(writer, ">{}\n{}", header, sequence)?;
+            }
+        } else if let Some(header) = line.strip_prefix('>') {
+            keep = wanted(header);
+            if keep {
+                nreads += 1;
+                writeln!(writer, "{}", line)?;
+            }
+        } else if keep {
+            // Sequence lines of a (possibly multi-line) FASTA record
+            writeln!(writer, "{}", line)?;
+        }
+    }
+    writer.flush()?;
+    Ok((nreads, ids.len() - found.len()))
+}
+
+/// Pull the reads kraken2 assigned to `taxid` out of the `reads` it classified.
+///
+/// `kout` is the per-read output of that run (see [KrakenConfig::keep_kout]).
+/// With `include_children`, reads assigned to any descendant of `taxid` are included too, using the
+/// run's report (`<kout prefix>.kreport`) to resolve the subtree (see [build_taxid_children]).
+/// Writes `<kout prefix>.taxid<taxid>.fasta` and returns its path
+pub fn extract_reads(
+    kout: &Path,
+    reads: &Path,
+    taxid: u64,
+    include_children: bool,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let prefix = kout.with_extension("");
+    let output = PathBuf::from(format!("{}.taxid{}.fasta", prefix.display(), taxid));
+    let taxids = if include_children {
+        build_taxid_children(&kout.with_extension("kreport"), taxid)?
+    } else {
+        [taxid].into_iter().collect()
+    };
+    let ids = kout_read_ids(kout, &taxids)?;
+    let (nreads, nmissing) = write_reads_with_ids(reads, &ids, &output)?;
+    eprintln!(
+        "\tExtracted [{}] reads assigned to taxid [{}] to: {}",
+        nreads,
+        taxid,
+        output.display()
+    );
+    if nmissing > 0 {
+        eprintln!(
+            "\tWarning: [{}] reads assigned to taxid [{}] in [{}] are missing from [{}]. Was kraken2 run on a different reads file?",
+            nmissing,
+            taxid,
+            kout.display(),
+            reads.display()
+        );
+    }
+    Ok(output)
+}
+
+/// A single line of a kraken2 report (kreport)
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct KreportRecord {
+    pub clade_percent_classified: f64,
+    pub clade_nreads_classified: u64,
+    pub taxon_nreads_classified: u64,
+    pub rank: String,
+    pub taxid: u64,
+    pub name: String,
+    /// Depth in the taxonomy tree (taken from the indentation of the name column)
+    pub depth: usize,
+    /// Distinct k-mers assigned to the clade (KrakenUniq reports only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unique_kmers: Option<u64>,
+    /// Average times each of those k-mers was seen (KrakenUniq reports only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kmer_duplication: Option<f64>,
+    /// Fraction of the clade's k-mers in the database that were seen (KrakenUniq reports only, if known)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kmer_coverage: Option<f64>,
+}
+
+/// Rank codes of a kraken2 report for the rank names KrakenUniq writes
+fn krakenuniq_rank_code(rank: &str, taxid: u64) -> &str {
+    match (rank, taxid) {
+        (_, 0) => "U",
+        (_, 1) => "R",
+        ("superkingdom" | "domain", _) => "D",
+        ("kingdom", _) => "K",
+        ("phylum", _) => "P",
+        ("class", _) => "C",
+        ("order", _) => "O",
+        ("family", _) => "F",
+        ("genus", _) => "G",
+        ("species", _) => "S",
+        _ => "-",
+    }
+}
+
+/// Read a kraken2 report (standard 6 column format, or 8 columns if `--report-minimizer-data` was used)
+/// or a KrakenUniq report (`#` comments, a header, then 9 columns including k-mer counts).
+/// KrakenUniq rank names are converted to kraken2 rank codes (`-` for ranks without one)
+pub fn read_kreport(path: &Path) -> Result<Vec<KreportRecord>, Box<dyn std::error::Error>> {
+    // kraken2 never quotes fields, so quote characters in taxon names must be read literally
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .has_headers(false)
+        .quoting(false)
+        .flexible(true)
+        .from_path(path)?;
+
+    let mut records = Vec::new();
+    for result in reader.records() {
+        let row = result?;
+        if row[0].starts_with('#') || &row[0] == "%" {
+            continue;
+        }
+        if row.len() == 9 {
+            let raw_name = &row[8];
+            let name = raw_name.trim_start();
+            let taxid = row[6].trim().parse()?;
+            records.push(KreportRecord {
+                clade_percent_classified: row[0].trim().parse()?,
+                clade_nreads_classified: row[1].trim().parse()?,
+                taxon_nreads_classified: row[2].trim().parse()?,
+                rank: krakenuniq_rank_code(row[7].trim(), taxid).to_string(),
+                taxid,
+                name: name.to_string(),
+                depth: (raw_name.len() - name.len()) / 2,
+                unique_kmers: Some(row[3].trim().parse()?),
+                kmer_duplication: Some(row[4].trim().parse()?),
+                // `NA` when the database doesn't know the clade's genome size
+                kmer_coverage: row[5].trim().parse().ok(),
+            });
+            continue;
+        }
+        // Minimizer data adds two columns before rank
+        let offset = match row.len() {
+            6 => 0,
+            8 => 2,
+            n => return Err(format!("Unexpected number of columns in kreport: {}", n).into()),
+        };
+        let raw_name = &row[5 + offset];
+        let name = raw_name.trim_start();
+        records.push(KreportRecord {
+            clade_percent_classified: row[0].trim().parse()?,
+            clade_nreads_classified: row[1].trim().parse()?,
+            taxon_nreads_classified: row[2].trim().parse()?,
+            rank: row[3 + offset].trim().to_string(),
+            taxid: row[4 + offset].trim().parse()?,
+            name: name.to_string(),
+            depth: (raw_name.len() - name.len()) / 2,
+            unique_kmers: None,
+            kmer_duplication: None,
+            kmer_coverage: None,
+        });
+    }
+    Ok(records)
+}
+
+/// A single species of a Bracken output (`.bracken`)
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct BrackenRecord {
+    pub name: String,
+    #[serde(alias = "taxonomy_id")]
+    pub taxid: u64,
+    #[serde(alias = "taxonomy_lvl")]
+    pub rank: String,
+    pub kraken_assigned_reads: u64,
+    pub added_reads: u64,
+    /// Bracken's re-estimated read count
+    pub new_est_reads: u64,
+    pub fraction_total_reads: f64,
+}
+
+/// Read a Bracken output table (tab separated with a header line)
+pub fn read_bracken(path: &Path) -> Result<Vec<BrackenRecord>, Box<dyn std::error::Error>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .quoting(false)
+        .from_path(path)?;
+    Ok(reader
+        .deserialize()
+        .collect::<Result<Vec<BrackenRecord>, _>>()?)
+}
+
+/// For each line of a kraken2 report, the indices of its lineage from the top of the tree down to (and including) itself.
+///
+/// Reports list taxa depth-first, so a line's parent is the closest line above it that is one level shallower
+pub(crate) fn kreport_lineages(kreport: &[KreportRecord]) -> Vec<Vec<usize>> {
+    let mut lineage: Vec<usize> = vec![];
+    kreport
+        .iter()
+        .enumerate()
+        .map(|(i, record)| {
+            while lineage
+                .last()
+                .is_some_and(|&parent| kreport[parent].depth >= record.depth)
+            {
+                lineage.pop();
+            }
+            lineage.push(i);
+            lineage.clone()
+        })
+        .collect()
+}
+
+/// `root_taxid` and all its descendants, reconstructed from the nesting (indentation) of a kraken2 report
+/// (see [kreport_lineages]). Fails if `root_taxid` isn't in the report
+pub fn build_taxid_children(
+    kreport: &Path,
+    root_taxid: u64,
+) -> Result<std::collections::HashSet<u64>, Box<dyn std::error::Error>> {
+    let records = read_kreport(kreport)?;
+    let root = records
+        .iter()
+        .position(|r| r.taxid == root_taxid)
+        .ok_or_else(|| {
+            format!(
+                "taxid [{}] does not appear in kraken report [{}] so its children can't be resolved",
+                root_taxid,
+                kreport.display()
+            )
+        })?;
+    Ok(records
+        .iter()
+        .zip(kreport_lineages(&records))
+        .filter(|(_, lineage)| lineage.contains(&root))
+        .map(|(record, _)| record.taxid)
+        .collect())
+}
+
+/// Write a Krona text file (for `ktImportText`): one line per taxon with reads assigned directly to it,
+/// giving the read count then the names of its lineage (below root) from the top of the tree down
+pub fn write_krona(kreport: &[KreportRecord], path: &Path) -> std::io::Result<()> {
+    use std::io::Write;
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+    for (record, lineage) in kreport.iter().zip(kreport_lineages(kreport)) {
+        if record.taxon_nreads_classified == 0 {
+            continue;
+        }
+        write!(writer, "{}", record.taxon_nreads_classified)?;
+        for &i in &lineage {
+            // Krona adds its own root node
+            if kreport[i].taxid != 1 {
+                write!(writer, "\t{}", kreport[i].name)?;
+            }
+        }
+        writeln!(writer)?;
+    }
+    writer.flush()
+}
+
+/// Total number of reads kraken2 classified (i.e. the clade count of the root node)
+pub fn kreport_nreads_classified(kreport: &[KreportRecord]) -> u64 {
+    kreport
+        .iter()
+        .filter(|r| r.taxid == 1)
+        .map(|r| r.clade_nreads_classified)
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    #[test]
+    fn kreport_parsing() {
+        let kreport = crate::kraken::read_kreport(std::path::Path::new(
+            "outdir/humanGRCh38_9000_ebv_1000_hpv16_1000_hpylori_1000.grch38_noalt.kreport",
+        ))
+        .unwrap();
+        assert_eq!(kreport[0].rank, "U");
+        assert_eq!(kreport[0].clade_nreads_classified, 2568);
+        assert_eq!(kreport[2].name, "Viruses");
+        assert_eq!(kreport[2].depth, 1);
+        assert_eq!(crate::kraken::kreport_nreads_classified(&kreport), 3363);
+    }
+
+    #[test]
+    fn confidence_validation() {
+        let config = |confidence: &str| crate::kraken::KrakenConfig {
+            confidence: confidence.to_string(),
+            ..Default::default()
+        };
+        assert!(config("0.01").validate().is_ok());
+        assert!(config("0").validate().is_ok());
+        assert!(config("1.0").validate().is_ok());
+        let err = config("1.5").validate().unwrap_err();
+        assert!(err.to_string().contains("[1.5] is out of range"));
+        assert!(config("-0.1").validate().is_err());
+        let err = config("high").validate().unwrap_err();
+        assert!(err.to_string().contains("[high] is not a number"));
+    }
+
+    #[test]
+    fn compressed_input_flags() {
+        use crate::kraken::kraken_compression_flag;
+        use std::path::Path;
+        assert_eq!(
+            kraken_compression_flag(Path::new("tmp/sample.fasta.gz")),
+            Some("--gzip-compressed")
+        );
+        assert_eq!(
+            kraken_compression_flag(Path::new("tmp/sample.fastq.bz2")),
+            Some("--bzip2-compressed")
+        );
+        assert_eq!(kraken_compression_flag(Path::new("tmp/sample.fasta")), None);
+    }
+
+    #[test]
+    fn kraken_stderr_statistics() {
+        let stderr = "Loading database information... done.\n\
+            1000 sequences (0.15 Mbp) processed in 0.030s (2000.0 Kseq/m, 300.00 Mbp/m).\n\
+            \x20 800 sequences classified (80.00%)\n\
+            \x20 200 sequences unclassified (20.00%)\n";
+        let count = |outcome| crate::kraken::kraken_sequence_count(stderr, outcome);
+        assert_eq!(count("processed"), Some(1000));
+        assert_eq!(count("classified"), Some(800));
+        assert_eq!(count("unclassified"), Some(200));
+
+        // Unexpected formatting is not an error
+        let localised = "1.000 sequences (0,15 Mbp) processed in 0,030s\n";
+        assert_eq!(
+            crate::kraken::kraken_sequence_count(localised, "processed"),
+            None
+        );
+        assert_eq!(crate::kraken::kraken_sequence_count("", "classified"), None);
+    }
+
+    #[test]
+    fn bracken_parsing() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "name\ttaxonomy_id\ttaxonomy_lvl\tkraken_assigned_reads\tadded_reads\tnew_est_reads\tfraction_total_reads").unwrap();
+        writeln!(
+            file,
+            "Human gammaherpesvirus 4\t10376\tS\t900\t60\t960\t0.70000"
+        )
+        .unwrap();
+        writeln!(
+            file,
+            "Alphapapillomavirus 9\t337041\tS\t400\t12\t412\t0.30000"
+        )
+        .unwrap();
+        let bracken = crate::kraken::read_bracken(file.path()).unwrap();
+        assert_eq!(bracken.len(), 2);
+        assert_eq!(bracken[0].taxid, 10376);
+        assert_eq!(bracken[0].rank, "S");
+        assert_eq!(bracken[0].new_est_reads, 960);
+        assert_eq!(bracken[1].fraction_total_reads, 0.3);
+    }
+
+    #[test]
+    fn kreport_nothing_classified() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "100.00\t12\t12\tU\t0\tunclassified").unwrap();
+        let kreport = crate::kraken::read_kreport(file.path()).unwrap();
+        assert_eq!(kreport.len(), 1);
+        assert_eq!(crate::kraken::kreport_nreads_classified(&kreport), 0);
+
+        let empty = tempfile::NamedTempFile::new().unwrap();
+        assert!(crate::kraken::read_kreport(empty.path())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn kreport_names_with_commas_and_quotes() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, " 50.00\t2\t0\tR\t1\troot").unwrap();
+        writeln!(
+            file,
+            " 50.00\t2\t2\tS\t10376\t  Human gammaherpesvirus 4 (EBV), \"B95-8\""
+        )
+        .unwrap();
+        let kreport = crate::kraken::read_kreport(file.path()).unwrap();
+        assert_eq!(kreport[1].name, "Human gammaherpesvirus 4 (EBV), \"B95-8\"");
+        assert_eq!(kreport[1].depth, 1);
+    }
+
+    #[test]
+    fn krakenuniq_report_parsing() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "# KrakenUniq v0.7.3 DATE:2024-06-05T10:00:00Z DB:krakenuniq_db\tDB_SIZE:100\tWC_TIME:3.2").unwrap();
+        writeln!(
+            file,
+            "# CL:krakenuniq --db krakenuniq_db --report-file sample.kreport sample.fasta"
+        )
+        .unwrap();
+        writeln!(file).unwrap();
+        writeln!(
+            file,
+            "%\treads\ttaxReads\tkmers\tdup\tcov\ttaxID\trank\ttaxName"
+        )
+        .unwrap();
+        writeln!(file, "20\t5\t5\t0\t0\tNA\t0\tno rank\tunclassified").unwrap();
+        writeln!(file, "80\t20\t0\t1500\t1.2\tNA\t1\tno rank\troot").unwrap();
+        writeln!(
+            file,
+            "80\t20\t0\t1500\t1.2\tNA\t10239\tsuperkingdom\t  Viruses"
+        )
+        .unwrap();
+        writeln!(
+            file,
+            "80\t20\t20\t1500\t1.2\t0.0088\t10376\tspecies\t    Human gammaherpesvirus 4"
+        )
+        .unwrap();
+        let kreport = crate::kraken::read_kreport(file.path()).unwrap();
+
+        let ranks: Vec<&str> = kreport.iter().map(|r| r.rank.as_str()).collect();
+        assert_eq!(ranks, vec!["U", "R", "D", "S"]);
+        let ebv = &kreport[3];
+        assert_eq!(
+            (ebv.taxid, ebv.name.as_str(), ebv.depth),
+            (10376, "Human gammaherpesvirus 4", 2)
+        );
+        assert_eq!(ebv.clade_nreads_classified, 20);
+        assert_eq!(ebv.unique_kmers, Some(1500));
+        assert_eq!(ebv.kmer_duplication, Some(1.2));
+        assert_eq!(ebv.kmer_coverage, Some(0.0088));
+        assert_eq!(kreport[1].kmer_coverage, None);
+        assert_eq!(crate::kraken::kreport_nreads_classified(&kreport), 20);
+    }
+
+    #[test]
+    fn krakenuniq_rejects_kraken2_only_options() {
+        let krakenuniq = crate::kraken::KrakenConfig {
+            krakenuniq: Some(crate::kraken::KrakenUniqConfig {
+                db: std::path::PathBuf::from("unused"),
+                preload: false,
+            }),
+            ..Default::default()
+        };
+        assert!(krakenuniq.validate().is_ok());
+        let with_bracken = crate::kraken::KrakenConfig {
+            bracken: Some(crate::kraken::BrackenConfig {
+                db: std::path::PathBuf::from("unused"),
+                read_len: 150,
+            }),
+            ..krakenuniq.clone()
+        };
+        assert!(with_bracken.validate().is_err());
+        let with_taxids = crate::kraken::KrakenConfig {
+            classified_out_taxids: Some(vec![10376]),
+            ..krakenuniq
+        };
+        assert!(with_taxids.validate().is_err());
+    }
+
+    #[test]
+    fn classified_reads_subset_by_taxid() {
+        let output = tempfile::NamedTempFile::new().unwrap();
+        let nreads = crate::kraken::filter_classified_by_taxid(
+            std::path::Path::new(
+                "outdir/humanGRCh38_9000_ebv_1000_hpv16_1000_hpylori_1000.grch38_noalt.classified",
+            ),
+            output.path(),
+            &[333760].into_iter().collect(),
+        )
+        .unwrap();
+        assert_eq!(nreads, 996);
+
+        let contents = std::fs::read_to_string(output.path()).unwrap();
+        assert_eq!(contents.lines().count(), 996 * 2);
+        assert!(contents
+            .lines()
+            .filter(|l| l.starts_with('>'))
+            .all(|l| l.ends_with("kraken:taxid|333760")));
+    }
+
+    #[test]
+    fn extract_reads_by_taxid() {
+        let dir = tempfile::tempdir().unwrap();
+        let kout = dir.path().join("sample.kout");
+        std::fs::write(
+            &kout,
+            "C\tread1/1\t10376\t100\t10376:66\n\
+             U\tread2\t0\t100\t0:66\n\
+             C\tread3\tHuman gammaherpesvirus 4 (taxid 10376)\t100\t10376:66\n\
+             C\tread4\t333760\t100\t333760:66\n",
+        )
+        .unwrap();
+
+        let fasta = dir.path().join("sample.fasta");
+        std::fs::write(
+            &fasta,
+            ">read1/1\nACGT\n>read2\nCCCC\n>read3 comment\nGGGG\nTTTT\n>read4\nAAAA\n",
+        )
+        .unwrap();
+        let output = crate::kraken::extract_reads(&kout, &fasta, 10376, false).unwrap();
+        assert_eq!(output, dir.path().join("sample.taxid10376.fasta"));
+        assert_eq!(
+            std::fs::read_to_string(&output).unwrap(),
+            ">read1/1\nACGT\n>read3 comment\nGGGG\nTTTT\n"
+        );
+
+        // FASTQ input is written out as FASTA
+        let fastq = dir.path().join("sample.fastq");
+        std::fs::write(&fastq, "@read2\nCCCC\n+\n????\n@read4\nAAAA\n+\n@@@@\n").unwrap();
+        let output = crate::kraken::extract_reads(&kout, &fastq, 333760, false).unwrap();
+        assert_eq!(std::fs::read_to_string(output).unwrap(), ">read4\nAAAA\n");
+
+        // Children are resolved from the run's kreport
+        std::fs::write(
+            dir.path().join("sample.kreport"),
+            " 50.00\t4\t0\tR\t1\troot\n 50.00\t4\t0\tD\t10239\t  Viruses\n\
+             25.00\t2\t2\tS\t10376\t    EBV\n 25.00\t1\t1\tS\t333760\t    HPV16\n",
+        )
+        .unwrap();
+        let output = crate::kraken::extract_reads(&kout, &fasta, 10239, true).unwrap();
+        let headers: Vec<String> = std::fs::read_to_string(output)
+            .unwrap()
+            .lines()
+            .filter(|l| l.starts_with('>'))
+            .map(String::from)
+            .collect();
+        assert_eq!(headers, vec![">read1/1", ">read3 comment", ">read4"]);
+
+        // Ids in the kout but not the reads are counted
+        let ids = ["read1/1", "read3", "absent"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let subset = dir.path().join("subset.fasta");
+        assert_eq!(
+            crate::kraken::write_reads_with_ids(&fasta, &ids, &subset).unwrap(),
+            (2, 1)
+        );
+    }
+
+    #[test]
+    fn taxid_children_from_kreport() {
+        let kreport = std::path::Path::new(
+            "outdir/humanGRCh38_9000_ebv_1000_hpv16_1000_hpylori_1000.grch38_noalt.kreport",
+        );
+        let children = crate::kraken::build_taxid_children(kreport, 10375).unwrap();
+        assert_eq!(
+            children,
+            [10375, 3050299, 10376, 12509].into_iter().collect()
+        );
+        assert_eq!(
+            crate::kraken::build_taxid_children(kreport, 333760).unwrap(),
+            [333760].into_iter().collect()
+        );
+        let err = crate::kraken::build_taxid_children(kreport, 999999999).unwrap_err();
+        assert!(err.to_string().contains("does not appear"));
+    }
+
+    #[test]
+    fn krona_text() {
+        let dir = tempfile::tempdir().unwrap();
+        let kreport_path = dir.path().join("sample.kreport");
+        std::fs::write(
+            &kreport_path,
+            " 25.00\t5\t5\tU\t0\tunclassified\n\
+             75.00\t15\t1\tR\t1\troot\n\
+             70.00\t14\t0\tD\t10239\t  Viruses\n\
+             50.00\t10\t2\tG\t10375\t    Lymphocryptovirus\n\
+             40.00\t8\t8\tS\t10376\t      Human gammaherpesvirus 4\n\
+             20.00\t4\t4\tS\t333760\t    Human papillomavirus 16\n",
+        )
+        .unwrap();
+        let kreport = crate::kraken::read_kreport(&kreport_path).unwrap();
+
+        let lineages = crate::kraken::kreport_lineages(&kreport);
+        assert_eq!(lineages[4], vec![1, 2, 3, 4]);
+        assert_eq!(lineages[5], vec![1, 2, 5]);
+
+        let krona = dir.path().join("sample.krona.txt");
+        crate::kraken::write_krona(&kreport, &krona).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(krona).unwrap(),
+            "5\tunclassified\n\
+             1\n\
+             2\tViruses\tLymphocryptovirus\n\
+             8\tViruses\tLymphocryptovirus\tHuman gammaherpesvirus 4\n\
+             4\tViruses\tHuman papillomavirus 16\n"
+        );
+    }
 }
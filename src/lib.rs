@@ -1,2 +1,16 @@
 pub mod bam;
+pub mod batch;
+pub mod config;
+pub mod deps;
+pub mod fastx;
 pub mod kraken;
+pub mod merge;
+pub mod pipeline;
+pub mod regions;
+pub mod sleuth;
+pub mod state;
+pub mod subtype;
+pub mod summary;
+pub mod taxonomy;
+#[cfg(test)]
+pub(crate) mod test_bam;
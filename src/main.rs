@@ -3,16 +3,186 @@ fn main() {
     // micrite::bam2unmappedreads(bam_path, bam_output_path);
     // bam = "inst/"
 
-    let config = micrite::kraken::KrakenConfig {
-        krakendb: std::path::PathBuf::from("~/databases/kraken2/k2_standard_08gb_20240605"),
-        threads: 8,
-        confidence: "0.01".to_string(),
-        outdir: "outdir".to_string(),
+    // Options come from a JSON config file (`micrite --config <file> ...`) or the defaults
+    let mut args: Vec<String> = std::env::args().collect();
+    // `--quiet` (anywhere) turns off progress bars, `--resume` skips stages completed by an earlier run,
+    // `--no-multiqc` skips the MultiQC custom content
+    let quiet = args.iter().any(|arg| arg == "--quiet");
+    let resume = args.iter().any(|arg| arg == "--resume");
+    let no_multiqc = args.iter().any(|arg| arg == "--no-multiqc");
+    args.retain(|arg| arg != "--quiet" && arg != "--resume" && arg != "--no-multiqc");
+    // `--jobs N` (anywhere) sets how many samples `batch` screens at once
+    let jobs = args.iter().position(|arg| arg == "--jobs").map(|i| {
+        let jobs = args
+            .get(i + 1)
+            .and_then(|n| n.parse::<usize>().ok())
+            .unwrap_or_else(|| {
+                eprintln!("usage: --jobs <number of samples to screen at once>");
+                std::process::exit(1);
+            });
+        args.drain(i..i + 2);
+        jobs
+    });
+    let mut screen_config = if args.get(1).map(String::as_str) == Some("--config") {
+        let path = args
+            .get(2)
+            .expect("usage: micrite --config <config json> [subcommand ...]")
+            .clone();
+        args.drain(1..3);
+        let screen_config = micrite::config::read_screen_config(std::path::Path::new(&path))
+            .unwrap_or_else(|err| {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            });
+        eprintln!("Effective configuration:\n{:#?}", screen_config);
+        screen_config
+    } else {
+        micrite::config::ScreenConfig::default()
     };
+    screen_config.extraction.quiet |= quiet;
+    screen_config.extraction.resume |= resume;
+    screen_config.extraction.no_multiqc |= no_multiqc;
+    if let Some(jobs) = jobs {
+        screen_config.batch.jobs = jobs;
+    }
+    let config = screen_config.kraken.clone();
 
-    micrite::bam::bam2microbes(
-        "testfiles/humanGRCh38_9000_ebv_1000_hpv16_1000_hpylori_1000.grch38_noalt.bam",
-        "outdir",
-        config,
-    );
+    if let Err(err) = screen_config.validate() {
+        eprintln!("Invalid configuration: {}", err);
+        std::process::exit(1);
+    }
+
+    // Preflight: confirm external tools and databases are available
+    if args.get(1).map(String::as_str) == Some("check-deps") {
+        let ready = micrite::deps::check_deps(&config);
+        std::process::exit(if ready { 0 } else { 1 });
+    }
+
+    if config.warmup {
+        micrite::kraken::warmup_kraken(&config);
+    }
+
+    // Re-align the reads of a kraken hit to its genome and report coverage
+    if args.get(1).map(String::as_str) == Some("sleuth") {
+        const USAGE: &str =
+            "usage: micrite sleuth <reads fasta> <reference genome fasta> [--max-coverage-cv <cv>]";
+        let max_coverage_cv = args
+            .iter()
+            .position(|arg| arg == "--max-coverage-cv")
+            .map(|i| {
+                let cv = args
+                    .get(i + 1)
+                    .and_then(|cv| cv.parse::<f64>().ok())
+                    .unwrap_or_else(|| {
+                        eprintln!("{}", USAGE);
+                        std::process::exit(1);
+                    });
+                args.drain(i..i + 2);
+                cv
+            });
+        let (Some(reads), Some(reference)) = (args.get(2).cloned(), args.get(3).cloned()) else {
+            eprintln!("{}", USAGE);
+            std::process::exit(1);
+        };
+        let sleuth_config = micrite::sleuth::SleuthConfig {
+            reference: std::path::PathBuf::from(reference),
+            threads: config.threads,
+            outdir: config.outdir.clone(),
+            window_size: 1000,
+            max_coverage_cv,
+        };
+        if let Err(err) = sleuth_config.validate() {
+            eprintln!("Invalid configuration: {}", err);
+            std::process::exit(1);
+        }
+        micrite::sleuth::run_sleuth(std::path::Path::new(&reads), &sleuth_config);
+        return;
+    }
+
+    // Genotype a kraken hit (e.g. HPV) from a directory of per-subtype marker FASTAs
+    if args.get(1).map(String::as_str) == Some("subtype") {
+        let (Some(reads), Some(markers)) = (args.get(2).cloned(), args.get(3).cloned()) else {
+            eprintln!("usage: micrite subtype <reads fasta> <marker directory>");
+            std::process::exit(1);
+        };
+        micrite::subtype::run_subtype(
+            std::path::Path::new(&reads),
+            &micrite::subtype::SubtypeConfig {
+                markers: std::path::PathBuf::from(markers),
+                threads: config.threads,
+                outdir: config.outdir.clone(),
+                min_reads: 5,
+            },
+        );
+        return;
+    }
+
+    // Combine the kraken reports of a batch into cohort taxon-by-sample matrices
+    if args.get(1).map(String::as_str) == Some("merge") {
+        let dir = args
+            .get(2)
+            .cloned()
+            .expect("usage: micrite merge <directory of kraken reports>");
+        micrite::merge::run_merge(std::path::Path::new(&dir), &config.outdir);
+        return;
+    }
+
+    // Screen a FASTA/FASTQ of reads directly, without a bam
+    if args.get(1).map(String::as_str) == Some("screen-reads") {
+        let reads = args
+            .get(2)
+            .cloned()
+            .expect("usage: micrite screen-reads <reads fasta/fastq>");
+        if let Err(err) = micrite::pipeline::screen_reads(
+            std::path::Path::new(&reads),
+            &config,
+            &screen_config.filters,
+            &screen_config.extraction,
+        ) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Screen every bam listed (newline separated) in a file
+    if args.get(1).map(String::as_str) == Some("batch") {
+        let bam_list = args
+            .get(2)
+            .cloned()
+            .expect("usage: micrite batch <file of bam paths>");
+        let bams = micrite::batch::read_bam_list(std::path::Path::new(&bam_list))
+            .expect("Failed to read list of bam paths");
+        let results = micrite::batch::screen_bams(
+            &bams,
+            &config.outdir,
+            screen_config.batch.jobs,
+            screen_config.batch.layout,
+            &config,
+            &screen_config.filters,
+            &screen_config.extraction,
+        );
+        let failed = micrite::batch::report_batch(&results);
+        std::process::exit(if failed == 0 { 0 } else { 1 });
+    }
+
+    // Screen one bam
+    let bam = match &args[1..] {
+        [bam] if !bam.starts_with("--") => bam,
+        _ => {
+            eprintln!(
+                "usage: micrite [--config <config json>] [--quiet] [--resume] [--no-multiqc] <bam>"
+            );
+            std::process::exit(1);
+        }
+    };
+    if let Err(err) = micrite::pipeline::screen(
+        std::path::Path::new(bam),
+        &config,
+        &screen_config.filters,
+        &screen_config.extraction,
+    ) {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::kraken::read_kreport;
+
+/// Kraken clade counts of every taxon seen across a cohort, one column per sample
+pub struct CohortMatrix {
+    /// Sample prefixes (kreport file stems), in column order
+    pub samples: Vec<String>,
+    /// Taxa classified in at least one sample, sorted by total reads across samples (most first)
+    pub taxa: Vec<CohortTaxon>,
+}
+
+/// One row of a [CohortMatrix]
+pub struct CohortTaxon {
+    pub taxid: u64,
+    pub name: String,
+    pub rank: String,
+    /// `clade_nreads_classified` in each sample (0 where the taxon wasn't reported)
+    pub nreads: Vec<u64>,
+    /// `clade_percent_classified` in each sample (0 where the taxon wasn't reported)
+    pub percent: Vec<f64>,
+}
+
+/// Kraken reports (`*.kreport`) in `dir` or any directory below it (e.g. the per-sample directories of a batch), sorted by path
+pub fn find_kreports(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut kreports = vec![];
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if path.extension().is_some_and(|ext| ext == "kreport") {
+                kreports.push(path);
+            }
+        }
+    }
+    kreports.sort();
+    Ok(kreports)
+}
+
+/// Combine per-sample kraken reports into a taxon-by-sample matrix. Unclassified reads (taxid 0) are left out.
+///
+/// Fails if a report can't be read or two reports share a sample prefix
+pub fn merge_kreports(kreports: &[PathBuf]) -> Result<CohortMatrix, Box<dyn std::error::Error>> {
+    let mut samples: Vec<String> = vec![];
+    let mut taxa: Vec<CohortTaxon> = vec![];
+    let mut rows: HashMap<u64, usize> = HashMap::new();
+    for (column, kreport) in kreports.iter().enumerate() {
+        let sample = kreport
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        if samples.contains(&sample) {
+            return Err(format!(
+                "More than one kraken report is named [{}]; sample columns would be ambiguous",
+                sample
+            )
+            .into());
+        }
+        samples.push(sample);
+
+        let records = read_kreport(kreport).map_err(|err| {
+            format!(
+                "Failed to read kraken report [{}]: {}",
+                kreport.display(),
+                err
+            )
+        })?;
+        for record in records.into_iter().filter(|r| r.taxid != 0) {
+            let row = *rows.entry(record.taxid).or_insert_with(|| {
+                taxa.push(CohortTaxon {
+                    taxid: record.taxid,
+                    name: record.name.clone(),
+                    rank: record.rank.clone(),
+                    nreads: vec![],
+                    percent: vec![],
+                });
+                taxa.len() - 1
+            });
+            let taxon = &mut taxa[row];
+            taxon.nreads.resize(column, 0);
+            taxon.percent.resize(column, 0.0);
+            taxon.nreads.push(record.clade_nreads_classified);
+            taxon.percent.push(record.clade_percent_classified);
+        }
+    }
+    for taxon in &mut taxa {
+        taxon.nreads.resize(samples.len(), 0);
+        taxon.percent.resize(samples.len(), 0.0);
+    }
+    taxa.sort_by_key(|taxon| {
+        (
+            std::cmp::Reverse(taxon.nreads.iter().sum::<u64>()),
+            taxon.taxid,
+        )
+    });
+    Ok(CohortMatrix { samples, taxa })
+}
+
+/// Write one value per taxon and sample as a TSV with `taxid`, `name` and `rank` leading columns
+fn write_matrix<T: ToString>(
+    path: &Path,
+    matrix: &CohortMatrix,
+    values: impl Fn(&CohortTaxon) -> &[T],
+) -> csv::Result<()> {
+    let mut writer = csv::WriterBuilder::new().delimiter(b'\t').from_path(path)?;
+    let mut header = vec!["taxid".to_string(), "name".to_string(), "rank".to_string()];
+    header.extend(matrix.samples.iter().cloned());
+    writer.write_record(&header)?;
+    for taxon in &matrix.taxa {
+        let mut row = vec![
+            taxon.taxid.to_string(),
+            taxon.name.clone(),
+            taxon.rank.clone(),
+        ];
+        row.extend(values(taxon).iter().map(T::to_string));
+        writer.write_record(&row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Merge every kraken report under `dir` into `<outdir>/cohort.counts.tsv` (clade read counts)
+/// and `<outdir>/cohort.percent.tsv` (clade percentages)
+pub fn run_merge(dir: &Path, outdir: &str) -> CohortMatrix {
+    std::fs::create_dir_all(outdir).expect("Failed to create output directory");
+    let outfile_counts = format!("{}/cohort.counts.tsv", outdir);
+    let outfile_percent = format!("{}/cohort.percent.tsv", outdir);
+
+    let kreports = find_kreports(dir).unwrap_or_else(|err| {
+        panic!(
+            "Failed to search [{}] for kraken reports: {}",
+            dir.display(),
+            err
+        )
+    });
+    if kreports.is_empty() {
+        panic!("No kraken reports (.kreport) found in [{}]", dir.display());
+    }
+
+    eprintln!("\nMerging [{}] kraken reports", kreports.len());
+    let matrix = merge_kreports(&kreports).unwrap_or_else(|err| panic!("{}", err));
+    write_matrix(Path::new(&outfile_counts), &matrix, |taxon| &taxon.nreads)
+        .expect("Failed to write cohort count matrix");
+    write_matrix(Path::new(&outfile_percent), &matrix, |taxon| &taxon.percent)
+        .expect("Failed to write cohort percent matrix");
+    eprintln!(
+        "\t[{}] taxa across [{}] samples",
+        matrix.taxa.len(),
+        matrix.samples.len()
+    );
+    eprintln!("\tCohort read counts saved to: {}", outfile_counts);
+    eprintln!("\tCohort percentages saved to: {}", outfile_percent);
+    matrix
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn merges_kreports_into_matrix() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("sampleA.kreport"),
+            " 50.00\t10\t10\tU\t0\tunclassified\n 50.00\t10\t0\tR\t1\troot\n 50.00\t10\t10\tS\t10376\t  Human gammaherpesvirus 4\n",
+        )
+        .unwrap();
+        std::fs::create_dir(dir.path().join("sampleB")).unwrap();
+        std::fs::write(
+            dir.path().join("sampleB/sampleB.kreport"),
+            "100.00\t30\t0\tR\t1\troot\n 90.00\t27\t27\tS\t333760\t  Human papillomavirus 16\n 10.00\t3\t3\tS\t10376\t  Human gammaherpesvirus 4\n",
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "not a report").unwrap();
+
+        let kreports = crate::merge::find_kreports(dir.path()).unwrap();
+        assert_eq!(kreports.len(), 2);
+        let outdir = dir.path().join("cohort");
+        let matrix = crate::merge::run_merge(dir.path(), outdir.to_str().unwrap());
+        assert_eq!(matrix.samples, vec!["sampleA", "sampleB"]);
+
+        let counts = std::fs::read_to_string(outdir.join("cohort.counts.tsv")).unwrap();
+        assert_eq!(
+            counts,
+            "taxid\tname\trank\tsampleA\tsampleB\n\
+             1\troot\tR\t10\t30\n\
+             333760\tHuman papillomavirus 16\tS\t0\t27\n\
+             10376\tHuman gammaherpesvirus 4\tS\t10\t3\n"
+        );
+        let percent = std::fs::read_to_string(outdir.join("cohort.percent.tsv")).unwrap();
+        assert!(percent.contains("333760\tHuman papillomavirus 16\tS\t0\t90\n"));
+
+        // Reports sharing a sample prefix can't be told apart
+        std::fs::write(
+            dir.path().join("sampleB.kreport"),
+            "100.00\t1\t1\tU\t0\tunclassified\n",
+        )
+        .unwrap();
+        let kreports = crate::merge::find_kreports(dir.path()).unwrap();
+        assert!(crate::merge::merge_kreports(&kreports).is_err());
+    }
+}
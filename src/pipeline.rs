@@ -0,0 +1,509 @@
+use std::path::Path;
+
+use crate::bam::{
+    add_classified_taxids, bam2unmappedreads, reads2goodreads, Context, ExtractionConfig,
+    ReadFilterConfig, Stage,
+};
+use crate::kraken::{read_bracken, read_kreport, KrakenConfig, KrakenOutput};
+use crate::state::{input_hash, ClassifyResult, CompletedStage, PipelineState};
+use crate::summary::{RunStatus, RunSummary};
+
+/// Screen one bam for microbes: extract unmapped (and microbial-contig) reads, classify them with kraken2
+/// (or KrakenUniq) and summarise the hits. For embedding micrite in a larger pipeline;
+/// the CLI is a thin wrapper around it.
+///
+/// Results (the bam summary, kraken reports, `<prefix>.micrite.json` and MultiQC custom content) are written to `config_kraken.outdir`,
+/// intermediates to [ExtractionConfig::intermediate_dir]. The returned [RunSummary] is the one written to JSON.
+/// Stops early with [RunStatus::NoReads] or [RunStatus::Extracted] (see [ExtractionConfig::stop_after]).
+/// Completed stages are recorded in `<prefix>.micrite.state` for [ExtractionConfig::resume].
+/// Invalid settings (see [crate::config::ScreenConfig::validate]) fail before anything is read or written
+pub fn screen(
+    bam: &Path,
+    config_kraken: &KrakenConfig,
+    config_filters: &ReadFilterConfig,
+    config_extraction: &ExtractionConfig,
+) -> Result<RunSummary, Box<dyn std::error::Error>> {
+    run(
+        Input::Bam(bam),
+        config_kraken,
+        config_filters,
+        config_extraction,
+    )
+}
+
+/// Screen a FASTA/FASTQ of reads (optionally gzipped) that didn't come from a bam, e.g. host-depleted reads:
+/// quality filter them with [reads2goodreads], then classify and summarise them as [screen] does.
+///
+/// The prefix defaults to the file name without its reads extension, and the read counts go to
+/// `<prefix>.reads_summary.txt` in place of the bam summary
+pub fn screen_reads(
+    reads: &Path,
+    config_kraken: &KrakenConfig,
+    config_filters: &ReadFilterConfig,
+    config_extraction: &ExtractionConfig,
+) -> Result<RunSummary, Box<dyn std::error::Error>> {
+    run(
+        Input::Reads(reads),
+        config_kraken,
+        config_filters,
+        config_extraction,
+    )
+}
+
+/// What a run screens
+#[derive(Clone, Copy)]
+enum Input<'a> {
+    /// An aligned bam (or cram), whose unmapped and microbial reads are extracted
+    Bam(&'a Path),
+    /// Reads that only need quality filtering
+    Reads(&'a Path),
+}
+
+fn run(
+    source: Input,
+    config_kraken: &KrakenConfig,
+    config_filters: &ReadFilterConfig,
+    config_extraction: &ExtractionConfig,
+) -> Result<RunSummary, Box<dyn std::error::Error>> {
+    config_kraken.validate()?;
+    config_filters.validate()?;
+    config_extraction.validate()?;
+
+    //Filepaths
+    let (input_path, kind) = match source {
+        Input::Bam(path) => (path, "BAM"),
+        Input::Reads(path) => (path, "reads"),
+    };
+    let input = input_path
+        .to_str()
+        .context("Failed to convert input path to string")?;
+    let outdir = config_kraken.outdir.as_str();
+    if !input_path.exists() {
+        return Err(format!("Could not find {} file [{}]", kind, input).into());
+    }
+    let prefix = match (&config_extraction.sample_id, source) {
+        (Some(sample_id), _) => sample_id.as_str(),
+        (None, Input::Bam(_)) => input_path
+            .file_stem()
+            .context("failed to extract file stem")?
+            .to_str()
+            .context("Failed to convert bam file stem into prefix")?,
+        (None, Input::Reads(_)) => {
+            crate::fastx::reads_stem(input_path).context("Failed to take prefix of reads file")?
+        }
+    };
+
+    // Intermediates go to the tmpdir, final results to outdir
+    let tmpdir = config_extraction.intermediate_dir();
+    let tmpdir = tmpdir
+        .to_str()
+        .context("Failed to convert tmpdir to string")?;
+    let reads_extension = if config_extraction.emit_fastq {
+        "fastq"
+    } else {
+        "fasta"
+    };
+    let compression = if config_extraction.compress_intermediates {
+        ".gz"
+    } else {
+        ""
+    };
+    let unmapped_fasta = format!("{tmpdir}/{prefix}.{reads_extension}{compression}");
+    let input_summary = match source {
+        Input::Bam(_) => format!("{outdir}/{prefix}.bam_summary.txt"),
+        Input::Reads(_) => format!("{outdir}/{prefix}.reads_summary.txt"),
+    };
+    let state_path = format!("{outdir}/{prefix}.micrite.state");
+    // Create working directories
+    std::fs::create_dir_all(outdir).context("Failed to create output directory")?;
+    std::fs::create_dir_all(tmpdir).context("Failed to create intermediate directory")?;
+
+    let mut run_summary = crate::summary::RunSummary::new(input);
+    let mut state = if config_extraction.resume {
+        PipelineState::read(Path::new(&state_path))
+    } else {
+        PipelineState::default()
+    };
+
+    // Collect unmapped reads into FASTQAformat
+    // Progress bars, resuming, where the run stops and MultiQC output don't change what extraction produces
+    let extract_settings = format!(
+        "{:?}\n{:?}",
+        config_filters,
+        ExtractionConfig {
+            quiet: false,
+            resume: false,
+            stop_after: None,
+            no_multiqc: false,
+            ..config_extraction.clone()
+        }
+    );
+    let extract_hash = input_hash(&[input_path], &extract_settings)
+        .map_err(|err| format!("Failed to read [{}]: {}", input, err))?;
+    let resumed_extraction = state
+        .extract
+        .as_ref()
+        .and_then(|stage| stage.reusable(&extract_hash))
+        .cloned();
+    let extraction = match resumed_extraction {
+        Some(extraction) => {
+            eprintln!("resuming: extract already complete ({})", unmapped_fasta);
+            extraction
+        }
+        None => {
+            let extract = match source {
+                Input::Bam(_) => bam2unmappedreads,
+                Input::Reads(_) => reads2goodreads,
+            };
+            let extraction = extract(
+                input,
+                unmapped_fasta.as_str(),
+                input_summary.as_str(),
+                config_filters,
+                config_extraction,
+            )
+            .map_err(|err| format!("Failed to extract reads from [{}]: {}", input, err))?;
+            eprintln!("Created {reads_extension} file of unmapped reads at {unmapped_fasta}");
+            // An empty reads file is a valid result when nothing passed, so only require it when reads were written
+            let mut outputs = vec![input_summary.clone().into()];
+            if extraction.reads_written > 0 {
+                outputs.push(unmapped_fasta.clone().into());
+            }
+            state.extract = Some(CompletedStage {
+                input_hash: extract_hash,
+                outputs,
+                result: extraction.clone(),
+            });
+            state.classify = None;
+            state.write(Path::new(&state_path))?;
+            extraction
+        }
+    };
+    run_summary.extraction = Some(extraction);
+    let reads_written = run_summary
+        .extraction
+        .as_ref()
+        .map_or(0, |extraction| extraction.reads_written);
+    if reads_written == 0 {
+        eprintln!("Warning: no reads passed extraction, so there is nothing to classify");
+        run_summary.status = RunStatus::NoReads;
+        write_summaries(&run_summary, outdir, prefix, config_extraction)?;
+        return Ok(run_summary);
+    }
+    if config_extraction.stop_after == Some(Stage::Extract) {
+        eprintln!("Stopping after read extraction (stop_after = Extract)");
+        run_summary.status = RunStatus::Extracted;
+        write_summaries(&run_summary, outdir, prefix, config_extraction)?;
+        return Ok(run_summary);
+    }
+
+    // Run Kraken
+    let db = match &config_kraken.krakenuniq {
+        Some(krakenuniq) => krakenuniq.db.display().to_string(),
+        None => config_kraken.krakendb.display().to_string(),
+    };
+    let confidence = config_kraken.confidence.clone();
+    let taxonomy = config_kraken
+        .taxonomy
+        .as_ref()
+        .map(crate::taxonomy::read_taxonomy_cached)
+        .transpose()?;
+    let classify_hash = input_hash(
+        &[Path::new(&unmapped_fasta)],
+        &format!("{:?}", config_kraken),
+    )
+    .map_err(|err| format!("Failed to read [{}]: {}", unmapped_fasta, err))?;
+    let kreport_path = format!("{outdir}/{prefix}.kreport");
+    let resumed_classification = state
+        .classify
+        .as_ref()
+        .and_then(|stage| stage.reusable(&classify_hash));
+    let kraken_output = match resumed_classification {
+        Some(classified) => {
+            eprintln!("resuming: classify already complete ({})", kreport_path);
+            KrakenOutput {
+                classifier: if config_kraken.krakenuniq.is_some() {
+                    "krakenuniq"
+                } else {
+                    "kraken2"
+                },
+                kout: classified.kout.clone(),
+                kreport: read_kreport(Path::new(&kreport_path))
+                    .map_err(|err| format!("Failed to read [{}]: {}", kreport_path, err))?,
+                sequences_processed: classified.sequences_processed,
+                sequences_classified: classified.sequences_classified,
+                sequences_unclassified: classified.sequences_unclassified,
+                bracken: config_kraken
+                    .bracken
+                    .as_ref()
+                    .map(|_| read_bracken(&Path::new(&kreport_path).with_extension("bracken")))
+                    .transpose()?,
+            }
+        }
+        None => {
+            let kraken_output = config_kraken
+                .classifier()
+                .run(unmapped_fasta.clone().into());
+            let mut outputs = vec![kreport_path.clone().into()];
+            outputs.extend(kraken_output.kout.clone());
+            if kraken_output.bracken.is_some() {
+                outputs.push(Path::new(&kreport_path).with_extension("bracken"));
+            }
+            state.classify = Some(CompletedStage {
+                input_hash: classify_hash,
+                outputs,
+                result: ClassifyResult {
+                    kout: kraken_output.kout.clone(),
+                    sequences_processed: kraken_output.sequences_processed,
+                    sequences_classified: kraken_output.sequences_classified,
+                    sequences_unclassified: kraken_output.sequences_unclassified,
+                },
+            });
+            state.write(Path::new(&state_path))?;
+            kraken_output
+        }
+    };
+    if let Some(processed) = kraken_output.sequences_processed {
+        if processed != reads_written {
+            eprintln!(
+                "Warning: kraken2 processed [{}] sequences but [{}] reads were extracted for it",
+                processed, reads_written
+            );
+        }
+    }
+    let kout = kraken_output.kout.clone();
+    let mut kraken_summary =
+        crate::summary::KrakenSummary::new(db, confidence, kraken_output, taxonomy.as_deref());
+    if let Some(control) = &config_kraken.positive_control {
+        let control = crate::summary::PositiveControl::new(control, &kraken_summary.taxa);
+        eprintln!(
+            "Positive control [{}] {}: [{}] reads (need [{}])",
+            control.taxid,
+            if control.detected {
+                "detected"
+            } else {
+                "NOT DETECTED"
+            },
+            control.nreads,
+            control.min_reads
+        );
+        kraken_summary.positive_control = Some(control);
+    }
+    if let Some(debug_reads) = &config_extraction.debug_reads {
+        match &kout {
+            Some(kout) => {
+                let nmatched = add_classified_taxids(debug_reads, kout)?;
+                eprintln!(
+                    "Added kraken classifications of [{}] reads to {}",
+                    nmatched,
+                    debug_reads.display()
+                );
+            }
+            None => eprintln!(
+                "Keep kraken's per-read output (keep_kout) to add classified taxids to {}",
+                debug_reads.display()
+            ),
+        }
+    }
+    run_summary.kraken = Some(kraken_summary);
+    run_summary.status = RunStatus::Classified;
+    write_summaries(&run_summary, outdir, prefix, config_extraction)?;
+    Ok(run_summary)
+}
+
+/// Write the run summary JSON and, unless [ExtractionConfig::no_multiqc], the MultiQC custom content
+fn write_summaries(
+    run_summary: &RunSummary,
+    outdir: &str,
+    prefix: &str,
+    config_extraction: &ExtractionConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    run_summary.write(Path::new(&format!("{outdir}/{prefix}.micrite.json")))?;
+    if !config_extraction.no_multiqc {
+        run_summary.write_multiqc(prefix, Path::new(outdir))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn screen_returns_run_summary() {
+        use crate::test_bam::{sequence, TestBam, TestRead};
+
+        let dir = tempfile::tempdir().unwrap();
+        let bam = TestBam::new()
+            .contig("chr1", 10_000)
+            .read(TestRead::mapped("mapped1", "chr1", 100, &sequence(100, 1)))
+            .read(TestRead::unmapped("good1", &sequence(100, 2)))
+            .write(dir.path(), "sample");
+        let outdir = dir.path().join("out");
+        // kraken2 isn't needed: the run stops before classification
+        let config_kraken = crate::kraken::KrakenConfig {
+            outdir: outdir.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let summary = crate::pipeline::screen(
+            &bam,
+            &config_kraken,
+            &crate::bam::ReadFilterConfig::default(),
+            &crate::bam::ExtractionConfig {
+                tmpdir: Some(dir.path().join("tmp")),
+                stop_after: Some(crate::bam::Stage::Extract),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(summary.status, crate::summary::RunStatus::Extracted);
+        assert_eq!(summary.extraction.unwrap().reads_written, 1);
+        assert!(summary.kraken.is_none());
+        assert!(outdir.join("sample.micrite.json").exists());
+
+        let missing = crate::pipeline::screen(
+            &dir.path().join("missing.bam"),
+            &config_kraken,
+            &crate::bam::ReadFilterConfig::default(),
+            &crate::bam::ExtractionConfig::default(),
+        );
+        assert!(missing
+            .unwrap_err()
+            .to_string()
+            .contains("Could not find BAM"));
+    }
+
+    #[test]
+    fn screen_reads_without_bam() {
+        use crate::test_bam::sequence;
+
+        let dir = tempfile::tempdir().unwrap();
+        let reads = dir.path().join("depleted.fastq.gz");
+        let good = String::from_utf8(sequence(100, 1)).unwrap();
+        let mut fastq = format!("@good1 extra\n{}\n+\n{}\n", good, "I".repeat(100));
+        fastq += &format!("@lowqual\n{}\n+\n{}\n", good, "#".repeat(100));
+        fastq += &format!("@short\n{}\n+\n{}\n", &good[..30], "I".repeat(30));
+        // FASTA records have no qualities to judge
+        fastq += &format!(">good2\n{}\n{}\n", &good[..50], &good[50..]);
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, fastq.as_bytes()).unwrap();
+        std::fs::write(&reads, encoder.finish().unwrap()).unwrap();
+
+        let outdir = dir.path().join("out");
+        let config_kraken = crate::kraken::KrakenConfig {
+            outdir: outdir.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let summary = crate::pipeline::screen_reads(
+            &reads,
+            &config_kraken,
+            &crate::bam::ReadFilterConfig::default(),
+            &crate::bam::ExtractionConfig {
+                tmpdir: Some(dir.path().join("tmp")),
+                stop_after: Some(crate::bam::Stage::Extract),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let extraction = summary.extraction.unwrap();
+        assert_eq!(extraction.total_reads, 4);
+        assert_eq!(extraction.reads_written, 2);
+        assert_eq!(
+            extraction
+                .unmapped_quality_failures
+                .get(crate::bam::ReadQc::LowPhred),
+            1
+        );
+        assert_eq!(
+            extraction
+                .unmapped_quality_failures
+                .get(crate::bam::ReadQc::TooShort),
+            1
+        );
+
+        let fasta = std::fs::read_to_string(dir.path().join("tmp/depleted.fasta")).unwrap();
+        assert_eq!(fasta, format!(">good1\n{}\n>good2\n{}\n", good, good));
+        let reads_summary =
+            std::fs::read_to_string(outdir.join("depleted.reads_summary.txt")).unwrap();
+        assert!(reads_summary.contains("total reads\t4\n"));
+        assert!(reads_summary.contains("good quality sequences\t2\n"));
+        assert!(outdir.join("depleted.micrite.json").exists());
+    }
+
+    #[test]
+    fn resume_skips_completed_stages() {
+        use crate::test_bam::{sequence, TestBam, TestRead};
+
+        let dir = tempfile::tempdir().unwrap();
+        let bam = TestBam::new()
+            .contig("chr1", 10_000)
+            .read(TestRead::unmapped("good1", &sequence(100, 1)))
+            .write(dir.path(), "sample");
+        let outdir = dir.path().join("out");
+        let config_kraken = crate::kraken::KrakenConfig {
+            outdir: outdir.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let config_extraction = crate::bam::ExtractionConfig {
+            tmpdir: Some(dir.path().join("tmp")),
+            stop_after: Some(crate::bam::Stage::Extract),
+            resume: true,
+            ..Default::default()
+        };
+        let screen = |config_extraction: &crate::bam::ExtractionConfig| {
+            crate::pipeline::screen(
+                &bam,
+                &config_kraken,
+                &crate::bam::ReadFilterConfig::default(),
+                config_extraction,
+            )
+            .unwrap()
+        };
+        screen(&config_extraction);
+        let bam_summary = outdir.join("sample.bam_summary.txt");
+        std::fs::write(&bam_summary, "untouched").unwrap();
+
+        // Same inputs: extraction is skipped and its summary comes from the state file
+        let summary = screen(&config_extraction);
+        assert_eq!(summary.extraction.unwrap().reads_written, 1);
+        assert_eq!(std::fs::read_to_string(&bam_summary).unwrap(), "untouched");
+
+        // Changed settings invalidate it
+        let stricter = crate::bam::ExtractionConfig {
+            dedup_sequences: true,
+            ..config_extraction.clone()
+        };
+        screen(&stricter);
+        assert_ne!(std::fs::read_to_string(&bam_summary).unwrap(), "untouched");
+
+        // A kraken run recorded against the current reads file is reused without kraken2
+        std::fs::write(
+            outdir.join("sample.kreport"),
+            "  0.00\t0\t0\tU\t0\tunclassified\n100.00\t1\t0\tR\t1\troot\n100.00\t1\t1\tS\t10376\t  Human gammaherpesvirus 4\n",
+        )
+        .unwrap();
+        let state_path = outdir.join("sample.micrite.state");
+        let mut state = crate::state::PipelineState::read(&state_path);
+        state.classify = Some(crate::state::CompletedStage {
+            input_hash: crate::state::input_hash(
+                &[&dir.path().join("tmp/sample.fasta")],
+                &format!("{:?}", config_kraken),
+            )
+            .unwrap(),
+            outputs: vec![outdir.join("sample.kreport")],
+            result: crate::state::ClassifyResult {
+                kout: None,
+                sequences_processed: Some(1),
+                sequences_classified: Some(1),
+                sequences_unclassified: Some(0),
+            },
+        });
+        state.write(&state_path).unwrap();
+        let summary = screen(&crate::bam::ExtractionConfig {
+            stop_after: None,
+            ..stricter
+        });
+        assert_eq!(summary.status, crate::summary::RunStatus::Classified);
+        let kraken = summary.kraken.unwrap();
+        assert_eq!(kraken.sequences_processed, Some(1));
+        assert_eq!(kraken.species[0].taxid, 10376);
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::path::Path;
+
+/// Reference intervals (e.g. from a BED file), sorted and merged per contig so overlaps are a binary search
+#[derive(Debug, Default)]
+pub struct RegionSet {
+    regions: HashMap<String, Vec<(u64, u64)>>,
+}
+
+impl RegionSet {
+    /// Build from (contig, start, end) intervals, 0-based and half-open
+    pub fn from_intervals<'a>(intervals: impl IntoIterator<Item = (&'a str, u64, u64)>) -> Self {
+        let mut regions: HashMap<String, Vec<(u64, u64)>> = HashMap::new();
+        for (contig, start, end) in intervals {
+            regions
+                .entry(contig.to_string())
+                .or_default()
+                .push((start, end));
+        }
+        for intervals in regions.values_mut() {
+            intervals.sort_unstable();
+            let mut merged: Vec<(u64, u64)> = Vec::with_capacity(intervals.len());
+            for &(start, end) in intervals.iter() {
+                match merged.last_mut() {
+                    Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                    _ => merged.push((start, end)),
+                }
+            }
+            *intervals = merged;
+        }
+        RegionSet { regions }
+    }
+
+    /// Read the first three columns (contig, 0-based start, end) of a BED file.
+    /// `track`/`browser` lines, `#` comments and blank lines are skipped
+    pub fn read_bed(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let file = std::fs::File::open(path)
+            .map_err(|err| format!("Failed to open [{}]: {}", path.display(), err))?;
+        let mut intervals: Vec<(String, u64, u64)> = vec![];
+        for (i, line) in std::io::BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty()
+                || line.starts_with('#')
+                || line.starts_with("track")
+                || line.starts_with("browser")
+            {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            let parse = |field: Option<&&str>| field.and_then(|f| f.trim().parse::<u64>().ok());
+            match (parse(fields.get(1)), parse(fields.get(2))) {
+                (Some(start), Some(end)) if start <= end => {
+                    intervals.push((fields[0].to_string(), start, end))
+                }
+                _ => {
+                    return Err(format!(
+                        "Line {} of [{}] is not a BED interval (contig, start, end): {}",
+                        i + 1,
+                        path.display(),
+                        line
+                    )
+                    .into())
+                }
+            }
+        }
+        Ok(Self::from_intervals(intervals.iter().map(
+            |(contig, start, end)| (contig.as_str(), *start, *end),
+        )))
+    }
+
+    /// Merged intervals on `contig` (empty if it has none)
+    pub fn intervals(&self, contig: &str) -> &[(u64, u64)] {
+        self.regions.get(contig).map_or(&[], Vec::as_slice)
+    }
+
+    /// Contigs with at least one interval
+    pub fn contigs(&self) -> impl Iterator<Item = &str> {
+        self.regions.keys().map(String::as_str)
+    }
+
+    /// Number of (merged) intervals across all contigs
+    pub fn len(&self) -> usize {
+        self.regions.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Whether `[start, end)` overlaps any of the sorted, merged `intervals` (see [RegionSet::intervals])
+pub fn overlaps_any(intervals: &[(u64, u64)], start: u64, end: u64) -> bool {
+    let first_ending_after = intervals.partition_point(|&(_, interval_end)| interval_end <= start);
+    intervals
+        .get(first_ending_after)
+        .is_some_and(|&(interval_start, _)| interval_start < end)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn bed_intervals_are_merged() {
+        let dir = tempfile::tempdir().unwrap();
+        let bed = dir.path().join("regions.bed");
+        std::fs::write(
+            &bed,
+            "track name=herv\n# retroviral loci\nchr1\t500\t600\tHERV-K\nchr1\t100\t200\nchr1\t150\t250\n\nchr2\t0\t10\n",
+        )
+        .unwrap();
+        let regions = crate::regions::RegionSet::read_bed(&bed).unwrap();
+        assert_eq!(regions.intervals("chr1"), &[(100, 250), (500, 600)]);
+        assert_eq!(regions.intervals("chr2"), &[(0, 10)]);
+        assert!(regions.intervals("chrX").is_empty());
+        assert_eq!(regions.len(), 3);
+
+        std::fs::write(&bed, "chr1\t100\n").unwrap();
+        let err = crate::regions::RegionSet::read_bed(&bed).unwrap_err();
+        assert!(err.to_string().contains("Line 1"));
+    }
+
+    #[test]
+    fn overlap_lookup() {
+        use crate::regions::overlaps_any;
+        let intervals = [(100, 250), (500, 600)];
+        assert!(overlaps_any(&intervals, 240, 300));
+        assert!(overlaps_any(&intervals, 50, 101));
+        assert!(overlaps_any(&intervals, 520, 530));
+        // Half-open: touching an end isn't an overlap
+        assert!(!overlaps_any(&intervals, 250, 500));
+        assert!(!overlaps_any(&intervals, 0, 100));
+        assert!(!overlaps_any(&intervals, 600, 700));
+        assert!(!overlaps_any(&[], 0, 100));
+    }
+}
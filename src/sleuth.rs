@@ -0,0 +1,350 @@
+use rust_htslib::bam::{self, record::Cigar, Read};
+use std::path::{Path, PathBuf};
+
+/// Options for re-aligning extracted reads to the genome of a kraken hit
+pub struct SleuthConfig {
+    /// Reference genome FASTA of the microbe
+    pub reference: PathBuf,
+    pub threads: u8,
+    pub outdir: String,
+    /// Width of the windows used to judge how evenly reads are spread (1kb by default)
+    pub window_size: u64,
+    /// Mark a contig `suspicious` when its [CoverageStats::coverage_evenness] is above this
+    pub max_coverage_cv: Option<f64>,
+}
+
+impl SleuthConfig {
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.window_size == 0 {
+            return Err("window_size must be at least 1".into());
+        }
+        if let Some(max_cv) = self.max_coverage_cv {
+            if max_cv.is_nan() || max_cv < 0.0 {
+                return Err(
+                    format!("max_coverage_cv [{}] must be a non-negative number", max_cv).into(),
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Coverage of one contig of the microbial genome by the re-aligned reads
+#[derive(Debug, PartialEq, serde::Serialize)]
+pub struct CoverageStats {
+    pub contig: String,
+    pub length: u64,
+    /// Primary alignments to the contig
+    pub nreads: u64,
+    /// Fraction of bases covered by at least one read
+    pub breadth: f64,
+    pub mean_depth: f64,
+    /// Windows containing at least one covered base
+    pub windows_covered: u64,
+    pub windows_total: u64,
+    /// Fraction of reads starting in the window with the most read starts.
+    /// Close to 1 means a single pile-up, which is more likely an artifact than an infection
+    pub top_window_read_fraction: f64,
+    /// Coefficient of variation of the mean depth of each window (0 when nothing is covered).
+    /// Reads tiling the genome give a low value; reads stacked at one (often conserved or repetitive) locus a high one
+    pub coverage_evenness: f64,
+    /// `suspicious` if coverage is too uneven (see [SleuthConfig::max_coverage_cv]), otherwise `ok`
+    pub status: String,
+}
+
+/// Per-base depth and per-window read starts of one contig
+pub(crate) struct ContigCoverage {
+    name: String,
+    depth: Vec<u32>,
+    window_reads: Vec<u64>,
+    window_size: u64,
+    nreads: u64,
+}
+
+impl ContigCoverage {
+    pub(crate) fn new(name: &str, length: u64, window_size: u64) -> Self {
+        ContigCoverage {
+            name: name.to_string(),
+            depth: vec![0; length as usize],
+            window_reads: vec![0; length.div_ceil(window_size) as usize],
+            window_size,
+            nreads: 0,
+        }
+    }
+
+    /// Add a read whose aligned bases cover the 0-based, half-open reference intervals in `blocks`
+    pub(crate) fn add_read(&mut self, blocks: &[(u64, u64)]) {
+        let Some(&(start, _)) = blocks.first() else {
+            return;
+        };
+        self.nreads += 1;
+        if let Some(window) = self
+            .window_reads
+            .get_mut((start / self.window_size) as usize)
+        {
+            *window += 1;
+        }
+        for &(start, end) in blocks {
+            let end = (end as usize).min(self.depth.len());
+            for depth in &mut self.depth[(start as usize).min(end)..end] {
+                *depth += 1;
+            }
+        }
+    }
+
+    pub(crate) fn stats(&self) -> CoverageStats {
+        let length = self.depth.len() as u64;
+        let covered = self.depth.iter().filter(|&&d| d > 0).count() as u64;
+        let total_depth: u64 = self.depth.iter().map(|&d| d as u64).sum();
+        let windows_covered = self
+            .depth
+            .chunks(self.window_size as usize)
+            .filter(|window| window.iter().any(|&d| d > 0))
+            .count() as u64;
+        let top_window = self.window_reads.iter().copied().max().unwrap_or(0);
+        let window_depths: Vec<f64> = self
+            .depth
+            .chunks(self.window_size as usize)
+            .map(|window| window.iter().map(|&d| d as f64).sum::<f64>() / window.len() as f64)
+            .collect();
+        let fraction = |n: u64, total: u64| {
+            if total > 0 {
+                n as f64 / total as f64
+            } else {
+                0.0
+            }
+        };
+
+        CoverageStats {
+            contig: self.name.clone(),
+            length,
+            nreads: self.nreads,
+            breadth: fraction(covered, length),
+            mean_depth: fraction(total_depth, length),
+            windows_covered,
+            windows_total: self.window_reads.len() as u64,
+            top_window_read_fraction: fraction(top_window, self.nreads),
+            coverage_evenness: coefficient_of_variation(&window_depths),
+            status: "ok".to_string(),
+        }
+    }
+}
+
+/// Standard deviation over mean (0 for empty or all-zero input)
+pub fn coefficient_of_variation(values: &[f64]) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    let n = values.len() as f64;
+    let mean = values.iter().sum::<f64>() / n;
+    if mean == 0.0 {
+        return 0.0;
+    }
+    let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n;
+    variance.sqrt() / mean
+}
+
+/// Reference intervals (0-based, half-open) covered by the aligned bases of a record
+pub(crate) fn aligned_blocks(record: &bam::Record) -> Vec<(u64, u64)> {
+    let mut blocks = vec![];
+    let mut pos = record.pos() as u64;
+    for op in record.cigar().iter() {
+        match op {
+            Cigar::Match(len) | Cigar::Equal(len) | Cigar::Diff(len) => {
+                blocks.push((pos, pos + *len as u64));
+                pos += *len as u64;
+            }
+            Cigar::Del(len) | Cigar::RefSkip(len) => pos += *len as u64,
+            _ => {}
+        }
+    }
+    blocks
+}
+
+/// Align `reads` to `reference` with minimap2 (short-read preset, primary alignments only), writing SAM to `sam`
+pub(crate) fn align_reads(reference: &Path, reads: &Path, threads: u8, sam: &Path) {
+    let minimap2 = which::which("minimap2")
+        .expect("minimap2 not found. Please ensure it is installed and added to your PATH.");
+    let output = std::process::Command::new(minimap2)
+        .args(["-a", "-x", "sr", "--secondary=no"])
+        .args(["-t", &threads.to_string()])
+        .arg("-o")
+        .arg(sam)
+        .arg(reference)
+        .arg(reads)
+        .output()
+        .expect("Failed to run minimap2 alignment");
+    if !output.status.success() {
+        let stderr_str = String::from_utf8_lossy(&output.stderr);
+        panic!(
+            "\tminimap2 alignment Failed. Stderr\n========\n{}\n========",
+            stderr_str
+        )
+    }
+}
+
+/// Re-align `reads` (e.g. the kraken-classified reads of one taxid) to the microbe's genome with minimap2
+/// and report how the reads cover it. Writes `<outdir>/<reads prefix>.sleuth.tsv`.
+///
+/// Reads spread across the whole genome are strong evidence of a real infection;
+/// reads stacked in a single window (see [CoverageStats::top_window_read_fraction]) are suspicious
+pub fn run_sleuth(reads: &Path, config: &SleuthConfig) -> Vec<CoverageStats> {
+    std::fs::create_dir_all(&config.outdir).expect("Failed to create output directory");
+    let prefix = reads
+        .file_stem()
+        .expect("Failed to extract reads file stem")
+        .to_str()
+        .expect("failed filepath to str conversion");
+    let outfile_sam = format!("{}/{}.sleuth.sam", config.outdir, prefix);
+    let outfile_tsv = format!("{}/{}.sleuth.tsv", config.outdir, prefix);
+
+    eprintln!("\nRunning Sleuth");
+    align_reads(
+        &config.reference,
+        reads,
+        config.threads,
+        Path::new(&outfile_sam),
+    );
+
+    let mut sam = bam::Reader::from_path(&outfile_sam).expect("Failed to read minimap2 alignments");
+    let header = sam.header().clone();
+    let mut contigs: Vec<ContigCoverage> = (0..header.target_count())
+        .map(|tid| {
+            ContigCoverage::new(
+                std::str::from_utf8(header.tid2name(tid)).unwrap(),
+                header.target_len(tid).unwrap_or(0),
+                config.window_size,
+            )
+        })
+        .collect();
+    for r in sam.records() {
+        let record = r.unwrap_or_else(|err| panic!("Failed to read sam record: {:?}", err));
+        if record.is_unmapped() | record.is_secondary() | record.is_supplementary() {
+            continue;
+        }
+        contigs[record.tid() as usize].add_read(&aligned_blocks(&record));
+    }
+
+    let stats: Vec<CoverageStats> = contigs
+        .iter()
+        .map(|contig| {
+            let mut stats = contig.stats();
+            if config
+                .max_coverage_cv
+                .is_some_and(|max_cv| stats.coverage_evenness > max_cv)
+            {
+                stats.status = "suspicious".to_string();
+            }
+            stats
+        })
+        .collect();
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .from_path(&outfile_tsv)
+        .expect("Failed to create sleuth tsv");
+    for contig in &stats {
+        eprintln!(
+            "\t{}: [{}] reads, breadth [{:.3}], mean depth [{:.2}], [{}/{}] windows covered, coverage CV [{:.2}] ({})",
+            contig.contig,
+            contig.nreads,
+            contig.breadth,
+            contig.mean_depth,
+            contig.windows_covered,
+            contig.windows_total,
+            contig.coverage_evenness,
+            contig.status
+        );
+        writer
+            .serialize(contig)
+            .expect("Failed to write to sleuth tsv");
+    }
+    writer.flush().expect("Failed to flush sleuth tsv");
+    eprintln!("\tSleuth coverage report saved to: {}", outfile_tsv);
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sleuth::ContigCoverage;
+
+    #[test]
+    fn even_coverage() {
+        let mut coverage = ContigCoverage::new("virus", 4000, 1000);
+        for start in (0..4000).step_by(100) {
+            coverage.add_read(&[(start, start + 100)]);
+        }
+        let stats = coverage.stats();
+        assert_eq!(stats.nreads, 40);
+        assert_eq!(stats.breadth, 1.0);
+        assert_eq!(stats.mean_depth, 1.0);
+        assert_eq!((stats.windows_covered, stats.windows_total), (4, 4));
+        assert_eq!(stats.top_window_read_fraction, 0.25);
+        assert_eq!(stats.coverage_evenness, 0.0);
+    }
+
+    #[test]
+    fn single_pileup() {
+        // A partial last window still counts, and blocks skip deletions
+        let mut coverage = ContigCoverage::new("virus", 3500, 1000);
+        for _ in 0..10 {
+            coverage.add_read(&[(2000, 2050), (2060, 2110)]);
+        }
+        let stats = coverage.stats();
+        assert_eq!(stats.windows_total, 4);
+        assert_eq!(stats.windows_covered, 1);
+        assert!((stats.breadth - 100.0 / 3500.0).abs() < 1e-9);
+        assert!((stats.mean_depth - 1000.0 / 3500.0).abs() < 1e-9);
+        assert_eq!(stats.top_window_read_fraction, 1.0);
+        assert!(stats.coverage_evenness > 1.5);
+    }
+
+    #[test]
+    fn config_validation() {
+        let config = |max_coverage_cv| crate::sleuth::SleuthConfig {
+            reference: "genome.fa".into(),
+            threads: 1,
+            outdir: "outdir".to_string(),
+            window_size: 1000,
+            max_coverage_cv,
+        };
+        assert!(config(None).validate().is_ok());
+        assert!(config(Some(1.5)).validate().is_ok());
+        assert!(config(Some(-0.1)).validate().is_err());
+        assert!(config(Some(f64::NAN)).validate().is_err());
+    }
+
+    #[test]
+    fn coverage_evenness() {
+        use crate::sleuth::coefficient_of_variation;
+        assert_eq!(coefficient_of_variation(&[5.0; 10]), 0.0);
+        assert_eq!(coefficient_of_variation(&[0.0; 10]), 0.0);
+        assert_eq!(coefficient_of_variation(&[]), 0.0);
+
+        // Mild variation stays low, a single spike is high
+        let wobble = [4.0, 5.0, 6.0, 5.0, 4.0, 6.0, 5.0, 5.0];
+        let mut spike = [0.0; 8];
+        spike[3] = 40.0;
+        assert!(coefficient_of_variation(&wobble) < 0.2);
+        assert!((coefficient_of_variation(&spike) - 7f64.sqrt()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn aligned_blocks_follow_cigar() {
+        use rust_htslib::bam::record::{Cigar, CigarString};
+        let mut record = rust_htslib::bam::Record::new();
+        let cigar = CigarString(vec![
+            Cigar::SoftClip(5),
+            Cigar::Match(10),
+            Cigar::Ins(2),
+            Cigar::Match(5),
+            Cigar::Del(3),
+            Cigar::Match(10),
+        ]);
+        record.set(b"read", Some(&cigar), &[b'A'; 32], &[30; 32]);
+        record.set_pos(100);
+        assert_eq!(
+            crate::sleuth::aligned_blocks(&record),
+            vec![(100, 110), (110, 115), (118, 128)]
+        );
+    }
+}
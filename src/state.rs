@@ -0,0 +1,163 @@
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+use crate::bam::ExtractionSummary;
+
+/// Stages of a [crate::pipeline::screen] run that have completed, written to `<outdir>/<prefix>.micrite.state`
+/// so a re-run with [crate::bam::ExtractionConfig::resume] can skip them.
+///
+/// Each stage records a hash of its inputs (see [input_hash]): a changed bam, reads file or config
+/// fails the comparison and the stage (and so everything downstream of it) runs again
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct PipelineState {
+    pub extract: Option<CompletedStage<ExtractionSummary>>,
+    pub classify: Option<CompletedStage<ClassifyResult>>,
+}
+
+/// A stage's input hash, the files it wrote and what it reported
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct CompletedStage<T> {
+    pub input_hash: String,
+    pub outputs: Vec<PathBuf>,
+    pub result: T,
+}
+
+/// What a kraken run reported beyond the files it wrote (see [crate::kraken::KrakenOutput])
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct ClassifyResult {
+    pub kout: Option<PathBuf>,
+    pub sequences_processed: Option<u64>,
+    pub sequences_classified: Option<u64>,
+    pub sequences_unclassified: Option<u64>,
+}
+
+impl<T> CompletedStage<T> {
+    /// The stage's result if it ran on the same inputs and all of its outputs are still there (and not empty)
+    pub fn reusable(&self, input_hash: &str) -> Option<&T> {
+        let outputs_present = self
+            .outputs
+            .iter()
+            .all(|output| std::fs::metadata(output).is_ok_and(|metadata| metadata.len() > 0));
+        (self.input_hash == input_hash && outputs_present).then_some(&self.result)
+    }
+}
+
+impl PipelineState {
+    /// The state left by an earlier run.
+    /// Missing or unreadable state (e.g. from a crash mid-write) means nothing has completed
+    pub fn read(path: &Path) -> Self {
+        let Ok(file) = std::fs::File::open(path) else {
+            return PipelineState::default();
+        };
+        serde_json::from_reader(std::io::BufReader::new(file)).unwrap_or_else(|err| {
+            eprintln!(
+                "Warning: ignoring unreadable pipeline state [{}]: {}",
+                path.display(),
+                err
+            );
+            PipelineState::default()
+        })
+    }
+
+    /// Write via a temporary file and rename, so a crash never leaves half-written state behind
+    pub fn write(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let tmp = path.with_extension("state.tmp");
+        let file = std::fs::File::create(&tmp)
+            .map_err(|err| format!("Failed to create [{}]: {}", tmp.display(), err))?;
+        serde_json::to_writer_pretty(file, self)
+            .map_err(|err| format!("Failed to write pipeline state: {}", err))?;
+        std::fs::rename(&tmp, path)
+            .map_err(|err| format!("Failed to replace [{}]: {}", path.display(), err))?;
+        Ok(())
+    }
+}
+
+/// Hash of a stage's input files and settings.
+///
+/// Files are identified by path, size and modification time rather than their contents, which would mean
+/// reading a whole bam. The hash is only stable for a given build of micrite: after an upgrade stages re-run
+pub fn input_hash(files: &[&Path], settings: &str) -> std::io::Result<String> {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    for file in files {
+        let metadata = std::fs::metadata(file)?;
+        file.hash(&mut hasher);
+        metadata.len().hash(&mut hasher);
+        metadata
+            .modified()?
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .hash(&mut hasher);
+    }
+    settings.hash(&mut hasher);
+    Ok(format!("{:016x}", hasher.finish()))
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn stages_are_reused_only_for_the_same_inputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.bam");
+        let output = dir.path().join("sample.kreport");
+        std::fs::write(&input, "bam").unwrap();
+        std::fs::write(&output, "report").unwrap();
+
+        let hash = crate::state::input_hash(&[&input], "confidence 0.05").unwrap();
+        assert_eq!(
+            hash,
+            crate::state::input_hash(&[&input], "confidence 0.05").unwrap()
+        );
+        assert_ne!(
+            hash,
+            crate::state::input_hash(&[&input], "confidence 0.1").unwrap()
+        );
+
+        let stage = crate::state::CompletedStage {
+            input_hash: hash.clone(),
+            outputs: vec![output.clone()],
+            result: 42,
+        };
+        assert_eq!(stage.reusable(&hash), Some(&42));
+        assert_eq!(stage.reusable("0000000000000000"), None);
+
+        // Outputs that are gone (or empty) can't be reused
+        std::fs::write(&output, "").unwrap();
+        assert_eq!(stage.reusable(&hash), None);
+
+        // A changed input changes the hash
+        std::fs::write(&input, "a different bam").unwrap();
+        assert_ne!(
+            hash,
+            crate::state::input_hash(&[&input], "confidence 0.05").unwrap()
+        );
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sample.micrite.state");
+        assert!(crate::state::PipelineState::read(&path).classify.is_none());
+
+        let state = crate::state::PipelineState {
+            extract: None,
+            classify: Some(crate::state::CompletedStage {
+                input_hash: "abc".to_string(),
+                outputs: vec![dir.path().join("sample.kreport")],
+                result: crate::state::ClassifyResult {
+                    kout: None,
+                    sequences_processed: Some(10),
+                    sequences_classified: Some(8),
+                    sequences_unclassified: Some(2),
+                },
+            }),
+        };
+        state.write(&path).unwrap();
+        let read = crate::state::PipelineState::read(&path);
+        let classify = read.classify.unwrap();
+        assert_eq!(classify.input_hash, "abc");
+        assert_eq!(classify.result.sequences_processed, Some(10));
+
+        std::fs::write(&path, "{ not json").unwrap();
+        assert!(crate::state::PipelineState::read(&path).classify.is_none());
+    }
+}
@@ -0,0 +1,236 @@
+use rust_htslib::bam::{self, Read};
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use crate::sleuth::{align_reads, aligned_blocks, ContigCoverage};
+
+/// Options for genotyping a hit (e.g. HPV16 vs HPV18) from its reads
+pub struct SubtypeConfig {
+    /// Directory of marker references, one FASTA per subtype (e.g. `HPV16.fa` holding the HPV16 L1 gene).
+    /// The file stem names the subtype, so new marker sets (e.g. EBV type 1/2) are just another directory
+    pub markers: PathBuf,
+    pub threads: u8,
+    pub outdir: String,
+    /// Minimum reads aligned to a subtype's markers before it can be called
+    pub min_reads: u64,
+}
+
+/// How well the reads support one subtype
+#[derive(Debug, PartialEq, serde::Serialize)]
+pub struct SubtypeSupport {
+    pub subtype: String,
+    /// Primary alignments to this subtype's markers
+    pub nreads: u64,
+    /// Fraction of marker bases covered by at least one read
+    pub breadth: f64,
+    /// Whether this is the called genotype
+    pub called: bool,
+}
+
+/// Marker FASTAs (`.fa`, `.fasta`, `.fna`) in `dir` as (subtype, path), sorted by subtype
+pub fn read_marker_set(dir: &Path) -> std::io::Result<Vec<(String, PathBuf)>> {
+    let mut markers = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_fasta = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| matches!(ext, "fa" | "fasta" | "fna"));
+        if !is_fasta {
+            continue;
+        }
+        if let Some(subtype) = path.file_stem().and_then(|stem| stem.to_str()) {
+            markers.push((subtype.to_string(), path.clone()));
+        }
+    }
+    markers.sort();
+    Ok(markers)
+}
+
+/// Concatenate marker FASTAs into one reference, naming each sequence `<subtype>|<original name>`
+/// so alignments can be traced back to their subtype
+fn write_combined_markers(markers: &[(String, PathBuf)], output: &Path) -> std::io::Result<()> {
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(output)?);
+    for (subtype, path) in markers {
+        let reader = std::io::BufReader::new(std::fs::File::open(path)?);
+        for line in reader.lines() {
+            let line = line?;
+            match line.strip_prefix('>') {
+                Some(name) => writeln!(writer, ">{}|{}", subtype, name)?,
+                None => writeln!(writer, "{}", line)?,
+            }
+        }
+    }
+    writer.flush()
+}
+
+/// Call the subtype with the most reads (ties broken by breadth), if it has at least `min_reads`
+pub(crate) fn call_subtype(support: &mut [SubtypeSupport], min_reads: u64) -> Option<String> {
+    let best = support
+        .iter_mut()
+        .filter(|s| s.nreads >= min_reads && s.nreads > 0)
+        .max_by(|a, b| {
+            a.nreads
+                .cmp(&b.nreads)
+                .then(a.breadth.total_cmp(&b.breadth))
+        })?;
+    best.called = true;
+    Some(best.subtype.clone())
+}
+
+/// Align `reads` (e.g. the kraken-classified reads of HPV, taxid 10566) to every subtype's markers and call the
+/// best supported genotype. Writes per-subtype support to `<outdir>/<reads prefix>.subtype.tsv`
+pub fn run_subtype(reads: &Path, config: &SubtypeConfig) -> Option<String> {
+    std::fs::create_dir_all(&config.outdir).expect("Failed to create output directory");
+    let prefix = reads
+        .file_stem()
+        .expect("Failed to extract reads file stem")
+        .to_str()
+        .expect("failed filepath to str conversion");
+    let outfile_markers = format!("{}/{}.subtype_markers.fa", config.outdir, prefix);
+    let outfile_sam = format!("{}/{}.subtype.sam", config.outdir, prefix);
+    let outfile_tsv = format!("{}/{}.subtype.tsv", config.outdir, prefix);
+
+    let markers = read_marker_set(&config.markers).unwrap_or_else(|err| {
+        panic!(
+            "Failed to read subtype markers from [{}]: {}",
+            config.markers.display(),
+            err
+        )
+    });
+    if markers.is_empty() {
+        panic!(
+            "No marker FASTAs (.fa, .fasta, .fna) found in [{}]",
+            config.markers.display()
+        );
+    }
+    write_combined_markers(&markers, Path::new(&outfile_markers))
+        .expect("Failed to write combined subtype markers");
+
+    eprintln!("\nRunning Subtype");
+    align_reads(
+        Path::new(&outfile_markers),
+        reads,
+        config.threads,
+        Path::new(&outfile_sam),
+    );
+
+    let mut sam = bam::Reader::from_path(&outfile_sam).expect("Failed to read minimap2 alignments");
+    let header = sam.header().clone();
+    let mut coverage: Vec<ContigCoverage> = (0..header.target_count())
+        .map(|tid| {
+            ContigCoverage::new(
+                std::str::from_utf8(header.tid2name(tid)).unwrap(),
+                header.target_len(tid).unwrap_or(0),
+                1000,
+            )
+        })
+        .collect();
+    for r in sam.records() {
+        let record = r.unwrap_or_else(|err| panic!("Failed to read sam record: {:?}", err));
+        if record.is_unmapped() | record.is_secondary() | record.is_supplementary() {
+            continue;
+        }
+        coverage[record.tid() as usize].add_read(&aligned_blocks(&record));
+    }
+
+    // Pool the marker sequences of each subtype
+    let mut support: Vec<SubtypeSupport> = markers
+        .iter()
+        .map(|(subtype, _)| {
+            let stats: Vec<_> = coverage
+                .iter()
+                .map(ContigCoverage::stats)
+                .filter(|s| s.contig.split('|').next() == Some(subtype.as_str()))
+                .collect();
+            let length: u64 = stats.iter().map(|s| s.length).sum();
+            let covered: f64 = stats.iter().map(|s| s.breadth * s.length as f64).sum();
+            SubtypeSupport {
+                subtype: subtype.clone(),
+                nreads: stats.iter().map(|s| s.nreads).sum(),
+                breadth: if length > 0 {
+                    covered / length as f64
+                } else {
+                    0.0
+                },
+                called: false,
+            }
+        })
+        .collect();
+    let called = call_subtype(&mut support, config.min_reads);
+
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .from_path(&outfile_tsv)
+        .expect("Failed to create subtype tsv");
+    for subtype in &support {
+        eprintln!(
+            "\t{}: [{}] reads, breadth [{:.3}]",
+            subtype.subtype, subtype.nreads, subtype.breadth
+        );
+        writer
+            .serialize(subtype)
+            .expect("Failed to write to subtype tsv");
+    }
+    writer.flush().expect("Failed to flush subtype tsv");
+    match &called {
+        Some(subtype) => eprintln!("\tCalled subtype: [{}]", subtype),
+        None => eprintln!(
+            "\tNo subtype has at least [{}] supporting reads",
+            config.min_reads
+        ),
+    }
+    eprintln!("\tSubtype report saved to: {}", outfile_tsv);
+    called
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::subtype::SubtypeSupport;
+
+    fn support(subtype: &str, nreads: u64, breadth: f64) -> SubtypeSupport {
+        SubtypeSupport {
+            subtype: subtype.to_string(),
+            nreads,
+            breadth,
+            called: false,
+        }
+    }
+
+    #[test]
+    fn calls_best_supported_subtype() {
+        let mut subtypes = vec![
+            support("HPV16", 40, 0.6),
+            support("HPV18", 40, 0.8),
+            support("HPV31", 3, 0.1),
+        ];
+        assert_eq!(
+            crate::subtype::call_subtype(&mut subtypes, 5),
+            Some("HPV18".to_string())
+        );
+        let called: Vec<bool> = subtypes.iter().map(|s| s.called).collect();
+        assert_eq!(called, vec![false, true, false]);
+
+        let mut subtypes = vec![support("HPV16", 2, 0.6), support("HPV18", 0, 0.0)];
+        assert_eq!(crate::subtype::call_subtype(&mut subtypes, 5), None);
+    }
+
+    #[test]
+    fn marker_sets() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("HPV18.fa"), ">L1\nACGT\n").unwrap();
+        std::fs::write(dir.path().join("HPV16.fasta"), ">L1 gene\nAACC\nGGTT\n").unwrap();
+        std::fs::write(dir.path().join("README.txt"), "not a marker").unwrap();
+
+        let markers = crate::subtype::read_marker_set(dir.path()).unwrap();
+        let subtypes: Vec<&str> = markers.iter().map(|(s, _)| s.as_str()).collect();
+        assert_eq!(subtypes, vec!["HPV16", "HPV18"]);
+
+        let combined = dir.path().join("combined.fa");
+        crate::subtype::write_combined_markers(&markers, &combined).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(combined).unwrap(),
+            ">HPV16|L1 gene\nAACC\nGGTT\n>HPV18|L1\nACGT\n"
+        );
+    }
+}
@@ -0,0 +1,572 @@
+use std::path::Path;
+
+use crate::bam::ExtractionSummary;
+use crate::kraken::{
+    kreport_nreads_classified, KrakenOutput, KreportRecord, PositiveControlConfig,
+};
+use crate::taxonomy::{kreport_lineage_names, Taxonomy};
+
+/// Machine-readable record of one [crate::pipeline::screen] run, written to `<outdir>/<prefix>.micrite.json`
+#[derive(Debug, serde::Serialize)]
+pub struct RunSummary {
+    pub micrite_version: &'static str,
+    /// When the run started (UTC, RFC 3339)
+    pub timestamp: String,
+    /// The screened bam (or reads file, see [crate::pipeline::screen_reads])
+    pub bam: String,
+    pub status: RunStatus,
+    /// None if extraction didn't finish
+    pub extraction: Option<ExtractionSummary>,
+    /// None if kraken wasn't run (e.g. stopped after extraction)
+    pub kraken: Option<KrakenSummary>,
+}
+
+/// How far a [crate::pipeline::screen] run got
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunStatus {
+    /// Still running (the status of a summary that was never finished)
+    Incomplete,
+    /// Reads were extracted and classified by kraken2
+    Classified,
+    /// Stopped after extraction as requested (see [crate::bam::ExtractionConfig::stop_after])
+    Extracted,
+    /// No reads passed extraction, so there was nothing to classify
+    NoReads,
+}
+
+/// Kraken2 (or KrakenUniq) settings and results
+#[derive(Debug, serde::Serialize)]
+pub struct KrakenSummary {
+    /// `kraken2` or `krakenuniq`
+    pub classifier: &'static str,
+    pub db: String,
+    pub confidence: String,
+    pub nreads_classified: u64,
+    /// Sequences kraken2 reported processing and classifying on stderr (None if they couldn't be parsed)
+    pub sequences_processed: Option<u64>,
+    pub sequences_classified: Option<u64>,
+    /// Every taxon kraken2 assigned reads to (clade level)
+    pub taxa: Vec<KreportRecord>,
+    /// Where [KrakenSummary::species] counts come from: `bracken` (re-estimated) or `raw` (kraken2 clade counts)
+    pub species_counts: &'static str,
+    /// Reads per species, most first
+    pub species: Vec<SpeciesAbundance>,
+    /// Recovery of the spike-in control (None if no control is configured)
+    pub positive_control: Option<PositiveControl>,
+}
+
+/// Whether a spike-in control was recovered (see [crate::kraken::KrakenConfig::positive_control])
+#[derive(Debug, PartialEq, serde::Serialize)]
+pub struct PositiveControl {
+    pub taxid: u64,
+    /// Taxon name from the kraken report (None if the control taxid wasn't reported)
+    pub name: Option<String>,
+    /// Clade reads classified to the control
+    pub nreads: u64,
+    pub min_reads: u64,
+    pub detected: bool,
+}
+
+impl PositiveControl {
+    pub fn new(config: &PositiveControlConfig, taxa: &[KreportRecord]) -> Self {
+        let taxon = taxa.iter().find(|r| r.taxid == config.taxid);
+        let nreads = taxon.map_or(0, |r| r.clade_nreads_classified);
+        PositiveControl {
+            taxid: config.taxid,
+            name: taxon.map(|r| r.name.clone()),
+            nreads,
+            min_reads: config.min_reads,
+            detected: nreads >= config.min_reads,
+        }
+    }
+}
+
+/// Read support for one species
+#[derive(Debug, PartialEq, serde::Serialize)]
+pub struct SpeciesAbundance {
+    pub taxid: u64,
+    pub name: String,
+    pub nreads: u64,
+    /// Distinct k-mers behind the species' reads (KrakenUniq only).
+    /// Far fewer unique k-mers than reads suggests a single fragment rather than a genome
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unique_kmers: Option<u64>,
+    /// `;` separated names from the top of the taxonomy down to the species (see [crate::taxonomy])
+    pub lineage: Option<String>,
+}
+
+impl KrakenSummary {
+    /// Species lineages come from `taxonomy` if given, otherwise from the kraken report's own nesting
+    pub fn new(
+        db: String,
+        confidence: String,
+        output: KrakenOutput,
+        taxonomy: Option<&Taxonomy>,
+    ) -> Self {
+        let (species_counts, mut species): (_, Vec<SpeciesAbundance>) = match &output.bracken {
+            Some(bracken) => (
+                "bracken",
+                bracken
+                    .iter()
+                    .map(|r| SpeciesAbundance {
+                        taxid: r.taxid,
+                        name: r.name.clone(),
+                        nreads: r.new_est_reads,
+                        unique_kmers: None,
+                        lineage: None,
+                    })
+                    .collect(),
+            ),
+            None => (
+                "raw",
+                output
+                    .kreport
+                    .iter()
+                    .filter(|r| r.rank == "S")
+                    .map(|r| SpeciesAbundance {
+                        taxid: r.taxid,
+                        name: r.name.clone(),
+                        nreads: r.clade_nreads_classified,
+                        unique_kmers: r.unique_kmers,
+                        lineage: None,
+                    })
+                    .collect(),
+            ),
+        };
+        species.retain(|s| s.nreads > 0);
+        let kreport_lineages = kreport_lineage_names(&output.kreport);
+        for s in &mut species {
+            s.lineage = taxonomy
+                .and_then(|taxonomy| taxonomy.lineage(s.taxid))
+                .or_else(|| kreport_lineages.get(&s.taxid).cloned());
+        }
+        species.sort_by_key(|s| std::cmp::Reverse(s.nreads));
+        KrakenSummary {
+            classifier: output.classifier,
+            db,
+            confidence,
+            nreads_classified: kreport_nreads_classified(&output.kreport),
+            sequences_processed: output.sequences_processed,
+            sequences_classified: output.sequences_classified,
+            taxa: output
+                .kreport
+                .into_iter()
+                .filter(|r| r.taxid != 0 && r.clade_nreads_classified > 0)
+                .collect(),
+            species_counts,
+            species,
+            positive_control: None,
+        }
+    }
+}
+
+impl RunSummary {
+    pub fn new(bam: &str) -> Self {
+        RunSummary {
+            micrite_version: env!("CARGO_PKG_VERSION"),
+            timestamp: utc_timestamp(std::time::SystemTime::now()),
+            bam: bam.to_string(),
+            status: RunStatus::Incomplete,
+            extraction: None,
+            kraken: None,
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let file = std::fs::File::create(path).map_err(|err| {
+            format!(
+                "Failed to create run summary json [{}]: {}",
+                path.display(),
+                err
+            )
+        })?;
+        serde_json::to_writer_pretty(file, self)
+            .map_err(|err| format!("Failed to write run summary json: {}", err))?;
+        eprintln!("Run summary saved to: {}", path.display());
+        Ok(())
+    }
+
+    /// Write MultiQC custom content for `sample` to `outdir`, so `multiqc` run over a cohort's output directories
+    /// shows micrite next to the other QC tools:
+    /// - `<sample>_micrite_mqc.json`: a table of read counts and the top species
+    /// - `<sample>_micrite_species_mqc.json`: a bar plot of the [MULTIQC_TOP_SPECIES] species with most reads
+    ///   (only once kraken has run)
+    ///
+    /// MultiQC merges the files of every sample into one section per file type
+    pub fn write_multiqc(
+        &self,
+        sample: &str,
+        outdir: &Path,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let extraction = self.extraction.as_ref();
+        let count = |field: fn(&ExtractionSummary) -> u64| extraction.map(field);
+        let top_species = self
+            .kraken
+            .as_ref()
+            .and_then(|kraken| kraken.species.first());
+        let row = serde_json::json!({
+            "status": self.status,
+            "total_reads": count(|e| e.total_reads),
+            "unmapped_reads": count(|e| e.unmapped_reads),
+            "percent_unmapped": extraction
+                .filter(|e| e.total_reads > 0)
+                .map(|e| 100.0 * e.unmapped_reads as f64 / e.total_reads as f64),
+            "reads_screened": count(|e| e.reads_written),
+            "reads_classified": self.kraken.as_ref().map(|kraken| kraken.nreads_classified),
+            "top_species": top_species.map(|s| s.name.as_str()),
+            "top_species_reads": top_species.map(|s| s.nreads),
+        });
+        let table = serde_json::json!({
+            "id": "micrite",
+            "section_name": "micrite",
+            "description": "Reads left unexplained by the host reference, screened for microbes with kraken2",
+            "plot_type": "table",
+            "pconfig": { "id": "micrite_table", "title": "micrite: read summary" },
+            "headers": {
+                "status": { "title": "Status" },
+                "total_reads": { "title": "Total reads", "format": "{:,.0f}" },
+                "unmapped_reads": { "title": "Unmapped reads", "format": "{:,.0f}" },
+                "percent_unmapped": { "title": "% Unmapped", "suffix": "%", "min": 0, "max": 100 },
+                "reads_screened": {
+                    "title": "Reads screened",
+                    "description": "Good quality reads sent to kraken2",
+                    "format": "{:,.0f}"
+                },
+                "reads_classified": { "title": "Reads classified", "format": "{:,.0f}" },
+                "top_species": { "title": "Top species" },
+                "top_species_reads": { "title": "Top species reads", "format": "{:,.0f}" },
+            },
+            "data": { sample: row },
+        });
+        write_json(&outdir.join(format!("{sample}_micrite_mqc.json")), &table)?;
+
+        if let Some(kraken) = &self.kraken {
+            let counts: serde_json::Map<String, serde_json::Value> = kraken
+                .species
+                .iter()
+                .take(MULTIQC_TOP_SPECIES)
+                .map(|s| (s.name.clone(), s.nreads.into()))
+                .collect();
+            let bargraph = serde_json::json!({
+                "id": "micrite_species",
+                "section_name": "micrite: top species",
+                "description": format!(
+                    "Reads per species ({} counts), for the {} species with most reads in each sample",
+                    kraken.species_counts, MULTIQC_TOP_SPECIES
+                ),
+                "plot_type": "bargraph",
+                "pconfig": { "id": "micrite_species_plot", "title": "micrite: top species", "ylab": "Reads" },
+                "data": { sample: counts },
+            });
+            write_json(
+                &outdir.join(format!("{sample}_micrite_species_mqc.json")),
+                &bargraph,
+            )?;
+        }
+        eprintln!(
+            "MultiQC custom content saved to: {}",
+            outdir.join(format!("{sample}_micrite*_mqc.json")).display()
+        );
+        Ok(())
+    }
+}
+
+/// Species per sample shown in the MultiQC bar plot (see [RunSummary::write_multiqc])
+pub const MULTIQC_TOP_SPECIES: usize = 10;
+
+fn write_json(path: &Path, value: &serde_json::Value) -> Result<(), Box<dyn std::error::Error>> {
+    let file = std::fs::File::create(path)
+        .map_err(|err| format!("Failed to create [{}]: {}", path.display(), err))?;
+    serde_json::to_writer_pretty(file, value)
+        .map_err(|err| format!("Failed to write [{}]: {}", path.display(), err))?;
+    Ok(())
+}
+
+/// Format a time as `YYYY-MM-DDTHH:MM:SSZ`
+fn utc_timestamp(time: std::time::SystemTime) -> String {
+    let secs = time
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+
+    // Days since the epoch to a proleptic Gregorian date (Howard Hinnant's civil_from_days)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn timestamps() {
+        use crate::summary::utc_timestamp;
+        assert_eq!(utc_timestamp(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        assert_eq!(
+            utc_timestamp(UNIX_EPOCH + Duration::from_secs(951_782_400)),
+            "2000-02-29T00:00:00Z"
+        );
+        assert_eq!(
+            utc_timestamp(UNIX_EPOCH + Duration::from_secs(1_735_689_599)),
+            "2024-12-31T23:59:59Z"
+        );
+    }
+
+    #[test]
+    fn species_counts_raw_or_bracken() {
+        use crate::kraken::{BrackenRecord, KrakenOutput, KreportRecord};
+
+        let kreport = |rank: &str, taxid, name: &str, nreads| KreportRecord {
+            clade_percent_classified: 0.0,
+            clade_nreads_classified: nreads,
+            taxon_nreads_classified: nreads,
+            rank: rank.to_string(),
+            taxid,
+            name: name.to_string(),
+            depth: 0,
+            unique_kmers: None,
+            kmer_duplication: None,
+            kmer_coverage: None,
+        };
+        let output = |bracken| KrakenOutput {
+            classifier: "kraken2",
+            kout: None,
+            kreport: vec![
+                kreport("R", 1, "root", 30),
+                kreport("S", 10376, "Human gammaherpesvirus 4", 10),
+                kreport("S", 333760, "Human papillomavirus 16", 20),
+                kreport("S1", 12345, "strain", 1),
+            ],
+            sequences_processed: None,
+            sequences_classified: None,
+            sequences_unclassified: None,
+            bracken,
+        };
+
+        let raw =
+            crate::summary::KrakenSummary::new(String::new(), String::new(), output(None), None);
+        assert_eq!(raw.species_counts, "raw");
+        let species: Vec<(u64, u64)> = raw.species.iter().map(|s| (s.taxid, s.nreads)).collect();
+        assert_eq!(species, vec![(333760, 20), (10376, 10)]);
+        // Without a taxonomy, lineages come from the report's nesting (flat here)
+        assert_eq!(
+            raw.species[0].lineage.as_deref(),
+            Some("Human papillomavirus 16")
+        );
+
+        let bracken = vec![BrackenRecord {
+            name: "Human gammaherpesvirus 4".to_string(),
+            taxid: 10376,
+            rank: "S".to_string(),
+            kraken_assigned_reads: 10,
+            added_reads: 5,
+            new_est_reads: 15,
+            fraction_total_reads: 1.0,
+        }];
+        let adjusted = crate::summary::KrakenSummary::new(
+            String::new(),
+            String::new(),
+            output(Some(bracken)),
+            None,
+        );
+        assert_eq!(adjusted.species_counts, "bracken");
+        assert_eq!(adjusted.species[0].nreads, 15);
+        assert_eq!(adjusted.species.len(), 1);
+    }
+
+    #[test]
+    fn positive_control_detection() {
+        use crate::kraken::{KreportRecord, PositiveControlConfig};
+        use crate::summary::PositiveControl;
+
+        let taxa = vec![KreportRecord {
+            clade_percent_classified: 5.0,
+            clade_nreads_classified: 12,
+            taxon_nreads_classified: 12,
+            rank: "S".to_string(),
+            taxid: 10847,
+            name: "Escherichia virus phiX174".to_string(),
+            depth: 0,
+            unique_kmers: None,
+            kmer_duplication: None,
+            kmer_coverage: None,
+        }];
+        let control = |taxid, min_reads| PositiveControlConfig { taxid, min_reads };
+
+        let detected = PositiveControl::new(&control(10847, 10), &taxa);
+        assert!(detected.detected);
+        assert_eq!(detected.nreads, 12);
+        assert_eq!(detected.name.as_deref(), Some("Escherichia virus phiX174"));
+        assert!(!PositiveControl::new(&control(10847, 20), &taxa).detected);
+
+        // A control missing from the report wasn't recovered at all
+        let missing = PositiveControl::new(&control(99999, 1), &taxa);
+        assert_eq!((missing.nreads, missing.detected), (0, false));
+        assert_eq!(missing.name, None);
+    }
+
+    #[test]
+    fn run_summary_json() {
+        use crate::test_bam::{sequence, TestBam, TestRead};
+
+        let dir = tempfile::tempdir().unwrap();
+        let bam = TestBam::new()
+            .contig("chr1", 10_000)
+            .read(TestRead::mapped("mapped1", "chr1", 100, &sequence(100, 1)))
+            .read(TestRead::unmapped("good1", &sequence(100, 2)))
+            .read(TestRead::unmapped("short", &sequence(30, 3)))
+            .write(dir.path(), "sample");
+        let outdir = dir.path().join("out");
+        let config_kraken = crate::kraken::KrakenConfig {
+            outdir: outdir.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        crate::bam::bam2microbes(
+            bam.to_str().unwrap(),
+            outdir.to_str().unwrap(),
+            config_kraken,
+            &crate::bam::ReadFilterConfig::default(),
+            &crate::bam::ExtractionConfig {
+                tmpdir: Some(dir.path().join("tmp")),
+                stop_after: Some(crate::bam::Stage::Extract),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let json: serde_json::Value = serde_json::from_reader(
+            std::fs::File::open(outdir.join("sample.micrite.json")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(json["micrite_version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(json["extraction"]["total_reads"], 3);
+        assert_eq!(json["extraction"]["unmapped_reads"], 2);
+        assert_eq!(json["extraction"]["unmapped_good_quality_sequences"], 1);
+        assert_eq!(json["extraction"]["reads_written"], 1);
+        assert_eq!(json["extraction"]["microbial_contigs_present"], false);
+        assert_eq!(json["status"], "extracted");
+        assert!(json["kraken"].is_null());
+
+        // MultiQC gets the read counts, but no species plot before kraken has run
+        let mqc: serde_json::Value = serde_json::from_reader(
+            std::fs::File::open(outdir.join("sample_micrite_mqc.json")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(mqc["plot_type"], "table");
+        assert_eq!(mqc["data"]["sample"]["unmapped_reads"], 2);
+        assert!(mqc["data"]["sample"]["top_species"].is_null());
+        assert!(!outdir.join("sample_micrite_species_mqc.json").exists());
+    }
+
+    #[test]
+    fn multiqc_custom_content() {
+        use crate::kraken::{KrakenOutput, KreportRecord};
+
+        let kreport = (0..12)
+            .map(|i| KreportRecord {
+                clade_percent_classified: 0.0,
+                clade_nreads_classified: 100 - i,
+                taxon_nreads_classified: 100 - i,
+                rank: "S".to_string(),
+                taxid: 1000 + i,
+                name: format!("species {}", i),
+                depth: 0,
+                unique_kmers: None,
+                kmer_duplication: None,
+                kmer_coverage: None,
+            })
+            .collect();
+        let output = KrakenOutput {
+            classifier: "kraken2",
+            kout: None,
+            kreport,
+            sequences_processed: None,
+            sequences_classified: None,
+            sequences_unclassified: None,
+            bracken: None,
+        };
+        let mut summary = crate::summary::RunSummary::new("sample.bam");
+        summary.status = crate::summary::RunStatus::Classified;
+        summary.kraken = Some(crate::summary::KrakenSummary::new(
+            String::new(),
+            String::new(),
+            output,
+            None,
+        ));
+
+        let dir = tempfile::tempdir().unwrap();
+        summary.write_multiqc("sample", dir.path()).unwrap();
+        let read = |name: &str| -> serde_json::Value {
+            serde_json::from_reader(std::fs::File::open(dir.path().join(name)).unwrap()).unwrap()
+        };
+        let table = read("sample_micrite_mqc.json");
+        assert_eq!(table["data"]["sample"]["status"], "classified");
+        assert_eq!(table["data"]["sample"]["top_species"], "species 0");
+        assert_eq!(table["data"]["sample"]["top_species_reads"], 100);
+        // Without extraction counts there's nothing to report for them
+        assert!(table["data"]["sample"]["total_reads"].is_null());
+
+        let bargraph = read("sample_micrite_species_mqc.json");
+        assert_eq!(bargraph["plot_type"], "bargraph");
+        let counts = bargraph["data"]["sample"].as_object().unwrap();
+        assert_eq!(counts.len(), crate::summary::MULTIQC_TOP_SPECIES);
+        assert_eq!(counts["species 9"], 91);
+    }
+
+    #[test]
+    fn nothing_to_classify() {
+        use crate::test_bam::{sequence, TestBam, TestRead};
+
+        let dir = tempfile::tempdir().unwrap();
+        let bam = TestBam::new()
+            .contig("chr1", 10_000)
+            .read(TestRead::mapped("mapped1", "chr1", 100, &sequence(100, 1)))
+            .read(TestRead::unmapped("short", &sequence(30, 2)))
+            .write(dir.path(), "sample");
+        let outdir = dir.path().join("out");
+        // kraken2 isn't needed: the run stops before classification
+        let config_kraken = crate::kraken::KrakenConfig {
+            outdir: outdir.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let status = crate::bam::bam2microbes(
+            bam.to_str().unwrap(),
+            outdir.to_str().unwrap(),
+            config_kraken,
+            &crate::bam::ReadFilterConfig::default(),
+            &crate::bam::ExtractionConfig {
+                tmpdir: Some(dir.path().join("tmp")),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(status, crate::summary::RunStatus::NoReads);
+
+        let json: serde_json::Value = serde_json::from_reader(
+            std::fs::File::open(outdir.join("sample.micrite.json")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(json["status"], "no_reads");
+        assert_eq!(json["extraction"]["reads_written"], 0);
+        assert!(json["kraken"].is_null());
+        assert!(outdir.join("sample.bam_summary.txt").exists());
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use crate::kraken::{kreport_lineages, KreportRecord};
+
+/// NCBI taxonomy dump files (`nodes.dmp` and `names.dmp` from `taxdump.tar.gz`,
+/// also kept in the `taxonomy/` directory of a kraken2 database)
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TaxonomyConfig {
+    pub nodes: PathBuf,
+    pub names: PathBuf,
+}
+
+/// Parent and scientific name of every taxid in an NCBI taxonomy
+pub struct Taxonomy {
+    parents: HashMap<u64, u64>,
+    names: HashMap<u64, String>,
+}
+
+/// The fields of one line of a `.dmp` file (`field\t|\tfield\t|\n`)
+fn dmp_fields(line: &str) -> Vec<&str> {
+    line.trim_end_matches(['\n', '\r'])
+        .trim_end_matches("\t|")
+        .split("\t|\t")
+        .collect()
+}
+
+/// Read a dump file, handing the leading taxid and all fields of each line to `parse`
+fn read_dmp(
+    path: &Path,
+    mut parse: impl FnMut(u64, &[&str]) -> Result<(), String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let file = std::fs::File::open(path)
+        .map_err(|err| format!("Failed to open [{}]: {}", path.display(), err))?;
+    for line in std::io::BufReader::new(file).lines() {
+        let line = line?;
+        let fields = dmp_fields(&line);
+        let taxid = fields[0].trim().parse().map_err(|_| {
+            format!(
+                "Failed to parse taxid [{}] in [{}]",
+                fields[0],
+                path.display()
+            )
+        })?;
+        parse(taxid, &fields).map_err(|err| format!("{} in [{}]", err, path.display()))?;
+    }
+    Ok(())
+}
+
+impl Taxonomy {
+    pub fn read(config: &TaxonomyConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut parents = HashMap::new();
+        read_dmp(&config.nodes, |taxid, fields| {
+            let parent = fields
+                .get(1)
+                .and_then(|parent| parent.trim().parse().ok())
+                .ok_or_else(|| format!("No parent taxid for [{}]", taxid))?;
+            parents.insert(taxid, parent);
+            Ok(())
+        })?;
+
+        let mut names = HashMap::new();
+        read_dmp(&config.names, |taxid, fields| {
+            if fields.get(3) == Some(&"scientific name") {
+                names.insert(taxid, fields[1].to_string());
+            }
+            Ok(())
+        })?;
+        Ok(Taxonomy { parents, names })
+    }
+
+    /// Scientific names from the top of the tree (below root) down to `taxid`, separated by `;`.
+    /// None if `taxid` isn't in the taxonomy
+    pub fn lineage(&self, taxid: u64) -> Option<String> {
+        let mut path = vec![];
+        let mut current = taxid;
+        // Root is its own parent; the length check guards against cycles in a corrupt dump
+        while current != 1 && path.len() <= self.parents.len() {
+            path.push(self.names.get(&current).map_or("?", String::as_str));
+            current = *self.parents.get(&current)?;
+        }
+        path.reverse();
+        Some(path.join(";"))
+    }
+}
+
+/// Parsed taxonomies keyed by their (nodes, names) files
+type TaxonomyCache = Mutex<HashMap<(PathBuf, PathBuf), Arc<Taxonomy>>>;
+
+/// Taxonomies already read, so a batch parses the (large) dump files once rather than per sample
+fn cache() -> &'static TaxonomyCache {
+    static CACHE: OnceLock<TaxonomyCache> = OnceLock::new();
+    CACHE.get_or_init(Default::default)
+}
+
+/// [Taxonomy::read], reusing the taxonomy if these files have been read before
+pub fn read_taxonomy_cached(
+    config: &TaxonomyConfig,
+) -> Result<Arc<Taxonomy>, Box<dyn std::error::Error>> {
+    let key = (config.nodes.clone(), config.names.clone());
+    if let Some(taxonomy) = cache().lock().unwrap().get(&key) {
+        return Ok(Arc::clone(taxonomy));
+    }
+    eprintln!(
+        "Reading taxonomy from [{}] and [{}]",
+        config.nodes.display(),
+        config.names.display()
+    );
+    let taxonomy = Arc::new(Taxonomy::read(config)?);
+    cache().lock().unwrap().insert(key, Arc::clone(&taxonomy));
+    Ok(taxonomy)
+}
+
+/// Lineage of every taxon in a kraken report, formatted like [Taxonomy::lineage]. Built from the report's
+/// own nesting, so it only includes ancestors kraken2 reported (usually every rank, but not always)
+pub fn kreport_lineage_names(kreport: &[KreportRecord]) -> HashMap<u64, String> {
+    kreport
+        .iter()
+        .zip(kreport_lineages(kreport))
+        .map(|(record, lineage)| {
+            let names: Vec<&str> = lineage
+                .iter()
+                .map(|&i| &kreport[i])
+                .filter(|ancestor| ancestor.taxid != 1)
+                .map(|ancestor| ancestor.name.as_str())
+                .collect();
+            (record.taxid, names.join(";"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    fn write_taxonomy(dir: &std::path::Path) -> crate::taxonomy::TaxonomyConfig {
+        let nodes = dir.join("nodes.dmp");
+        let names = dir.join("names.dmp");
+        std::fs::write(
+            &nodes,
+            "1\t|\t1\t|\tno rank\t|\t\t|\n\
+             10239\t|\t1\t|\tsuperkingdom\t|\t\t|\n\
+             10292\t|\t10239\t|\tfamily\t|\t\t|\n\
+             10376\t|\t10292\t|\tspecies\t|\t\t|\n",
+        )
+        .unwrap();
+        std::fs::write(
+            &names,
+            "1\t|\troot\t|\t\t|\tscientific name\t|\n\
+             10239\t|\tViruses\t|\t\t|\tscientific name\t|\n\
+             10292\t|\tHerpesviridae\t|\t\t|\tscientific name\t|\n\
+             10376\t|\tHuman gammaherpesvirus 4\t|\t\t|\tscientific name\t|\n\
+             10376\t|\tEpstein-Barr virus\t|\t\t|\tgenbank common name\t|\n",
+        )
+        .unwrap();
+        crate::taxonomy::TaxonomyConfig { nodes, names }
+    }
+
+    #[test]
+    fn lineage_from_ncbi_taxonomy() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = write_taxonomy(dir.path());
+        let taxonomy = crate::taxonomy::read_taxonomy_cached(&config).unwrap();
+        assert_eq!(
+            taxonomy.lineage(10376).as_deref(),
+            Some("Viruses;Herpesviridae;Human gammaherpesvirus 4")
+        );
+        assert_eq!(taxonomy.lineage(1).as_deref(), Some(""));
+        assert_eq!(taxonomy.lineage(999), None);
+
+        // A second sample reuses the parsed taxonomy
+        let again = crate::taxonomy::read_taxonomy_cached(&config).unwrap();
+        assert!(std::sync::Arc::ptr_eq(&taxonomy, &again));
+    }
+
+    #[test]
+    fn lineage_from_kreport() {
+        use std::io::Write;
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, " 25.00\t10\t10\tU\t0\tunclassified").unwrap();
+        writeln!(file, " 75.00\t30\t0\tR\t1\troot").unwrap();
+        writeln!(file, " 75.00\t30\t0\tD\t10239\t  Viruses").unwrap();
+        writeln!(
+            file,
+            " 50.00\t20\t20\tS\t10376\t    Human gammaherpesvirus 4"
+        )
+        .unwrap();
+        writeln!(
+            file,
+            " 25.00\t10\t10\tS\t333760\t    Human papillomavirus 16"
+        )
+        .unwrap();
+        let kreport = crate::kraken::read_kreport(file.path()).unwrap();
+        let lineages = crate::taxonomy::kreport_lineage_names(&kreport);
+        assert_eq!(lineages[&10376], "Viruses;Human gammaherpesvirus 4");
+        assert_eq!(lineages[&333760], "Viruses;Human papillomavirus 16");
+        assert_eq!(lineages[&0], "unclassified");
+    }
+}
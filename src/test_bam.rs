@@ -0,0 +1,280 @@
+// Test-only helpers for building small indexed bams programmatically
+use rust_htslib::bam::{
+    self,
+    record::{Aux, Cigar, CigarString},
+};
+use std::path::{Path, PathBuf};
+
+/// A read to be written into a [TestBam]
+pub(crate) struct TestRead {
+    qname: String,
+    contig: Option<String>,
+    pos: i64,
+    seq: Vec<u8>,
+    qual: Vec<u8>,
+    cigar: Option<CigarString>,
+    mapq: u8,
+    flags: u16,
+    alignment_score: Option<i32>,
+    edit_distance: Option<i32>,
+}
+
+impl TestRead {
+    /// An unmapped read with uniform base quality 30
+    pub(crate) fn unmapped(qname: &str, seq: &[u8]) -> Self {
+        TestRead {
+            qname: qname.to_string(),
+            contig: None,
+            pos: -1,
+            seq: seq.to_vec(),
+            qual: vec![30; seq.len()],
+            cigar: None,
+            mapq: 0,
+            flags: 0x4,
+            alignment_score: None,
+            edit_distance: None,
+        }
+    }
+
+    /// A read aligned end-to-end (all `M`) at 0-based `pos` on `contig`, with MAPQ 60 and uniform base quality 30
+    pub(crate) fn mapped(qname: &str, contig: &str, pos: i64, seq: &[u8]) -> Self {
+        TestRead {
+            qname: qname.to_string(),
+            contig: Some(contig.to_string()),
+            pos,
+            seq: seq.to_vec(),
+            qual: vec![30; seq.len()],
+            cigar: Some(CigarString(vec![Cigar::Match(seq.len() as u32)])),
+            mapq: 60,
+            flags: 0,
+            alignment_score: None,
+            edit_distance: None,
+        }
+    }
+
+    pub(crate) fn qual(mut self, qual: u8) -> Self {
+        self.qual = vec![qual; self.seq.len()];
+        self
+    }
+
+    pub(crate) fn mapq(mut self, mapq: u8) -> Self {
+        self.mapq = mapq;
+        self
+    }
+
+    pub(crate) fn cigar(mut self, cigar: Vec<Cigar>) -> Self {
+        self.cigar = Some(CigarString(cigar));
+        self
+    }
+
+    /// Set additional SAM flag bits (e.g. 0x400 for duplicates)
+    pub(crate) fn flags(mut self, flags: u16) -> Self {
+        self.flags |= flags;
+        self
+    }
+
+    pub(crate) fn alignment_score(mut self, alignment_score: i32) -> Self {
+        self.alignment_score = Some(alignment_score);
+        self
+    }
+
+    pub(crate) fn edit_distance(mut self, edit_distance: i32) -> Self {
+        self.edit_distance = Some(edit_distance);
+        self
+    }
+}
+
+/// A small coordinate-sorted, indexed bam built in a test
+pub(crate) struct TestBam {
+    contigs: Vec<(String, u64)>,
+    reads: Vec<TestRead>,
+    sort_order: String,
+}
+
+impl TestBam {
+    pub(crate) fn new() -> Self {
+        TestBam {
+            contigs: vec![],
+            reads: vec![],
+            sort_order: "coordinate".to_string(),
+        }
+    }
+
+    /// A sample with one or two reads of every kind the extraction passes treat differently, for end-to-end tests.
+    /// Sequences are [sequence] with the seed noted (the EBV reads are slices of the `chrEBV` contig, seed 100):
+    ///
+    /// | read | where | kind | seed |
+    /// |---|---|---|---|
+    /// | `host_fwd`, `host_rev` | chr1:100, chr1:300 | host alignments, forward and reverse strand | 1, 2 |
+    /// | `host_dup`, `host_secondary` | chr1:500, chr1:700 | duplicate (0x400) and secondary (0x100) host alignments | 3, 4 |
+    /// | `ebv_fwd` | chrEBV:100 | convincing microbial alignment (AS 100) | |
+    /// | `ebv_rev` | chrEBV:400 | reverse-strand microbial alignment without an AS tag (so not convincing) | |
+    /// | `ebv_lowqual` | chrEBV:700 | microbial alignment with base quality 5 | |
+    /// | `good1`, `good2` | unmapped | good quality | 5, 6 |
+    /// | `short` | unmapped | 30bp | 7 |
+    /// | `lowqual` | unmapped | base quality 10 | 8 |
+    /// | `dup`, `qcfail` | unmapped | duplicate (0x400), QC fail (0x200) | 9, 10 |
+    /// | `polyA` | unmapped | homopolymer | |
+    pub(crate) fn mixed_sample() -> Self {
+        let ebv = sequence(2_000, 100);
+        TestBam::new()
+            .contig("chr1", 10_000)
+            .contig("chrEBV", 2_000)
+            .read(TestRead::mapped("host_fwd", "chr1", 100, &sequence(100, 1)))
+            .read(TestRead::mapped("host_rev", "chr1", 300, &sequence(100, 2)).flags(0x10))
+            .read(TestRead::mapped("host_dup", "chr1", 500, &sequence(100, 3)).flags(0x400))
+            .read(TestRead::mapped("host_secondary", "chr1", 700, &sequence(100, 4)).flags(0x100))
+            .read(TestRead::mapped("ebv_fwd", "chrEBV", 100, &ebv[100..200]).alignment_score(100))
+            .read(TestRead::mapped("ebv_rev", "chrEBV", 400, &ebv[400..500]).flags(0x10))
+            .read(
+                TestRead::mapped("ebv_lowqual", "chrEBV", 700, &ebv[700..800])
+                    .alignment_score(100)
+                    .qual(5),
+            )
+            .read(TestRead::unmapped("good1", &sequence(100, 5)))
+            .read(TestRead::unmapped("good2", &sequence(100, 6)))
+            .read(TestRead::unmapped("short", &sequence(30, 7)))
+            .read(TestRead::unmapped("lowqual", &sequence(100, 8)).qual(10))
+            .read(TestRead::unmapped("dup", &sequence(100, 9)).flags(0x400))
+            .read(TestRead::unmapped("qcfail", &sequence(100, 10)).flags(0x200))
+            .read(TestRead::unmapped("polyA", &[b'A'; 100]))
+    }
+
+    /// Override the `@HD SO` tag. Reads are still written in coordinate order
+    pub(crate) fn sort_order(mut self, sort_order: &str) -> Self {
+        self.sort_order = sort_order.to_string();
+        self
+    }
+
+    pub(crate) fn contig(mut self, name: &str, len: u64) -> Self {
+        self.contigs.push((name.to_string(), len));
+        self
+    }
+
+    pub(crate) fn read(mut self, read: TestRead) -> Self {
+        self.reads.push(read);
+        self
+    }
+
+    /// Write `<dir>/<name>.bam` (coordinate-sorted, unplaced unmapped reads last) and its `.bai` index
+    /// (`.csi` if a contig is too long for BAI).
+    /// Returns the bam path
+    pub(crate) fn write(&self, dir: &Path, name: &str) -> PathBuf {
+        self.write_as(&dir.join(format!("{}.bam", name)), bam::Format::Bam, None)
+    }
+
+    /// Write `<dir>/<name>.cram` compressed against `reference` (with a `.crai` index).
+    /// Returns the cram path
+    pub(crate) fn write_cram(&self, dir: &Path, name: &str, reference: &Path) -> PathBuf {
+        self.write_as(
+            &dir.join(format!("{}.cram", name)),
+            bam::Format::Cram,
+            Some(reference),
+        )
+    }
+
+    fn write_as(&self, path: &Path, format: bam::Format, reference: Option<&Path>) -> PathBuf {
+        let mut header = bam::Header::new();
+        let mut hd = bam::header::HeaderRecord::new(b"HD");
+        hd.push_tag(b"VN", "1.6").push_tag(b"SO", &self.sort_order);
+        header.push_record(&hd);
+        for (contig, len) in &self.contigs {
+            let mut sq = bam::header::HeaderRecord::new(b"SQ");
+            sq.push_tag(b"SN", contig).push_tag(b"LN", len);
+            // Lets htslib find the CRAM reference when writing the header
+            if let Some(reference) = reference {
+                sq.push_tag(b"UR", reference.canonicalize().unwrap().display());
+            }
+            header.push_record(&sq);
+        }
+
+        let tid = |read: &TestRead| -> i32 {
+            read.contig.as_ref().map_or(-1, |contig| {
+                self.contigs
+                    .iter()
+                    .position(|(name, _)| name == contig)
+                    .unwrap_or_else(|| panic!("contig [{}] not added to TestBam", contig))
+                    as i32
+            })
+        };
+
+        let mut reads: Vec<&TestRead> = self.reads.iter().collect();
+        reads.sort_by_key(|r| (tid(r) < 0, tid(r), r.pos));
+
+        let mut writer =
+            bam::Writer::from_path(path, &header, format).expect("Failed to create test bam");
+        if let Some(reference) = reference {
+            writer
+                .set_reference(reference)
+                .expect("Failed to set test cram reference");
+        }
+        for read in reads {
+            let mut record = bam::Record::new();
+            let cigar = read.cigar.clone().unwrap_or(CigarString(vec![]));
+            record.set(read.qname.as_bytes(), Some(&cigar), &read.seq, &read.qual);
+            record.set_tid(tid(read));
+            record.set_pos(read.pos);
+            record.set_mtid(-1);
+            record.set_mpos(-1);
+            record.set_mapq(read.mapq);
+            record.set_flags(read.flags);
+            if let Some(alignment_score) = read.alignment_score {
+                record.push_aux(b"AS", Aux::I32(alignment_score)).unwrap();
+            }
+            if let Some(edit_distance) = read.edit_distance {
+                record.push_aux(b"NM", Aux::I32(edit_distance)).unwrap();
+            }
+            writer
+                .write(&record)
+                .expect("Failed to write test bam record");
+        }
+        drop(writer);
+        // htslib writes a .crai rather than a .bai for CRAM input.
+        // Contigs longer than 2^29 bp can only be indexed with CSI
+        let index_type = if self.contigs.iter().any(|(_, len)| *len >= 1 << 29) {
+            bam::index::Type::Csi(14)
+        } else {
+            bam::index::Type::Bai
+        };
+        bam::index::build(path, None, index_type, 1).expect("Failed to index test bam");
+        path.to_path_buf()
+    }
+}
+
+/// A deterministic pseudo-random ACGT sequence (high complexity, no Ns)
+pub(crate) fn sequence(len: usize, seed: u64) -> Vec<u8> {
+    let mut state = seed
+        .wrapping_mul(6364136223846793005)
+        .wrapping_add(1442695040888963407);
+    (0..len)
+        .map(|_| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            b"ACGT"[(state >> 62) as usize]
+        })
+        .collect()
+}
+
+/// Write a single-sequence reference FASTA (and its `.fai`) to `<dir>/<name>.fa`. Returns the FASTA path
+pub(crate) fn write_reference(dir: &Path, name: &str, contig: &str, seq: &[u8]) -> PathBuf {
+    let path = dir.join(format!("{}.fa", name));
+    let header = format!(">{}\n", contig);
+    let mut fasta = header.clone().into_bytes();
+    fasta.extend_from_slice(seq);
+    fasta.push(b'\n');
+    std::fs::write(&path, fasta).expect("Failed to write test reference");
+    std::fs::write(
+        dir.join(format!("{}.fa.fai", name)),
+        format!(
+            "{}\t{}\t{}\t{}\t{}\n",
+            contig,
+            seq.len(),
+            header.len(),
+            seq.len(),
+            seq.len() + 1
+        ),
+    )
+    .expect("Failed to write test reference index");
+    path
+}